@@ -0,0 +1,12 @@
+-- Catalog price of one unit of each product; order totals are computed from these
+CREATE TABLE product_prices (
+    product_id UUID PRIMARY KEY REFERENCES inventory(product_id),
+    unit_price NUMERIC(19, 4) NOT NULL CHECK (unit_price > 0),
+    currency VARCHAR(3) NOT NULL CHECK (currency ~ '^[A-Z]{3}$'),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO product_prices (product_id, unit_price, currency) VALUES
+    ('11111111-1111-1111-1111-111111111111', 49.99, 'USD'),
+    ('22222222-2222-2222-2222-222222222222', 149.99, 'USD'),
+    ('33333333-3333-3333-3333-333333333333', 19.99, 'USD');
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE inventory
+    DROP CONSTRAINT IF EXISTS inventory_available_quantity_non_negative,
+    DROP CONSTRAINT IF EXISTS inventory_reserved_quantity_non_negative;
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Stock can never be reserved below zero, whatever the application does
+ALTER TABLE inventory
+    ADD CONSTRAINT inventory_available_quantity_non_negative CHECK (available_quantity >= 0),
+    ADD CONSTRAINT inventory_reserved_quantity_non_negative CHECK (reserved_quantity >= 0);
\ No newline at end of file
@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS idx_reservations_expires_at;
+
+ALTER TABLE reservations
+    DROP COLUMN IF EXISTS expires_at,
+    DROP COLUMN IF EXISTS saga_id;
\ No newline at end of file
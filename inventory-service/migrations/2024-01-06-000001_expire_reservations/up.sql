@@ -0,0 +1,7 @@
+-- Reservations are held until expires_at; the sweeper releases the ones still reserved after it.
+-- Reservations made before this have no expiry, as their orders may have been approved already
+ALTER TABLE reservations
+    ADD COLUMN saga_id UUID,
+    ADD COLUMN expires_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX idx_reservations_expires_at ON reservations(expires_at) WHERE status = 'reserved';
\ No newline at end of file
@@ -0,0 +1,24 @@
+ALTER TABLE reservations DROP COLUMN IF EXISTS warehouse_id;
+
+-- Fold every warehouse's stock back into one row per product
+CREATE TEMPORARY TABLE inventory_totals AS
+    SELECT product_id, SUM(available_quantity)::INTEGER AS available_quantity, SUM(reserved_quantity)::INTEGER AS reserved_quantity
+    FROM inventory
+    GROUP BY product_id;
+
+DELETE FROM inventory;
+
+ALTER TABLE inventory
+    DROP CONSTRAINT inventory_product_id_warehouse_id_key,
+    DROP COLUMN warehouse_id,
+    ADD CONSTRAINT inventory_product_id_key UNIQUE (product_id);
+
+INSERT INTO inventory (product_id, available_quantity, reserved_quantity)
+    SELECT product_id, available_quantity, reserved_quantity FROM inventory_totals;
+
+DROP TABLE inventory_totals;
+
+ALTER TABLE product_prices
+    ADD CONSTRAINT product_prices_product_id_fkey FOREIGN KEY (product_id) REFERENCES inventory(product_id);
+
+DROP TABLE IF EXISTS warehouses;
\ No newline at end of file
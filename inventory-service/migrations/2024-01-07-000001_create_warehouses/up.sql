@@ -0,0 +1,31 @@
+-- Stock is held per warehouse; the existing stock and reservations are at the Newark warehouse
+CREATE TABLE warehouses (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    code VARCHAR(50) NOT NULL UNIQUE,
+    name VARCHAR(255) NOT NULL,
+    latitude DOUBLE PRECISION NOT NULL CHECK (latitude BETWEEN -90 AND 90),
+    longitude DOUBLE PRECISION NOT NULL CHECK (longitude BETWEEN -180 AND 180),
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+INSERT INTO warehouses (id, code, name, latitude, longitude) VALUES
+    ('a0000000-0000-0000-0000-000000000001', 'EWR', 'Newark, NJ', 40.7357, -74.1724),
+    ('a0000000-0000-0000-0000-000000000002', 'RNO', 'Reno, NV', 39.5296, -119.8138);
+
+-- Prices referenced the single inventory row a product had
+ALTER TABLE product_prices DROP CONSTRAINT product_prices_product_id_fkey;
+
+ALTER TABLE inventory ADD COLUMN warehouse_id UUID REFERENCES warehouses(id);
+UPDATE inventory SET warehouse_id = 'a0000000-0000-0000-0000-000000000001';
+ALTER TABLE inventory
+    ALTER COLUMN warehouse_id SET NOT NULL,
+    DROP CONSTRAINT inventory_product_id_key,
+    ADD CONSTRAINT inventory_product_id_warehouse_id_key UNIQUE (product_id, warehouse_id);
+
+INSERT INTO inventory (product_id, warehouse_id, available_quantity) VALUES
+    ('11111111-1111-1111-1111-111111111111', 'a0000000-0000-0000-0000-000000000002', 60),
+    ('22222222-2222-2222-2222-222222222222', 'a0000000-0000-0000-0000-000000000002', 20);
+
+ALTER TABLE reservations ADD COLUMN warehouse_id UUID REFERENCES warehouses(id);
+UPDATE reservations SET warehouse_id = 'a0000000-0000-0000-0000-000000000001';
+ALTER TABLE reservations ALTER COLUMN warehouse_id SET NOT NULL;
\ No newline at end of file
@@ -0,0 +1,194 @@
+use shared::{Allocation, AllocationStrategy, GeoPoint, InventoryLine};
+use std::collections::HashMap;
+use std::fmt;
+use uuid::Uuid;
+use crate::models::{Inventory, Warehouse};
+
+/// Why the lines of an order could not be allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationError {
+    ProductNotFound,
+    InsufficientInventory,
+}
+
+impl fmt::Display for AllocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocationError::ProductNotFound => f.write_str("Product not found"),
+            AllocationError::InsufficientInventory => f.write_str("Insufficient inventory"),
+        }
+    }
+}
+
+/// Ids of `warehouses`, nearest to `ship_to` first. Without a destination,
+/// and between warehouses as far away, they are in the order of their codes.
+pub fn rank_warehouses(warehouses: &[Warehouse], ship_to: Option<&GeoPoint>) -> Vec<Uuid> {
+    let mut ranked: Vec<_> = warehouses
+        .iter()
+        .map(|warehouse| (ship_to.map_or(0.0, |to| to.distance_km(&warehouse.location())), warehouse))
+        .collect();
+    ranked.sort_by(|(a, a_warehouse), (b, b_warehouse)| a.total_cmp(b).then_with(|| a_warehouse.code.cmp(&b_warehouse.code)));
+    ranked.into_iter().map(|(_, warehouse)| warehouse.id).collect()
+}
+
+/// Decides where each of `lines` is reserved from the `stock` held at the
+/// warehouses, trying them in the order of `ranked`.
+pub fn allocate(
+    lines: &[InventoryLine],
+    stock: &[Inventory],
+    ranked: &[Uuid],
+    strategy: AllocationStrategy,
+) -> Result<Vec<Allocation>, AllocationError> {
+    if lines.iter().any(|line| !stock.iter().any(|item| item.product_id == line.product_id)) {
+        return Err(AllocationError::ProductNotFound);
+    }
+
+    let available: HashMap<(Uuid, Uuid), i32> = stock
+        .iter()
+        .map(|item| ((item.product_id, item.warehouse_id), item.available_quantity))
+        .collect();
+
+    match strategy {
+        AllocationStrategy::SingleShipment => ranked
+            .iter()
+            .find_map(|&warehouse_id| allocate_from(lines, available.clone(), &[warehouse_id]))
+            .ok_or(AllocationError::InsufficientInventory),
+        AllocationStrategy::SplitShipment => {
+            allocate_from(lines, available, ranked).ok_or(AllocationError::InsufficientInventory)
+        }
+    }
+}
+
+/// Takes each line from the first of `warehouse_ids` that have some of it
+/// left, or `None` if they do not have enough between them.
+fn allocate_from(
+    lines: &[InventoryLine],
+    mut available: HashMap<(Uuid, Uuid), i32>,
+    warehouse_ids: &[Uuid],
+) -> Option<Vec<Allocation>> {
+    let mut allocations = Vec::new();
+
+    for line in lines {
+        let mut remaining = line.quantity;
+        for &warehouse_id in warehouse_ids {
+            let Some(left) = available.get_mut(&(line.product_id, warehouse_id)) else { continue };
+            let quantity = remaining.min(*left);
+            if quantity > 0 {
+                *left -= quantity;
+                remaining -= quantity;
+                allocations.push(Allocation {
+                    product_id: line.product_id,
+                    warehouse_id,
+                    quantity,
+                });
+            }
+            if remaining == 0 {
+                break;
+            }
+        }
+        if remaining > 0 {
+            return None;
+        }
+    }
+
+    Some(allocations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEWARK: GeoPoint = GeoPoint { latitude: 40.7357, longitude: -74.1724 };
+    const RENO: GeoPoint = GeoPoint { latitude: 39.5296, longitude: -119.8138 };
+    const SACRAMENTO: GeoPoint = GeoPoint { latitude: 38.5816, longitude: -121.4944 };
+
+    fn warehouse(code: &str, location: GeoPoint) -> Warehouse {
+        Warehouse {
+            id: Uuid::new_v4(),
+            code: code.to_string(),
+            name: code.to_string(),
+            latitude: location.latitude,
+            longitude: location.longitude,
+            created_at: None,
+        }
+    }
+
+    fn stock(product_id: Uuid, warehouse: &Warehouse, available_quantity: i32) -> Inventory {
+        Inventory {
+            id: Uuid::new_v4(),
+            product_id,
+            available_quantity,
+            reserved_quantity: 0,
+            created_at: None,
+            updated_at: None,
+            warehouse_id: warehouse.id,
+        }
+    }
+
+    fn line(product_id: Uuid, quantity: i32) -> InventoryLine {
+        InventoryLine { product_id, quantity }
+    }
+
+    fn allocated(allocations: &[Allocation]) -> Vec<(Uuid, Uuid, i32)> {
+        allocations.iter().map(|a| (a.product_id, a.warehouse_id, a.quantity)).collect()
+    }
+
+    #[test]
+    fn warehouses_are_ranked_by_distance_to_the_destination() {
+        let east = warehouse("EWR", NEWARK);
+        let west = warehouse("RNO", RENO);
+        let warehouses = [east.clone(), west.clone()];
+
+        assert_eq!(rank_warehouses(&warehouses, Some(&SACRAMENTO)), [west.id, east.id]);
+        assert_eq!(rank_warehouses(&warehouses, None), [east.id, west.id]);
+    }
+
+    #[test]
+    fn split_shipment_tops_up_a_line_from_the_next_nearest_warehouse() {
+        let (east, west) = (warehouse("EWR", NEWARK), warehouse("RNO", RENO));
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let stock = [stock(first, &east, 10), stock(first, &west, 3), stock(second, &east, 5)];
+        let ranked = [west.id, east.id];
+
+        let allocations = allocate(
+            &[line(first, 5), line(second, 1)],
+            &stock,
+            &ranked,
+            AllocationStrategy::SplitShipment,
+        )
+        .unwrap();
+        assert_eq!(
+            allocated(&allocations),
+            [(first, west.id, 3), (first, east.id, 2), (second, east.id, 1)]
+        );
+
+        let short = allocate(&[line(first, 14)], &stock, &ranked, AllocationStrategy::SplitShipment);
+        assert_eq!(short.unwrap_err(), AllocationError::InsufficientInventory);
+    }
+
+    #[test]
+    fn single_shipment_comes_from_the_nearest_warehouse_that_has_everything() {
+        let (east, west) = (warehouse("EWR", NEWARK), warehouse("RNO", RENO));
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let stock = [stock(first, &east, 10), stock(first, &west, 10), stock(second, &east, 5)];
+        let ranked = [west.id, east.id];
+        let lines = [line(first, 2), line(second, 2)];
+
+        let allocations = allocate(&lines, &stock, &ranked, AllocationStrategy::SingleShipment).unwrap();
+        assert_eq!(allocated(&allocations), [(first, east.id, 2), (second, east.id, 2)]);
+
+        // Neither warehouse has 12 on its own, although both have together
+        let lines = [line(first, 12)];
+        let result = allocate(&lines, &stock, &ranked, AllocationStrategy::SingleShipment);
+        assert_eq!(result.unwrap_err(), AllocationError::InsufficientInventory);
+    }
+
+    #[test]
+    fn product_without_stock_anywhere_is_not_found() {
+        let east = warehouse("EWR", NEWARK);
+        let stock = [stock(Uuid::new_v4(), &east, 10)];
+
+        let result = allocate(&[line(Uuid::new_v4(), 1)], &stock, &[east.id], AllocationStrategy::SplitShipment);
+        assert_eq!(result.unwrap_err(), AllocationError::ProductNotFound);
+    }
+}
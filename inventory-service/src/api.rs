@@ -0,0 +1,133 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+    routing::get,
+    Router,
+};
+use diesel::prelude::*;
+use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+use shared::{Currency, Money};
+use uuid::Uuid;
+use crate::models::ProductPrice;
+use crate::schema::product_prices;
+
+type DbPool = Pool<AsyncPgConnection>;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: DbPool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProductPriceResponse {
+    pub product_id: Uuid,
+    pub unit_price: Money,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<Json<T>, (StatusCode, Json<ErrorResponse>)>;
+
+pub fn create_router(state: AppState) -> Router {
+    Router::new()
+        .route("/products/:id/price", get(get_product_price))
+        .route("/health", get(health_check))
+        .with_state(state)
+}
+
+async fn get_product_price(
+    State(state): State<AppState>,
+    Path(product_id): Path<Uuid>,
+) -> ApiResult<ProductPriceResponse> {
+    let mut conn = state.pool.get().await.map_err(internal_error)?;
+
+    let price = product_prices::table
+        .find(product_id)
+        .first::<ProductPrice>(&mut conn)
+        .await
+        .optional()
+        .map_err(internal_error)?
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(ErrorResponse { error: format!("No price for product {}", product_id) }),
+            )
+        })?;
+
+    let currency: Currency = price.currency.parse().map_err(internal_error)?;
+    // Prices are stored with four decimal places; quote them in the currency's
+    // minor units, which must not lose any of them
+    let amount = price.unit_price.with_scale(i64::from(currency.minor_units()));
+    if amount != price.unit_price {
+        return Err(internal_error(format!(
+            "price {} of product {} has more decimal places than {} allows",
+            price.unit_price, product_id, currency
+        )));
+    }
+
+    Ok(Json(ProductPriceResponse {
+        product_id: price.product_id,
+        unit_price: Money::new(amount, currency),
+    }))
+}
+
+pub async fn health_check() -> &'static str {
+    "OK"
+}
+
+fn internal_error(error: impl std::fmt::Display) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!("Price lookup failed: {}", error);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(ErrorResponse { error: "Internal server error".to_string() }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    async fn price_of(pool: &DbPool, unit_price: &str, currency: &str) -> Result<Money, StatusCode> {
+        let product_id = Uuid::new_v4();
+        let mut conn = pool.get().await.unwrap();
+        diesel::insert_into(product_prices::table)
+            .values((
+                product_prices::product_id.eq(product_id),
+                product_prices::unit_price.eq(unit_price.parse::<bigdecimal::BigDecimal>().unwrap()),
+                product_prices::currency.eq(currency),
+            ))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        let state = AppState { pool: pool.clone() };
+        get_product_price(State(state), Path(product_id))
+            .await
+            .map(|Json(response)| response.unit_price)
+            .map_err(|(status, _)| status)
+    }
+
+    #[tokio::test]
+    async fn price_is_quoted_in_the_minor_units_of_its_currency() {
+        let Some(pool) = test_pool().await else { return };
+
+        let price = price_of(&pool, "149.9900", "USD").await.unwrap();
+        assert_eq!(serde_json::to_value(&price).unwrap()["amount"], "149.99");
+        let price = price_of(&pool, "1500.0000", "JPY").await.unwrap();
+        assert_eq!(serde_json::to_value(&price).unwrap()["amount"], "1500");
+    }
+
+    #[tokio::test]
+    async fn price_the_currency_cannot_represent_is_not_quoted() {
+        let Some(pool) = test_pool().await else { return };
+
+        assert_eq!(price_of(&pool, "0.0049", "USD").await, Err(StatusCode::INTERNAL_SERVER_ERROR));
+        assert_eq!(price_of(&pool, "149.99", "JPY").await, Err(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+}
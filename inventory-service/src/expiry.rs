@@ -0,0 +1,215 @@
+use anyhow::Result;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel_async::{pooled_connection::bb8::Pool, AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use shared::outbox::NewOutboxEvent;
+use shared::schema::outbox_events;
+use shared::InventoryEvent;
+use std::time::Duration;
+use tracing::{error, info};
+use uuid::Uuid;
+use crate::models::Reservation;
+use crate::schema::{inventory, reservations};
+
+type DbPool = Pool<AsyncPgConnection>;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+const SWEEP_BATCH_SIZE: i64 = 100;
+
+/// Releases reservations that are still `reserved` after their `expires_at`,
+/// e.g. because their saga stalled, and announces each release on the
+/// inventory event topic so the orchestrator can compensate the saga.
+pub struct ReservationSweeper {
+    pool: DbPool,
+    event_topic: String,
+}
+
+impl ReservationSweeper {
+    pub fn new(pool: DbPool, event_topic: String) -> Self {
+        Self { pool, event_topic }
+    }
+
+    pub async fn run(&self) {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            match self.release_expired().await {
+                Ok(0) => {}
+                Ok(released) => info!("Released {} expired reservations", released),
+                Err(e) => error!("Error releasing expired reservations: {}", e),
+            }
+        }
+    }
+
+    /// Releases one batch of expired reservations and returns how many. The
+    /// stock, the reservation and the outbox event change in one transaction;
+    /// rows locked by a concurrent compensation are left for the next sweep.
+    pub async fn release_expired(&self) -> Result<usize> {
+        let mut conn = self.pool.get().await?;
+        let event_topic = self.event_topic.clone();
+
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            Box::pin(async move {
+                let now = Utc::now();
+                let expired = reservations::table
+                    .filter(reservations::status.eq("reserved"))
+                    .filter(reservations::expires_at.lt(now))
+                    .order(reservations::expires_at.asc())
+                    .limit(SWEEP_BATCH_SIZE)
+                    .for_update()
+                    .skip_locked()
+                    .load::<Reservation>(conn)
+                    .await?;
+
+                for reservation in &expired {
+                    diesel::update(
+                        inventory::table
+                            .filter(inventory::product_id.eq(reservation.product_id))
+                            .filter(inventory::warehouse_id.eq(reservation.warehouse_id)),
+                    )
+                    .set((
+                        inventory::available_quantity.eq(inventory::available_quantity + reservation.quantity),
+                        inventory::reserved_quantity.eq(inventory::reserved_quantity - reservation.quantity),
+                    ))
+                    .execute(conn)
+                    .await?;
+
+                    diesel::update(reservations::table.filter(reservations::id.eq(reservation.id)))
+                        .set((reservations::status.eq("expired"), reservations::updated_at.eq(now)))
+                        .execute(conn)
+                        .await?;
+
+                    let event = InventoryEvent::ReservationExpired {
+                        reservation_id: reservation.id,
+                        saga_id: reservation.saga_id,
+                        order_id: reservation.order_id,
+                        product_id: reservation.product_id,
+                        quantity: reservation.quantity,
+                        expired_at: now,
+                    };
+                    diesel::insert_into(outbox_events::table)
+                        .values(&NewOutboxEvent {
+                            id: Uuid::new_v4(),
+                            aggregate_id: reservation.saga_id.unwrap_or(reservation.order_id),
+                            event_type: "ReservationExpired".to_string(),
+                            event_data: serde_json::to_value(&event)?,
+                            topic: Some(event_topic.clone()),
+                        })
+                        .execute(conn)
+                        .await?;
+
+                    info!(
+                        "Reservation {} of order {} expired, released {} of product {}",
+                        reservation.id, reservation.order_id, reservation.quantity, reservation.product_id
+                    );
+                }
+
+                Ok(expired.len())
+            })
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::CommandHandler;
+    use crate::models::Inventory;
+    use crate::test_support::{stock_product, test_pool};
+    use shared::inbox::CommandProcessor;
+    use shared::outbox::DbOutboxEvent;
+    use shared::*;
+
+    /// Reserves `quantity` of `product_id` for a new saga with a reservation
+    /// that expires right away, and returns the reserve command.
+    async fn reserve_expiring(pool: &DbPool, product_id: Uuid, quantity: i32) -> Command {
+        let handler = CommandHandler::new(pool.clone(), "order-replies".to_string(), Duration::ZERO);
+        let payload = InventoryData {
+            order_id: Uuid::new_v4(),
+            lines: vec![InventoryLine { product_id, quantity }],
+            ship_to: None,
+            allocation_strategy: AllocationStrategy::default(),
+            allocations: Vec::new(),
+        };
+        let command = Command::new(
+            Uuid::new_v4(),
+            2,
+            0,
+            CommandType::ReserveInventory,
+            serde_json::to_value(&payload).unwrap(),
+        );
+
+        let mut conn = pool.get().await.unwrap();
+        let reply = handler.process(&mut conn, &command).await.unwrap();
+        assert_eq!(reply.status, CommandStatus::Success);
+        command
+    }
+
+    async fn load_inventory(pool: &DbPool, product_id: Uuid) -> Inventory {
+        let mut conn = pool.get().await.unwrap();
+        inventory::table
+            .filter(inventory::product_id.eq(product_id))
+            .first::<Inventory>(&mut conn)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn expired_reservation_is_released_and_announced() {
+        let Some(pool) = test_pool().await else { return };
+        let product_id = stock_product(&pool, 10).await;
+        let command = reserve_expiring(&pool, product_id, 4).await;
+
+        ReservationSweeper::new(pool.clone(), "inventory-events".to_string())
+            .release_expired()
+            .await
+            .unwrap();
+
+        let item = load_inventory(&pool, product_id).await;
+        assert_eq!((item.available_quantity, item.reserved_quantity), (10, 0));
+
+        let mut conn = pool.get().await.unwrap();
+        let reservation = reservations::table
+            .filter(reservations::product_id.eq(product_id))
+            .first::<Reservation>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(reservation.status, "expired");
+
+        let events = outbox_events::table
+            .filter(outbox_events::aggregate_id.eq(command.saga_id))
+            .filter(outbox_events::event_type.eq("ReservationExpired"))
+            .load::<DbOutboxEvent>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].topic.as_deref(), Some("inventory-events"));
+        let InventoryEvent::ReservationExpired { saga_id, quantity, .. } =
+            serde_json::from_value(events[0].event_data.clone()).unwrap();
+        assert_eq!((saga_id, quantity), (Some(command.saga_id), 4));
+    }
+
+    #[tokio::test]
+    async fn compensating_an_expired_reservation_releases_nothing_more() {
+        let Some(pool) = test_pool().await else { return };
+        let product_id = stock_product(&pool, 10).await;
+        let reserve = reserve_expiring(&pool, product_id, 4).await;
+
+        ReservationSweeper::new(pool.clone(), "inventory-events".to_string())
+            .release_expired()
+            .await
+            .unwrap();
+
+        let handler = CommandHandler::new(pool.clone(), "order-replies".to_string(), Duration::ZERO);
+        let compensate = Command::new(reserve.saga_id, 2, 0, CommandType::CompensateInventory, reserve.payload);
+        let mut conn = pool.get().await.unwrap();
+        let reply = handler.process(&mut conn, &compensate).await.unwrap();
+        assert_eq!(reply.status, CommandStatus::Success);
+
+        let item = load_inventory(&pool, product_id).await;
+        assert_eq!((item.available_quantity, item.reserved_quantity), (10, 0));
+    }
+}
@@ -1,14 +1,16 @@
 use anyhow::Result;
+use chrono::Utc;
 use diesel::prelude::*;
-use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection, RunQueryDsl, AsyncConnection};
+use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection, RunQueryDsl};
 use futures::StreamExt;
 use rdkafka::consumer::{Consumer, StreamConsumer};
-use rdkafka::producer::{FutureProducer, FutureRecord};
 use rdkafka::Message;
 use std::time::Duration;
 use tracing::{error, info, warn};
 use uuid::Uuid;
 use shared::*;
+use shared::inbox::{CommandProcessor, Inbox};
+use crate::allocation::{allocate, rank_warehouses};
 use crate::models::*;
 use crate::schema::*;
 
@@ -16,13 +18,18 @@ type DbPool = Pool<AsyncPgConnection>;
 
 pub struct CommandHandler {
     pool: DbPool,
-    producer: FutureProducer,
-    reply_topic: String,
+    inbox: Inbox,
+    /// How long a reservation is held before the sweeper releases it.
+    reservation_ttl: Duration,
 }
 
 impl CommandHandler {
-    pub fn new(pool: DbPool, producer: FutureProducer, reply_topic: String) -> Self {
-        Self { pool, producer, reply_topic }
+    pub fn new(pool: DbPool, reply_topic: String, reservation_ttl: Duration) -> Self {
+        Self {
+            pool,
+            inbox: Inbox::new(reply_topic),
+            reservation_ttl,
+        }
     }
 
     pub async fn run(&self, consumer: StreamConsumer) {
@@ -54,153 +61,194 @@ impl CommandHandler {
 
     async fn handle_command(&self, command: Command) -> Result<()> {
         let mut conn = self.pool.get().await?;
-
-        if let Some(existing) = self.check_idempotency(&mut conn, &command.idempotency_key).await? {
-            info!("Command already processed, returning cached result");
-            let reply = CommandReply {
-                id: Uuid::new_v4(),
-                command_id: command.id,
-                saga_id: command.saga_id,
-                status: CommandStatus::Success,
-                result: existing.result,
-                error: None,
-                created_at: chrono::Utc::now(),
-            };
-            self.send_reply(reply).await?;
-            return Ok(());
-        }
-
-        let reply = match command.command_type {
-            CommandType::ReserveInventory => self.handle_reserve_inventory(&mut conn, &command).await?,
-            CommandType::CompensateInventory => self.handle_compensate_inventory(&mut conn, &command).await?,
-            _ => {
-                warn!("Unsupported command type: {:?}", command.command_type);
-                CommandReply::failed(
-                    command.id,
-                    command.saga_id,
-                    "Unsupported command type".to_string(),
-                )
-            }
-        };
-
-        self.store_processed_command(&mut conn, &command, &reply).await?;
-        self.send_reply(reply).await?;
-
+        self.inbox.handle(&mut conn, self, &command).await?;
         Ok(())
     }
 
     async fn handle_reserve_inventory(&self, conn: &mut AsyncPgConnection, command: &Command) -> Result<CommandReply> {
         let inventory_data: InventoryData = serde_json::from_value(command.payload.clone())?;
         
-        let existing_reservation = reservations::table
+        let existing_reservations = reservations::table
             .filter(reservations::order_id.eq(inventory_data.order_id))
-            .filter(reservations::product_id.eq(inventory_data.product_id))
-            .first::<Reservation>(conn)
-            .await
-            .optional()?;
+            .filter(reservations::status.eq("reserved"))
+            .order((reservations::product_id, reservations::warehouse_id))
+            .load::<Reservation>(conn)
+            .await?;
 
-        if let Some(reservation) = existing_reservation {
-            if reservation.status == "reserved" {
-                return Ok(CommandReply::success(
-                    command.id,
-                    command.saga_id,
-                    Some(serde_json::to_value(&reservation)?),
-                ));
-            }
+        if !existing_reservations.is_empty() {
+            let allocations: Vec<_> = existing_reservations.iter().map(Reservation::allocation).collect();
+            return Ok(CommandReply::success(
+                command.id,
+                command.saga_id,
+                Some(serde_json::json!({"reserved": true, "allocations": allocations})),
+            ));
         }
 
-        let inventory_item = inventory::table
-            .filter(inventory::product_id.eq(inventory_data.product_id))
-            .first::<Inventory>(conn)
-            .await
-            .optional()?;
+        // Locking every warehouse's stock of the order's products, in one
+        // order, makes concurrent reservations of the same products wait for
+        // each other: stock cannot be oversold, nor can two orders deadlock
+        let product_ids: Vec<Uuid> = inventory_data.lines.iter().map(|line| line.product_id).collect();
+        let stock = inventory::table
+            .filter(inventory::product_id.eq_any(&product_ids))
+            .order((inventory::product_id, inventory::warehouse_id))
+            .for_update()
+            .load::<Inventory>(conn)
+            .await?;
 
-        let inventory_item = match inventory_item {
-            Some(item) => item,
-            None => {
-                return Ok(CommandReply::failed(
-                    command.id,
-                    command.saga_id,
-                    "Product not found".to_string(),
-                ));
+        let warehouse_ids: Vec<Uuid> = stock.iter().map(|item| item.warehouse_id).collect();
+        let warehouses = warehouses::table
+            .filter(warehouses::id.eq_any(&warehouse_ids))
+            .load::<Warehouse>(conn)
+            .await?;
+        let ranked = rank_warehouses(&warehouses, inventory_data.ship_to.as_ref());
+
+        let allocations = match allocate(&inventory_data.lines, &stock, &ranked, inventory_data.allocation_strategy) {
+            Ok(allocations) => allocations,
+            Err(e) => {
+                info!("Cannot reserve inventory for order {}: {}", inventory_data.order_id, e);
+                return Ok(CommandReply::failed(command.id, command.saga_id, e.to_string()));
             }
         };
 
-        if inventory_item.available_quantity < inventory_data.quantity {
+        let expires_at = Utc::now() + chrono::Duration::from_std(self.reservation_ttl)?;
+        for allocation in &allocations {
+            // The decrement also only applies while the stock is there, so an
+            // allocation that does not fit cannot oversell either; failing
+            // rolls back the lines reserved before it
+            let reserved = diesel::update(
+                inventory::table
+                    .filter(inventory::product_id.eq(allocation.product_id))
+                    .filter(inventory::warehouse_id.eq(allocation.warehouse_id))
+                    .filter(inventory::available_quantity.ge(allocation.quantity)),
+            )
+            .set((
+                inventory::available_quantity.eq(inventory::available_quantity - allocation.quantity),
+                inventory::reserved_quantity.eq(inventory::reserved_quantity + allocation.quantity),
+            ))
+            .execute(conn)
+            .await?;
+            if reserved == 0 {
+                return Err(anyhow::anyhow!(
+                    "Stock of product {} at warehouse {} changed while order {} was being reserved",
+                    allocation.product_id, allocation.warehouse_id, inventory_data.order_id
+                ));
+            }
+
+            let new_reservation = NewReservation {
+                id: Uuid::new_v4(),
+                product_id: allocation.product_id,
+                order_id: inventory_data.order_id,
+                quantity: allocation.quantity,
+                status: "reserved".to_string(),
+                saga_id: Some(command.saga_id),
+                expires_at: Some(expires_at),
+                warehouse_id: allocation.warehouse_id,
+            };
+
+            diesel::insert_into(reservations::table)
+                .values(&new_reservation)
+                .execute(conn)
+                .await?;
+        }
+
+        Ok(CommandReply::success(
+            command.id,
+            command.saga_id,
+            Some(serde_json::json!({"reserved": true, "allocations": allocations})),
+        ))
+    }
+
+    async fn handle_commit_inventory(&self, conn: &mut AsyncPgConnection, command: &Command) -> Result<CommandReply> {
+        let inventory_data: InventoryData = serde_json::from_value(command.payload.clone())?;
+        let held = held_reservations(conn, &inventory_data).await?;
+
+        let committed: Vec<(Uuid, Uuid)> = reservations::table
+            .filter(reservations::order_id.eq(inventory_data.order_id))
+            .filter(reservations::status.eq("committed"))
+            .select((reservations::product_id, reservations::warehouse_id))
+            .load(conn)
+            .await?;
+
+        // Every line has to be committed, now or by an earlier attempt; a
+        // location released in the meantime fails them all
+        let covered = |product_id: Uuid, warehouse_id: Option<Uuid>| {
+            held.iter()
+                .map(|reservation| (reservation.product_id, reservation.warehouse_id))
+                .chain(committed.iter().copied())
+                .any(|(p, w)| p == product_id && warehouse_id.is_none_or(|id| id == w))
+        };
+        let complete = if inventory_data.allocations.is_empty() {
+            inventory_data.lines.iter().all(|line| covered(line.product_id, None))
+        } else {
+            inventory_data
+                .allocations
+                .iter()
+                .all(|allocation| covered(allocation.product_id, Some(allocation.warehouse_id)))
+        };
+        if !complete {
             return Ok(CommandReply::failed(
                 command.id,
                 command.saga_id,
-                "Insufficient inventory".to_string(),
+                "No reservation to commit".to_string(),
             ));
         }
 
-        conn.transaction::<_, anyhow::Error, _>(|conn| {
-            Box::pin(async move {
-                diesel::update(inventory::table.filter(inventory::product_id.eq(inventory_data.product_id)))
-                    .set((
-                        inventory::available_quantity.eq(inventory::available_quantity - inventory_data.quantity),
-                        inventory::reserved_quantity.eq(inventory::reserved_quantity + inventory_data.quantity),
-                    ))
-                    .execute(conn)
-                    .await?;
-
-                let new_reservation = NewReservation {
-                    id: Uuid::new_v4(),
-                    product_id: inventory_data.product_id,
-                    order_id: inventory_data.order_id,
-                    quantity: inventory_data.quantity,
-                    status: "reserved".to_string(),
-                };
-
-                diesel::insert_into(reservations::table)
-                    .values(&new_reservation)
-                    .execute(conn)
-                    .await?;
-
-                Ok(())
-            })
-        }).await?;
+        // Moving the reservations out of `reserved` first keeps the expiry
+        // sweeper from releasing stock that has been committed
+        for reservation in &held {
+            diesel::update(reservations::table.filter(reservations::id.eq(reservation.id)))
+                .set((reservations::status.eq("committed"), reservations::updated_at.eq(Utc::now())))
+                .execute(conn)
+                .await?;
+
+            diesel::update(
+                inventory::table
+                    .filter(inventory::product_id.eq(reservation.product_id))
+                    .filter(inventory::warehouse_id.eq(reservation.warehouse_id)),
+            )
+            .set(inventory::reserved_quantity.eq(inventory::reserved_quantity - reservation.quantity))
+            .execute(conn)
+            .await?;
+
+            info!(
+                "Committed {} of product {} at warehouse {} for order {}",
+                reservation.quantity, reservation.product_id, reservation.warehouse_id, reservation.order_id
+            );
+        }
 
+        let allocations: Vec<_> = held.iter().map(Reservation::allocation).collect();
         Ok(CommandReply::success(
             command.id,
             command.saga_id,
-            Some(serde_json::json!({"reserved": true, "quantity": inventory_data.quantity})),
+            Some(serde_json::json!({"committed": true, "allocations": allocations})),
         ))
     }
 
     async fn handle_compensate_inventory(&self, conn: &mut AsyncPgConnection, command: &Command) -> Result<CommandReply> {
         let inventory_data: InventoryData = serde_json::from_value(command.payload.clone())?;
-        
-        let reservation = reservations::table
-            .filter(reservations::order_id.eq(inventory_data.order_id))
-            .filter(reservations::product_id.eq(inventory_data.product_id))
-            .first::<Reservation>(conn)
-            .await
-            .optional()?;
-
-        if let Some(reservation) = reservation {
-            if reservation.status == "reserved" {
-                conn.transaction::<_, anyhow::Error, _>(|conn| {
-                    Box::pin(async move {
-                        diesel::update(inventory::table.filter(inventory::product_id.eq(inventory_data.product_id)))
-                            .set((
-                                inventory::available_quantity.eq(inventory::available_quantity + reservation.quantity),
-                                inventory::reserved_quantity.eq(inventory::reserved_quantity - reservation.quantity),
-                            ))
-                            .execute(conn)
-                            .await?;
-
-                        diesel::update(reservations::table.filter(reservations::id.eq(reservation.id)))
-                            .set(reservations::status.eq("cancelled"))
-                            .execute(conn)
-                            .await?;
-
-                        Ok(())
-                    })
-                }).await?;
-
-                info!("Inventory reservation cancelled for order: {}", inventory_data.order_id);
-            }
+        let held = held_reservations(conn, &inventory_data).await?;
+
+        for reservation in &held {
+            diesel::update(reservations::table.filter(reservations::id.eq(reservation.id)))
+                .set((reservations::status.eq("cancelled"), reservations::updated_at.eq(Utc::now())))
+                .execute(conn)
+                .await?;
+
+            diesel::update(
+                inventory::table
+                    .filter(inventory::product_id.eq(reservation.product_id))
+                    .filter(inventory::warehouse_id.eq(reservation.warehouse_id)),
+            )
+            .set((
+                inventory::available_quantity.eq(inventory::available_quantity + reservation.quantity),
+                inventory::reserved_quantity.eq(inventory::reserved_quantity - reservation.quantity),
+            ))
+            .execute(conn)
+            .await?;
+        }
+
+        if !held.is_empty() {
+            info!("Inventory reservation cancelled for order: {}", inventory_data.order_id);
         }
 
         Ok(CommandReply::success(
@@ -209,42 +257,256 @@ impl CommandHandler {
             Some(serde_json::json!({"compensated": true})),
         ))
     }
+}
+
+/// The order's reservations that are still `reserved`, at the locations
+/// `inventory_data` was allocated, or at all of them if it names none. They
+/// are locked, so only one of a compensation, a commit and the expiry
+/// sweeper racing for them moves them out of `reserved` and changes the
+/// stock.
+async fn held_reservations(conn: &mut AsyncPgConnection, inventory_data: &InventoryData) -> Result<Vec<Reservation>> {
+    let held = reservations::table
+        .filter(reservations::order_id.eq(inventory_data.order_id))
+        .filter(reservations::status.eq("reserved"))
+        .order((reservations::product_id, reservations::warehouse_id))
+        .for_update()
+        .load::<Reservation>(conn)
+        .await?;
+
+    Ok(held
+        .into_iter()
+        .filter(|reservation| {
+            inventory_data.allocations.is_empty()
+                || inventory_data.allocations.iter().any(|allocation| {
+                    allocation.product_id == reservation.product_id && allocation.warehouse_id == reservation.warehouse_id
+                })
+        })
+        .collect())
+}
+
+impl CommandProcessor for CommandHandler {
+    async fn process(&self, conn: &mut AsyncPgConnection, command: &Command) -> Result<CommandReply> {
+        let reply = match command.command_type {
+            CommandType::ReserveInventory => self.handle_reserve_inventory(conn, command).await?,
+            CommandType::CommitInventory => self.handle_commit_inventory(conn, command).await?,
+            CommandType::CompensateInventory => self.handle_compensate_inventory(conn, command).await?,
+            _ => {
+                warn!("Unsupported command type: {:?}", command.command_type);
+                CommandReply::failed(
+                    command.id,
+                    command.saga_id,
+                    "Unsupported command type".to_string(),
+                )
+            }
+        };
+
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{add_warehouse, stock_product, stock_product_at, test_pool};
+
+    async fn send(handler: &CommandHandler, command_type: CommandType, payload: &InventoryData) -> CommandReply {
+        let command = Command::new(Uuid::new_v4(), 1, 0, command_type, serde_json::to_value(payload).unwrap());
+        let mut conn = handler.pool.get().await.unwrap();
+        handler.inbox.handle(&mut conn, handler, &command).await.unwrap()
+    }
+
+    async fn reserve(handler: &CommandHandler, product_id: Uuid, quantity: i32) -> CommandReply {
+        send(handler, CommandType::ReserveInventory, &order(&[(product_id, quantity)])).await
+    }
 
-    async fn check_idempotency(&self, conn: &mut AsyncPgConnection, key: &str) -> Result<Option<ProcessedCommand>> {
-        let result = processed_commands::table
-            .filter(processed_commands::idempotency_key.eq(key))
-            .first::<ProcessedCommand>(conn)
+    fn order(lines: &[(Uuid, i32)]) -> InventoryData {
+        InventoryData {
+            order_id: Uuid::new_v4(),
+            lines: lines
+                .iter()
+                .map(|&(product_id, quantity)| InventoryLine { product_id, quantity })
+                .collect(),
+            ship_to: None,
+            allocation_strategy: AllocationStrategy::default(),
+            allocations: Vec::new(),
+        }
+    }
+
+    async fn load_inventory(pool: &DbPool, product_id: Uuid) -> Inventory {
+        let mut conn = pool.get().await.unwrap();
+        inventory::table
+            .filter(inventory::product_id.eq(product_id))
+            .first::<Inventory>(&mut conn)
             .await
-            .optional()?;
-        Ok(result)
+            .unwrap()
     }
 
-    async fn store_processed_command(&self, conn: &mut AsyncPgConnection, command: &Command, reply: &CommandReply) -> Result<()> {
-        let processed_command = ProcessedCommand {
-            idempotency_key: command.idempotency_key.clone(),
-            command_id: command.id,
-            result: reply.result.clone(),
-            processed_at: Some(chrono::Utc::now()),
-        };
+    async fn available_at(pool: &DbPool, product_id: Uuid, warehouse_id: Uuid) -> i32 {
+        let mut conn = pool.get().await.unwrap();
+        inventory::table
+            .filter(inventory::product_id.eq(product_id))
+            .filter(inventory::warehouse_id.eq(warehouse_id))
+            .select(inventory::available_quantity)
+            .first(&mut conn)
+            .await
+            .unwrap()
+    }
 
-        diesel::insert_into(processed_commands::table)
-            .values(&processed_command)
-            .execute(conn)
-            .await?;
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_reservations_never_oversell() {
+        let Some(pool) = test_pool().await else { return };
+        let product_id = stock_product(&pool, 15).await;
+        let handler = CommandHandler::new(pool.clone(), "order-replies".to_string(), Duration::from_secs(900));
 
-        Ok(())
+        let replies = futures::future::join_all((0..40).map(|_| reserve(&handler, product_id, 2))).await;
+
+        let succeeded = replies.iter().filter(|r| r.status == CommandStatus::Success).count();
+        assert_eq!(succeeded, 7);
+        assert!(replies
+            .iter()
+            .filter(|r| r.status != CommandStatus::Success)
+            .all(|r| r.error.as_deref() == Some("Insufficient inventory")));
+
+        let item = load_inventory(&pool, product_id).await;
+        assert_eq!(item.available_quantity, 1);
+        assert_eq!(item.reserved_quantity, 14);
+
+        let mut conn = pool.get().await.unwrap();
+
+        let reserved: i64 = reservations::table
+            .filter(reservations::product_id.eq(product_id))
+            .count()
+            .get_result(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(reserved, 7);
+    }
+
+    #[tokio::test]
+    async fn reserving_an_unknown_product_fails() {
+        let Some(pool) = test_pool().await else { return };
+        let handler = CommandHandler::new(pool, "order-replies".to_string(), Duration::from_secs(900));
+
+        let reply = reserve(&handler, Uuid::new_v4(), 1).await;
+        assert_eq!(reply.status, CommandStatus::Failed);
+        assert_eq!(reply.error.as_deref(), Some("Product not found"));
     }
 
-    async fn send_reply(&self, reply: CommandReply) -> Result<()> {
-        let json = serde_json::to_string(&reply)?;
-        let key = reply.saga_id.to_string();
-        let record = FutureRecord::to(&self.reply_topic)
-            .payload(&json)
-            .key(&key);
+    #[tokio::test]
+    async fn committing_a_reservation_deducts_its_stock_for_good() {
+        let Some(pool) = test_pool().await else { return };
+        let product_id = stock_product(&pool, 10).await;
+        let handler = CommandHandler::new(pool.clone(), "order-replies".to_string(), Duration::from_secs(900));
+        let order = order(&[(product_id, 3)]);
+
+        assert_eq!(send(&handler, CommandType::ReserveInventory, &order).await.status, CommandStatus::Success);
+        assert_eq!(send(&handler, CommandType::CommitInventory, &order).await.status, CommandStatus::Success);
+        // A second commit, e.g. from a retried step, deducts nothing more
+        assert_eq!(send(&handler, CommandType::CommitInventory, &order).await.status, CommandStatus::Success);
+        // Neither does compensating it
+        assert_eq!(send(&handler, CommandType::CompensateInventory, &order).await.status, CommandStatus::Success);
+
+        let item = load_inventory(&pool, product_id).await;
+        assert_eq!((item.available_quantity, item.reserved_quantity), (7, 0));
+    }
 
-        self.producer.send(record, Duration::from_secs(5)).await
-            .map_err(|(e, _)| anyhow::anyhow!("Failed to send reply: {}", e))?;
+    #[tokio::test]
+    async fn released_reservation_cannot_be_committed() {
+        let Some(pool) = test_pool().await else { return };
+        let product_id = stock_product(&pool, 10).await;
+        let handler = CommandHandler::new(pool.clone(), "order-replies".to_string(), Duration::from_secs(900));
+        let order = order(&[(product_id, 3)]);
 
-        Ok(())
+        send(&handler, CommandType::ReserveInventory, &order).await;
+        send(&handler, CommandType::CompensateInventory, &order).await;
+        let reply = send(&handler, CommandType::CommitInventory, &order).await;
+
+        assert_eq!(reply.status, CommandStatus::Failed);
+        let item = load_inventory(&pool, product_id).await;
+        assert_eq!((item.available_quantity, item.reserved_quantity), (10, 0));
+    }
+
+    #[tokio::test]
+    async fn order_is_reserved_in_full_or_not_at_all() {
+        let Some(pool) = test_pool().await else { return };
+        let plenty = stock_product(&pool, 10).await;
+        let scarce = stock_product(&pool, 1).await;
+        let handler = CommandHandler::new(pool.clone(), "order-replies".to_string(), Duration::from_secs(900));
+
+        let reply = send(&handler, CommandType::ReserveInventory, &order(&[(plenty, 4), (scarce, 2)])).await;
+        assert_eq!(reply.status, CommandStatus::Failed);
+        assert_eq!(reply.error.as_deref(), Some("Insufficient inventory"));
+
+        let item = load_inventory(&pool, plenty).await;
+        assert_eq!((item.available_quantity, item.reserved_quantity), (10, 0));
+        let mut conn = pool.get().await.unwrap();
+        let reservations: i64 = reservations::table
+            .filter(reservations::product_id.eq_any([plenty, scarce]))
+            .count()
+            .get_result(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(reservations, 0);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn compensation_releases_every_line() {
+        let Some(pool) = test_pool().await else { return };
+        let first = stock_product(&pool, 10).await;
+        let second = stock_product(&pool, 5).await;
+        let handler = CommandHandler::new(pool.clone(), "order-replies".to_string(), Duration::from_secs(900));
+        let order = order(&[(first, 4), (second, 5)]);
+
+        assert_eq!(send(&handler, CommandType::ReserveInventory, &order).await.status, CommandStatus::Success);
+        let item = load_inventory(&pool, second).await;
+        assert_eq!((item.available_quantity, item.reserved_quantity), (0, 5));
+
+        assert_eq!(send(&handler, CommandType::CompensateInventory, &order).await.status, CommandStatus::Success);
+        let item = load_inventory(&pool, first).await;
+        assert_eq!((item.available_quantity, item.reserved_quantity), (10, 0));
+        let item = load_inventory(&pool, second).await;
+        assert_eq!((item.available_quantity, item.reserved_quantity), (5, 0));
+    }
+
+    #[tokio::test]
+    async fn reservation_is_allocated_nearest_first_and_released_where_it_was_taken() {
+        let Some(pool) = test_pool().await else { return };
+        let east = add_warehouse(&pool, GeoPoint { latitude: 40.7357, longitude: -74.1724 }).await;
+        let west = add_warehouse(&pool, GeoPoint { latitude: 39.5296, longitude: -119.8138 }).await;
+        let product_id = stock_product_at(&pool, &[(east, 10), (west, 3)]).await;
+        let handler = CommandHandler::new(pool.clone(), "order-replies".to_string(), Duration::from_secs(900));
+        let mut request = order(&[(product_id, 5)]);
+        request.ship_to = Some(GeoPoint { latitude: 38.5816, longitude: -121.4944 });
+
+        let reply = send(&handler, CommandType::ReserveInventory, &request).await;
+        assert_eq!(reply.status, CommandStatus::Success);
+        let allocations: Vec<Allocation> = serde_json::from_value(reply.result.unwrap()["allocations"].clone()).unwrap();
+        let allocated: Vec<_> = allocations.iter().map(|a| (a.warehouse_id, a.quantity)).collect();
+        assert_eq!(allocated, [(west, 3), (east, 2)]);
+        assert_eq!((available_at(&pool, product_id, west).await, available_at(&pool, product_id, east).await), (0, 8));
+
+        request.allocations = allocations;
+        assert_eq!(send(&handler, CommandType::CompensateInventory, &request).await.status, CommandStatus::Success);
+        assert_eq!((available_at(&pool, product_id, west).await, available_at(&pool, product_id, east).await), (3, 10));
+    }
+
+    #[tokio::test]
+    async fn single_shipment_is_reserved_at_one_warehouse() {
+        let Some(pool) = test_pool().await else { return };
+        let east = add_warehouse(&pool, GeoPoint { latitude: 40.7357, longitude: -74.1724 }).await;
+        let west = add_warehouse(&pool, GeoPoint { latitude: 39.5296, longitude: -119.8138 }).await;
+        let product_id = stock_product_at(&pool, &[(east, 10), (west, 3)]).await;
+        let handler = CommandHandler::new(pool.clone(), "order-replies".to_string(), Duration::from_secs(900));
+        let mut request = order(&[(product_id, 5)]);
+        request.ship_to = Some(GeoPoint { latitude: 38.5816, longitude: -121.4944 });
+        request.allocation_strategy = AllocationStrategy::SingleShipment;
+
+        assert_eq!(send(&handler, CommandType::ReserveInventory, &request).await.status, CommandStatus::Success);
+        assert_eq!((available_at(&pool, product_id, west).await, available_at(&pool, product_id, east).await), (3, 5));
+
+        let mut too_much = order(&[(product_id, 6)]);
+        too_much.allocation_strategy = AllocationStrategy::SingleShipment;
+        let reply = send(&handler, CommandType::ReserveInventory, &too_much).await;
+        assert_eq!(reply.error.as_deref(), Some("Insufficient inventory"));
+    }
+}
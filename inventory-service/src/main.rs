@@ -1,6 +1,11 @@
 mod schema;
 mod models;
 mod handlers;
+mod allocation;
+mod api;
+mod expiry;
+#[cfg(test)]
+mod test_support;
 
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use diesel::PgConnection;
@@ -15,7 +20,6 @@ use rdkafka::config::ClientConfig;
 use rdkafka::consumer::{Consumer, StreamConsumer};
 use rdkafka::producer::FutureProducer;
 use std::time::Duration;
-use tokio::time;
 use tracing::info;
 
 #[derive(Parser)]
@@ -32,6 +36,16 @@ struct Args {
     
     #[arg(long, default_value = "order-replies")]
     reply_topic: String,
+    
+    #[arg(long, default_value = "inventory-events")]
+    event_topic: String,
+    
+    /// How long a reservation is held for its saga before it is released
+    #[arg(long, env = "RESERVATION_TTL_SECS", default_value = "900")]
+    reservation_ttl_secs: u64,
+    
+    #[arg(long, env = "PORT", default_value = "3003")]
+    port: u16,
 }
 
 
@@ -64,15 +78,33 @@ async fn main() -> Result<()> {
 
     consumer.subscribe(&[&args.command_topic])?;
 
-    let command_handler = handlers::CommandHandler::new(pool.clone(), producer.clone(), args.reply_topic.clone());
+    let command_handler = handlers::CommandHandler::new(
+        pool.clone(),
+        args.reply_topic.clone(),
+        Duration::from_secs(args.reservation_ttl_secs),
+    );
+    let reservation_sweeper = expiry::ReservationSweeper::new(pool.clone(), args.event_topic.clone());
+    let outbox_processor = shared::outbox::OutboxProcessor::new(pool.clone(), producer.clone());
+
+    tokio::spawn(async move {
+        outbox_processor.run().await;
+    });
 
     tokio::spawn(async move {
         command_handler.run(consumer).await;
     });
 
-    info!("Inventory service started");
+    tokio::spawn(async move {
+        reservation_sweeper.run().await;
+    });
+
+    // Serve the price catalog the order service prices orders from
+    let app = api::create_router(api::AppState { pool: pool.clone() });
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", args.port)).await?;
+
+    info!("Inventory service started, serving prices on port {}", args.port);
+
+    axum::serve(listener, app).await?;
 
-    loop {
-        time::sleep(Duration::from_secs(30)).await;
-    }
+    Ok(())
 }
\ No newline at end of file
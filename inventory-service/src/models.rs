@@ -12,6 +12,7 @@ pub struct Inventory {
     pub reserved_quantity: i32,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
+    pub warehouse_id: Uuid,
 }
 
 #[derive(Debug, Clone, Queryable, Insertable, Serialize, Deserialize)]
@@ -24,6 +25,20 @@ pub struct Reservation {
     pub status: String,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
+    pub saga_id: Option<Uuid>,
+    /// When a reservation still `reserved` is released by the sweeper.
+    pub expires_at: Option<DateTime<Utc>>,
+    pub warehouse_id: Uuid,
+}
+
+impl Reservation {
+    pub fn allocation(&self) -> shared::Allocation {
+        shared::Allocation {
+            product_id: self.product_id,
+            warehouse_id: self.warehouse_id,
+            quantity: self.quantity,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -34,13 +49,36 @@ pub struct NewReservation {
     pub order_id: Uuid,
     pub quantity: i32,
     pub status: String,
+    pub saga_id: Option<Uuid>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub warehouse_id: Uuid,
+}
+
+#[derive(Debug, Clone, Queryable, Identifiable, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::warehouses)]
+pub struct Warehouse {
+    pub id: Uuid,
+    pub code: String,
+    pub name: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub created_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Clone, Queryable, Insertable)]
-#[diesel(table_name = crate::schema::processed_commands)]
-pub struct ProcessedCommand {
-    pub idempotency_key: String,
-    pub command_id: Uuid,
-    pub result: Option<serde_json::Value>,
-    pub processed_at: Option<DateTime<Utc>>,
+impl Warehouse {
+    pub fn location(&self) -> shared::GeoPoint {
+        shared::GeoPoint {
+            latitude: self.latitude,
+            longitude: self.longitude,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Queryable, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::product_prices)]
+pub struct ProductPrice {
+    pub product_id: Uuid,
+    pub unit_price: bigdecimal::BigDecimal,
+    pub currency: String,
+    pub updated_at: DateTime<Utc>,
 }
\ No newline at end of file
@@ -6,15 +6,16 @@ diesel::table! {
         reserved_quantity -> Int4,
         created_at -> Nullable<Timestamptz>,
         updated_at -> Nullable<Timestamptz>,
+        warehouse_id -> Uuid,
     }
 }
 
 diesel::table! {
-    processed_commands (idempotency_key) {
-        idempotency_key -> Varchar,
-        command_id -> Uuid,
-        result -> Nullable<Jsonb>,
-        processed_at -> Nullable<Timestamptz>,
+    product_prices (product_id) {
+        product_id -> Uuid,
+        unit_price -> Numeric,
+        currency -> Varchar,
+        updated_at -> Timestamptz,
     }
 }
 
@@ -27,11 +28,29 @@ diesel::table! {
         status -> Varchar,
         created_at -> Nullable<Timestamptz>,
         updated_at -> Nullable<Timestamptz>,
+        saga_id -> Nullable<Uuid>,
+        expires_at -> Nullable<Timestamptz>,
+        warehouse_id -> Uuid,
+    }
+}
+
+diesel::table! {
+    warehouses (id) {
+        id -> Uuid,
+        code -> Varchar,
+        name -> Varchar,
+        latitude -> Float8,
+        longitude -> Float8,
+        created_at -> Nullable<Timestamptz>,
     }
 }
 
+diesel::joinable!(inventory -> warehouses (warehouse_id));
+diesel::joinable!(reservations -> warehouses (warehouse_id));
+
 diesel::allow_tables_to_appear_in_same_query!(
     inventory,
-    processed_commands,
+    product_prices,
     reservations,
+    warehouses,
 );
\ No newline at end of file
@@ -0,0 +1,79 @@
+use diesel::prelude::*;
+use diesel::Connection;
+use diesel_async::pooled_connection::bb8::Pool;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use diesel_migrations::MigrationHarness;
+use std::sync::Mutex;
+use uuid::Uuid;
+use shared::GeoPoint;
+use crate::schema::{inventory, warehouses};
+
+static MIGRATED: Mutex<bool> = Mutex::new(false);
+
+/// The Newark warehouse the migrations create, which holds the stock of
+/// `stock_product`.
+pub const NEWARK_WAREHOUSE: Uuid = Uuid::from_u128(0xa0000000_0000_0000_0000_000000000001);
+
+/// Pool for the database in `INVENTORY_TEST_DATABASE_URL`; tests that need a
+/// database are skipped when it isn't set.
+pub async fn test_pool() -> Option<Pool<AsyncPgConnection>> {
+    let Ok(url) = std::env::var("INVENTORY_TEST_DATABASE_URL") else {
+        eprintln!("INVENTORY_TEST_DATABASE_URL not set, skipping");
+        return None;
+    };
+
+    {
+        let mut migrated = MIGRATED.lock().unwrap();
+        if !*migrated {
+            let mut conn = diesel::PgConnection::establish(&url).unwrap();
+            conn.run_pending_migrations(crate::MIGRATIONS).unwrap();
+            *migrated = true;
+        }
+    }
+
+    let config = AsyncDieselConnectionManager::<AsyncPgConnection>::new(url);
+    Some(Pool::builder().build(config).await.unwrap())
+}
+
+/// Adds a new product with `available_quantity` in stock and returns its id.
+pub async fn stock_product(pool: &Pool<AsyncPgConnection>, available_quantity: i32) -> Uuid {
+    stock_product_at(pool, &[(NEWARK_WAREHOUSE, available_quantity)]).await
+}
+
+/// Adds a new product with the given available quantity at each warehouse
+/// and returns its id.
+pub async fn stock_product_at(pool: &Pool<AsyncPgConnection>, stock: &[(Uuid, i32)]) -> Uuid {
+    let product_id = Uuid::new_v4();
+    let mut conn = pool.get().await.unwrap();
+    for &(warehouse_id, available_quantity) in stock {
+        diesel::insert_into(inventory::table)
+            .values((
+                inventory::product_id.eq(product_id),
+                inventory::warehouse_id.eq(warehouse_id),
+                inventory::available_quantity.eq(available_quantity),
+            ))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+    }
+    product_id
+}
+
+/// Adds a new warehouse at `location` and returns its id.
+pub async fn add_warehouse(pool: &Pool<AsyncPgConnection>, location: GeoPoint) -> Uuid {
+    let id = Uuid::new_v4();
+    let mut conn = pool.get().await.unwrap();
+    diesel::insert_into(warehouses::table)
+        .values((
+            warehouses::id.eq(id),
+            warehouses::code.eq(id.simple().to_string()),
+            warehouses::name.eq("Test warehouse"),
+            warehouses::latitude.eq(location.latitude),
+            warehouses::longitude.eq(location.longitude),
+        ))
+        .execute(&mut conn)
+        .await
+        .unwrap();
+    id
+}
@@ -0,0 +1,3 @@
+ALTER TABLE saga_transactions
+    DROP COLUMN IF EXISTS definition_version,
+    DROP COLUMN IF EXISTS definition_name;
\ No newline at end of file
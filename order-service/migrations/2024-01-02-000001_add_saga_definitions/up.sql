@@ -0,0 +1,8 @@
+-- Sagas created before definitions existed are all order placement sagas
+ALTER TABLE saga_transactions
+    ADD COLUMN definition_name VARCHAR(255) NOT NULL DEFAULT 'create_order',
+    ADD COLUMN definition_version INTEGER NOT NULL DEFAULT 1;
+
+ALTER TABLE saga_transactions
+    ALTER COLUMN definition_name DROP DEFAULT,
+    ALTER COLUMN definition_version DROP DEFAULT;
@@ -0,0 +1,6 @@
+UPDATE saga_transactions
+SET context = jsonb_set(context, '{compensation_steps}', (
+        SELECT COALESCE(jsonb_agg(steps -> (compensation.value #>> '{}')::int ORDER BY compensation.ordinality), '[]'::jsonb)
+        FROM jsonb_array_elements(context -> 'compensation_steps') WITH ORDINALITY AS compensation(value, ordinality)
+    ))
+WHERE jsonb_typeof(context -> 'compensation_steps' -> 0) = 'number';
\ No newline at end of file
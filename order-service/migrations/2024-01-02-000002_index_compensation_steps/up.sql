@@ -0,0 +1,10 @@
+-- Compensating sagas used to keep copies of the steps to compensate; they now keep the
+-- steps' indices. Command types are unique within a saga, so each copy is matched by its own
+UPDATE saga_transactions
+SET context = jsonb_set(context, '{compensation_steps}', (
+        SELECT COALESCE(jsonb_agg(step.ordinality - 1 ORDER BY compensation.ordinality), '[]'::jsonb)
+        FROM jsonb_array_elements(context -> 'compensation_steps') WITH ORDINALITY AS compensation(value, ordinality)
+        JOIN jsonb_array_elements(steps) WITH ORDINALITY AS step(value, ordinality)
+            ON step.value -> 'command_type' = compensation.value -> 'command_type'
+    ))
+WHERE jsonb_typeof(context -> 'compensation_steps' -> 0) = 'object';
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE saga_transactions DROP COLUMN IF EXISTS pending_command_id;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE saga_transactions ADD COLUMN pending_command_id UUID;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE saga_transactions DROP COLUMN IF EXISTS version;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE saga_transactions ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Explicit destination topic, used for saga commands
+ALTER TABLE outbox_events ADD COLUMN topic VARCHAR(255);
\ No newline at end of file
@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS idx_saga_transactions_deadline;
+
+ALTER TABLE saga_transactions
+    DROP COLUMN IF EXISTS attempts,
+    DROP COLUMN IF EXISTS deadline;
\ No newline at end of file
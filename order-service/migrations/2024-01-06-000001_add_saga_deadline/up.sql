@@ -0,0 +1,5 @@
+ALTER TABLE saga_transactions
+    ADD COLUMN deadline TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX idx_saga_transactions_deadline ON saga_transactions(deadline) WHERE deadline IS NOT NULL;
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE orders
+    DROP COLUMN IF EXISTS currency,
+    ALTER COLUMN total_amount TYPE DECIMAL(10,2);
\ No newline at end of file
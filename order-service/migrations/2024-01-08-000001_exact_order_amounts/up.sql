@@ -0,0 +1,4 @@
+-- Amounts were previously truncated to whole units before being stored
+ALTER TABLE orders
+    ALTER COLUMN total_amount TYPE NUMERIC,
+    ADD COLUMN currency VARCHAR(3) NOT NULL DEFAULT 'USD';
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE orders
+    DROP CONSTRAINT IF EXISTS orders_currency_format,
+    ALTER COLUMN currency SET DEFAULT 'USD';
\ No newline at end of file
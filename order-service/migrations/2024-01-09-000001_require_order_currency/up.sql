@@ -0,0 +1,4 @@
+-- Currency is always supplied by the API, which validates it against ISO 4217
+ALTER TABLE orders
+    ALTER COLUMN currency DROP DEFAULT,
+    ADD CONSTRAINT orders_currency_format CHECK (currency ~ '^[A-Z]{3}$');
\ No newline at end of file
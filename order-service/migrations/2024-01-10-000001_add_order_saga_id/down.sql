@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS idx_orders_customer_created_at;
+DROP INDEX IF EXISTS idx_orders_created_at;
+DROP INDEX IF EXISTS idx_orders_saga_id;
+ALTER TABLE orders DROP COLUMN IF EXISTS saga_id;
\ No newline at end of file
@@ -0,0 +1,11 @@
+ALTER TABLE orders ADD COLUMN saga_id UUID REFERENCES saga_transactions(id);
+
+-- Orders created before this migration are linked through the saga context
+UPDATE orders
+SET saga_id = saga_transactions.id
+FROM saga_transactions
+WHERE (saga_transactions.context -> 'order_data' ->> 'order_id')::uuid = orders.id;
+
+CREATE INDEX idx_orders_saga_id ON orders(saga_id);
+CREATE INDEX idx_orders_created_at ON orders(created_at DESC, id DESC);
+CREATE INDEX idx_orders_customer_created_at ON orders(customer_id, created_at DESC, id DESC);
\ No newline at end of file
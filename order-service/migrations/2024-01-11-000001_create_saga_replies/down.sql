@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_outbox_events_aggregate_id;
+DROP TABLE IF EXISTS saga_replies;
\ No newline at end of file
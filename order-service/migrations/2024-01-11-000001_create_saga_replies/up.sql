@@ -0,0 +1,14 @@
+-- Every reply the saga manager receives, including late and duplicate ones
+CREATE TABLE saga_replies (
+    id UUID PRIMARY KEY,
+    saga_id UUID NOT NULL,
+    command_id UUID NOT NULL,
+    status VARCHAR(50) NOT NULL,
+    result JSONB,
+    error TEXT,
+    replied_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    received_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_saga_replies_saga_id ON saga_replies(saga_id, received_at);
+CREATE INDEX idx_outbox_events_aggregate_id ON outbox_events(aggregate_id, created_at);
\ No newline at end of file
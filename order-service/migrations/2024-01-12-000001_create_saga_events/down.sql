@@ -0,0 +1,13 @@
+DROP TRIGGER IF EXISTS saga_events_append_only ON saga_events;
+DROP FUNCTION IF EXISTS reject_saga_event_changes();
+DROP TABLE IF EXISTS saga_events;
+
+UPDATE saga_transactions
+SET context = context || jsonb_build_object(
+        'compensation_steps', compensation -> 'steps',
+        'compensation_index', compensation -> 'completed',
+        'compensation_round', compensation -> 'round'
+    )
+WHERE compensation IS NOT NULL;
+
+ALTER TABLE saga_transactions DROP COLUMN IF EXISTS compensation;
\ No newline at end of file
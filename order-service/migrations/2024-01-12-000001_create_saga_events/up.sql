@@ -0,0 +1,34 @@
+-- Compensation progress used to live in untyped context keys. Its steps are step indices, as
+-- 2024-01-02-000002_index_compensation_steps rewrote the copies of steps older sagas stored
+ALTER TABLE saga_transactions ADD COLUMN compensation JSONB;
+
+UPDATE saga_transactions
+SET compensation = jsonb_build_object(
+        'steps', context -> 'compensation_steps',
+        'completed', context -> 'compensation_index',
+        'round', COALESCE(context -> 'compensation_round', '0'::jsonb)
+    ),
+    context = context - 'compensation_steps' - 'compensation_index' - 'compensation_round'
+WHERE context ? 'compensation_steps';
+
+-- Append-only log of every saga state transition
+CREATE TABLE saga_events (
+    id BIGSERIAL PRIMARY KEY,
+    saga_id UUID NOT NULL REFERENCES saga_transactions(id),
+    saga_version INTEGER NOT NULL,
+    event_type VARCHAR(100) NOT NULL,
+    data JSONB NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_saga_events_saga_id ON saga_events(saga_id, id);
+
+CREATE FUNCTION reject_saga_event_changes() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'saga_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER saga_events_append_only
+    BEFORE UPDATE OR DELETE ON saga_events
+    FOR EACH ROW EXECUTE FUNCTION reject_saga_event_changes();
\ No newline at end of file
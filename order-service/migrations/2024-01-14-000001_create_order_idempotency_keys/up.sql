@@ -0,0 +1,9 @@
+-- Idempotency-Key headers sent with POST /orders, so that a retried request
+-- returns the order it created the first time instead of creating another
+CREATE TABLE order_idempotency_keys (
+    idempotency_key VARCHAR(255) PRIMARY KEY,
+    request_hash VARCHAR(64) NOT NULL,
+    order_id UUID NOT NULL,
+    saga_id UUID NOT NULL REFERENCES saga_transactions(id),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
\ No newline at end of file
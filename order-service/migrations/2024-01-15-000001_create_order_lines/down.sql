@@ -0,0 +1,9 @@
+ALTER TABLE orders
+    ADD COLUMN product_id UUID,
+    ADD COLUMN quantity INTEGER;
+
+UPDATE orders SET product_id = order_lines.product_id, quantity = order_lines.quantity
+FROM order_lines
+WHERE order_lines.order_id = orders.id AND order_lines.line_number = 1;
+
+DROP TABLE order_lines;
\ No newline at end of file
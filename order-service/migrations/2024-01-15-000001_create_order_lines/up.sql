@@ -0,0 +1,17 @@
+-- An order has one or more lines; the single product and quantity of existing orders become their first line
+CREATE TABLE order_lines (
+    order_id UUID NOT NULL REFERENCES orders(id),
+    line_number INTEGER NOT NULL,
+    product_id UUID NOT NULL,
+    quantity INTEGER NOT NULL,
+    amount NUMERIC NOT NULL,
+    currency VARCHAR(3) NOT NULL,
+    PRIMARY KEY (order_id, line_number)
+);
+
+INSERT INTO order_lines (order_id, line_number, product_id, quantity, amount, currency)
+SELECT id, 1, product_id, quantity, total_amount, currency FROM orders;
+
+ALTER TABLE orders
+    DROP COLUMN product_id,
+    DROP COLUMN quantity;
\ No newline at end of file
@@ -0,0 +1 @@
+DROP INDEX idx_saga_transactions_order_id;
\ No newline at end of file
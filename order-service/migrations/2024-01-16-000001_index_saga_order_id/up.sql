@@ -0,0 +1,3 @@
+-- Sagas are looked up by the order in their context, since the order row
+-- does not exist until the saga has created it
+CREATE INDEX idx_saga_transactions_order_id ON saga_transactions ((context -> 'order_data' ->> 'order_id'));
\ No newline at end of file
@@ -1,31 +1,161 @@
 use axum::{
     extract::State,
-    http::StatusCode,
-    response::Json,
-    routing::post,
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+    routing::{delete, get, post},
     Router,
 };
 use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection};
-use rdkafka::producer::FutureProducer;
+use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use shared::*;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
-use crate::handlers::SagaManager;
+use crate::catalog::CatalogClient;
+use crate::errors::{ApiError, FieldError, InfrastructureError, Json, Path, Query};
+use crate::handlers::{InterventionError, InterventionKind, SagaManager};
+use crate::idempotency::{self, OrderRequestKey};
+use crate::queries::{self, OrderFilter, OrderPage, OrderView, SagaFilter, SagaHistory, SagaPage, SagaView};
+use crate::models::NewWebhookSubscription;
+use crate::sagas;
+use crate::webhooks::{self, DeliveryView, SubscriptionView};
 
 type DbPool = Pool<AsyncPgConnection>;
 
+/// Longest a client may ask `POST /orders` to wait for the saga to finish.
+const MAX_WAIT: Duration = Duration::from_secs(30);
+
 #[derive(Clone)]
 pub struct AppState {
     pub pool: DbPool,
-    pub producer: FutureProducer,
+    pub saga_manager: Arc<SagaManager>,
+    pub catalog: Arc<CatalogClient>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct CreateOrderRequest {
-    pub customer_id: Uuid,
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateOrderParams {
+    /// How long to wait for the saga to finish, e.g. `5s` or `500ms`.
+    pub wait: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderLineRequest {
     pub product_id: Uuid,
     pub quantity: i32,
-    pub total_amount: f64,
+}
+
+/// An order is either a list of `lines` or, for a single product, the
+/// `product_id` and `quantity` shorthand.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateOrderRequest {
+    pub customer_id: Uuid,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lines: Vec<OrderLineRequest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantity: Option<i32>,
+    /// Optional; the order is priced from the catalog and this total, when
+    /// sent, has to match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_amount: Option<Money>,
+    /// Where the order ships to. Stock is reserved at the warehouses nearest
+    /// to it; without it, in the order of the warehouses' codes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ship_to: Option<GeoPoint>,
+    /// Whether the order may ship from several warehouses, which it may by
+    /// default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allocation_strategy: Option<AllocationStrategy>,
+}
+
+impl CreateOrderRequest {
+    /// The requested lines with the path each line's fields are reported at.
+    fn requested_lines(&self) -> Vec<(String, OrderLineRequest)> {
+        match (self.product_id, self.quantity) {
+            (Some(product_id), Some(quantity)) if self.lines.is_empty() => {
+                vec![(String::new(), OrderLineRequest { product_id, quantity })]
+            }
+            _ => self
+                .lines
+                .iter()
+                .enumerate()
+                .map(|(i, line)| (format!("lines[{}].", i), line.clone()))
+                .collect(),
+        }
+    }
+
+    /// The request with its total at the smallest scale, so that e.g. `10.0`
+    /// and `10.00` are the same request to the idempotency check.
+    fn normalized(&self) -> CreateOrderRequest {
+        CreateOrderRequest {
+            total_amount: self
+                .total_amount
+                .as_ref()
+                .map(|total| Money::new(total.amount.normalized(), total.currency)),
+            ..self.clone()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let mut errors = Vec::new();
+
+        if self.customer_id.is_nil() {
+            errors.push(FieldError::new("customer_id", "must not be the nil UUID"));
+        }
+
+        let shorthand = self.product_id.is_some() || self.quantity.is_some();
+        if shorthand && !self.lines.is_empty() {
+            errors.push(FieldError::new("lines", "must not be combined with product_id and quantity"));
+        } else if !shorthand && self.lines.is_empty() {
+            errors.push(FieldError::new("lines", "must contain at least one line"));
+        } else if self.lines.is_empty() {
+            if self.product_id.is_none() {
+                errors.push(FieldError::new("product_id", "is required with quantity"));
+            }
+            if self.quantity.is_none() {
+                errors.push(FieldError::new("quantity", "is required with product_id"));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for (path, line) in self.requested_lines() {
+            if line.product_id.is_nil() {
+                errors.push(FieldError::new(format!("{}product_id", path), "must not be the nil UUID"));
+            } else if !seen.insert(line.product_id) {
+                errors.push(FieldError::new(
+                    format!("{}product_id", path),
+                    "is already ordered on another line",
+                ));
+            }
+            if line.quantity <= 0 {
+                errors.push(FieldError::new(format!("{}quantity", path), "must be greater than zero"));
+            }
+        }
+        match &self.total_amount {
+            Some(total) if !total.is_positive() => {
+                errors.push(FieldError::new("total_amount.amount", "must be greater than zero"));
+            }
+            Some(total) if !total.has_valid_precision() => {
+                errors.push(FieldError::new(
+                    "total_amount.amount",
+                    format!("has more decimal places than {} allows", total.currency),
+                ));
+            }
+            _ => {}
+        }
+        if self.ship_to.is_some_and(|ship_to| !ship_to.is_valid()) {
+            errors.push(FieldError::new("ship_to", "must be a latitude and longitude in degrees"));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::validation(errors))
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -36,15 +166,67 @@ pub struct CreateOrderResponse {
     pub message: String,
 }
 
-#[derive(Debug, Serialize)]
-pub struct ErrorResponse {
-    pub error: String,
+#[derive(Debug, Deserialize)]
+pub struct InterventionRequest {
+    pub action: InterventionKind,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    pub event_types: Vec<String>,
+    /// Key for the HMAC signature sent with every delivery.
+    pub secret: String,
+}
+
+impl CreateWebhookRequest {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let mut errors = Vec::new();
+
+        match reqwest::Url::parse(&self.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => errors.push(FieldError::new("url", "must be an http(s) URL")),
+        }
+        if self.event_types.is_empty() {
+            errors.push(FieldError::new("event_types", "must not be empty"));
+        }
+        if let Some(unknown) = self.event_types.iter().find(|t| !webhooks::EVENT_TYPES.contains(&t.as_str())) {
+            errors.push(FieldError::new(
+                "event_types",
+                format!("unknown event type {}, expected one of {}", unknown, webhooks::EVENT_TYPES.join(", ")),
+            ));
+        }
+        if self.secret.is_empty() {
+            errors.push(FieldError::new("secret", "must not be empty"));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::validation(errors))
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DeliveryLogParams {
+    pub limit: Option<i64>,
 }
 
 pub fn create_router(state: AppState) -> Router {
     Router::new()
-        .route("/orders", post(create_order))
-        .route("/health", axum::routing::get(health_check))
+        .route("/orders", post(create_order).get(list_orders))
+        .route("/orders/:id", get(get_order))
+        .route("/orders/:id/events", get(order_events))
+        .route("/sagas", get(list_sagas))
+        .route("/sagas/:id", get(get_saga))
+        .route("/sagas/:id/events", get(get_saga_events))
+        .route("/sagas/:id/interventions", post(intervene))
+        .route("/webhooks", post(create_webhook).get(list_webhooks))
+        .route("/webhooks/:id", delete(delete_webhook))
+        .route("/webhooks/:id/deliveries", get(get_webhook_deliveries))
+        .route("/health", get(health_check))
         .with_state(state)
         .layer(
             tower_http::cors::CorsLayer::new()
@@ -56,45 +238,691 @@ pub fn create_router(state: AppState) -> Router {
 
 pub async fn create_order(
     State(state): State<AppState>,
+    Query(params): Query<CreateOrderParams>,
+    headers: HeaderMap,
     Json(request): Json<CreateOrderRequest>,
-) -> Result<Json<CreateOrderResponse>, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<(StatusCode, Json<CreateOrderResponse>), ApiError> {
+    let wait = requested_wait(&params, &headers).map_err(ApiError::malformed)?;
+    let idempotency_key = idempotency_key(&headers).map_err(ApiError::malformed)?;
+    request.validate()?;
+
+    let started = start_order(&state, request, idempotency_key).await?;
+    let StartedOrder { order_id, saga_id, .. } = started;
+
+    let Some(wait) = wait else {
+        if started.replayed {
+            let mut conn = state.pool.get().await?;
+            let order = queries::find_order(&mut conn, order_id).await?;
+            return Ok((
+                StatusCode::OK,
+                Json(CreateOrderResponse {
+                    order_id,
+                    saga_id,
+                    status: order.map_or_else(|| "started".to_string(), |o| o.status),
+                    message: "Order was already created for this Idempotency-Key".to_string(),
+                }),
+            ));
+        }
+        return Ok((
+            StatusCode::OK,
+            Json(CreateOrderResponse {
+                order_id,
+                saga_id,
+                status: "started".to_string(),
+                message: "Order saga transaction has been initiated".to_string(),
+            }),
+        ));
+    };
+
+    let Some(saga_status) = state.saga_manager.wait_for_completion(saga_id, wait).await? else {
+        return Ok((
+            StatusCode::ACCEPTED,
+            Json(CreateOrderResponse {
+                order_id,
+                saga_id,
+                status: "started".to_string(),
+                message: format!("Order saga did not finish within {:?}", wait),
+            }),
+        ));
+    };
+
+    let mut conn = state.pool.get().await?;
+    let order = queries::find_order(&mut conn, order_id).await?;
+    // The order row is missing if the saga failed before creating it
+    let status = order.map_or_else(|| format!("{:?}", saga_status).to_lowercase(), |o| o.status);
+
+    Ok((
+        StatusCode::OK,
+        Json(CreateOrderResponse {
+            order_id,
+            saga_id,
+            status,
+            message: format!("Order saga finished as {:?}", saga_status),
+        }),
+    ))
+}
+
+/// The order and saga started for a request, which are those of an earlier
+/// request when `replayed`.
+struct StartedOrder {
+    order_id: Uuid,
+    saga_id: Uuid,
+    replayed: bool,
+}
+
+/// Starts the order saga, unless `idempotency_key` was already used for the
+/// same request, in which case the order it started is returned instead.
+async fn start_order(
+    state: &AppState,
+    request: CreateOrderRequest,
+    idempotency_key: Option<String>,
+) -> Result<StartedOrder, ApiError> {
+    let request_hash = idempotency::request_hash(&request.normalized())?;
+    if let Some(key) = &idempotency_key {
+        if let Some(started) = replay(state, key, &request_hash).await? {
+            tracing::info!("Replaying order {} for Idempotency-Key {}", started.order_id, key);
+            return Ok(started);
+        }
+    }
+
+    let (lines, total_amount) = price_order(state, &request).await?;
     let order_id = Uuid::new_v4();
-    
+
     let order_data = OrderData {
         order_id,
         customer_id: request.customer_id,
-        product_id: request.product_id,
-        quantity: request.quantity,
-        total_amount: request.total_amount,
+        lines,
+        total_amount,
+        ship_to: request.ship_to,
+        allocation_strategy: request.allocation_strategy.unwrap_or_default(),
     };
 
-    let saga = SagaTransaction::new(order_data);
-    let saga_id = saga.id;
+    let context = sagas::order_context(&order_data);
+    let request_key = idempotency_key.clone().map(|key| OrderRequestKey {
+        key,
+        request_hash: request_hash.clone(),
+        order_id,
+    });
 
-    let saga_manager = SagaManager::new(state.pool, state.producer);
-    
-    match saga_manager.start_saga(saga).await {
-        Ok(_) => {
+    match state
+        .saga_manager
+        .start_saga(sagas::CREATE_ORDER_SAGA, context, request_key)
+        .await
+    {
+        Ok(saga_id) => {
             tracing::info!("Started saga {} for order {}", saga_id, order_id);
-            Ok(Json(CreateOrderResponse {
+            Ok(StartedOrder {
                 order_id,
                 saga_id,
-                status: "started".to_string(),
-                message: "Order saga transaction has been initiated".to_string(),
-            }))
+                replayed: false,
+            })
         }
+        // A concurrent request with the same key got there first
+        Err(e) if idempotency::is_unique_violation(&e) && idempotency_key.is_some() => {
+            let key = idempotency_key.unwrap_or_default();
+            replay(state, &key, &request_hash).await?.ok_or_else(|| e.into())
+        }
+        Err(e) => Err(e.context("Failed to start order saga").into()),
+    }
+}
+
+/// The order's lines and total at catalog prices. A total sent by the client
+/// is only accepted if it is the same amount.
+async fn price_order(state: &AppState, request: &CreateOrderRequest) -> Result<(Vec<OrderLine>, Money), ApiError> {
+    let requested = request.requested_lines();
+    let unit_prices = futures::future::try_join_all(
+        requested.iter().map(|(_, line)| state.catalog.unit_price(line.product_id)),
+    )
+    .await
+    .map_err(InfrastructureError::Unavailable)?;
+
+    let mut errors = Vec::new();
+    let mut lines = Vec::with_capacity(requested.len());
+    for ((path, line), unit_price) in requested.into_iter().zip(unit_prices) {
+        match unit_price {
+            Some(unit_price) => lines.push(OrderLine {
+                product_id: line.product_id,
+                quantity: line.quantity,
+                amount: unit_price.times(line.quantity),
+            }),
+            None => errors.push(FieldError::new(format!("{}product_id", path), "is not in the catalog")),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::validation(errors));
+    }
+
+    let total = lines[1..]
+        .iter()
+        .try_fold(lines[0].amount.clone(), |total, line| total.checked_add(&line.amount))
+        .ok_or_else(|| ApiError::validation(vec![FieldError::new("lines", "are priced in different currencies")]))?;
+
+    match &request.total_amount {
+        Some(client_total) if *client_total != total => Err(ApiError::validation(vec![FieldError::new(
+            "total_amount",
+            format!("does not match the catalog total of {}", total),
+        )])),
+        _ => Ok((lines, total)),
+    }
+}
+
+/// The order started by an earlier request with `key`, or 409 if that
+/// request was a different one.
+async fn replay(state: &AppState, key: &str, request_hash: &str) -> Result<Option<StartedOrder>, ApiError> {
+    let mut conn = state.pool.get().await?;
+    match idempotency::find_key(&mut conn, key).await? {
+        None => Ok(None),
+        Some(stored) if stored.request_hash == request_hash => Ok(Some(StartedOrder {
+            order_id: stored.order_id,
+            saga_id: stored.saga_id,
+            replayed: true,
+        })),
+        Some(_) => Err(ApiError::conflict(format!(
+            "Idempotency-Key {} was already used with a different request",
+            key
+        ))),
+    }
+}
+
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, String> {
+    let Some(value) = headers.get("idempotency-key") else {
+        return Ok(None);
+    };
+    let key = value
+        .to_str()
+        .map_err(|_| "Idempotency-Key must be visible ASCII".to_string())?
+        .trim();
+    if key.is_empty() || key.len() > idempotency::MAX_KEY_LENGTH {
+        return Err(format!(
+            "Idempotency-Key must be between 1 and {} characters",
+            idempotency::MAX_KEY_LENGTH
+        ));
+    }
+    Ok(Some(key.to_string()))
+}
+
+/// The wait requested with `?wait=` or a `Prefer: wait=<seconds>` header
+/// (RFC 7240), capped at `MAX_WAIT`.
+fn requested_wait(params: &CreateOrderParams, headers: &HeaderMap) -> Result<Option<Duration>, String> {
+    let wait = match &params.wait {
+        Some(wait) => Some(parse_wait(wait)?),
+        None => headers
+            .get_all("prefer")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split([',', ';']))
+            .find_map(|preference| preference.trim().strip_prefix("wait="))
+            .map(|seconds| {
+                seconds
+                    .trim()
+                    .parse::<u64>()
+                    .map(Duration::from_secs)
+                    .map_err(|_| format!("Invalid Prefer wait: {}", seconds))
+            })
+            .transpose()?,
+    };
+    Ok(wait.map(|wait| wait.min(MAX_WAIT)))
+}
+
+/// Parses `500ms`, `5s` or a plain number of seconds.
+fn parse_wait(wait: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid wait: {}", wait);
+    if let Some(millis) = wait.strip_suffix("ms") {
+        millis.parse().map(Duration::from_millis).map_err(|_| invalid())
+    } else {
+        let seconds = wait.strip_suffix('s').unwrap_or(wait);
+        seconds.parse().map(Duration::from_secs).map_err(|_| invalid())
+    }
+}
+
+pub async fn get_order(
+    State(state): State<AppState>,
+    Path(order_id): Path<Uuid>,
+) -> Result<Json<OrderView>, ApiError> {
+    let mut conn = state.pool.get().await?;
+    queries::find_order(&mut conn, order_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Order {} not found", order_id)))
+}
+
+/// Streams the events of the order's saga as Server-Sent Events, starting
+/// after `Last-Event-ID` when the client is reconnecting. Each event's id is
+/// its sequence in the saga event log.
+pub async fn order_events(
+    State(state): State<AppState>,
+    Path(order_id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, ApiError> {
+    let last_event_id = match headers.get("last-event-id") {
+        None => None,
+        Some(value) => match value.to_str().ok().and_then(|v| v.trim().parse::<i64>().ok()) {
+            Some(sequence) => Some(sequence),
+            None => return Err(ApiError::malformed("Last-Event-ID must be an event id sent by this stream")),
+        },
+    };
+
+    let mut conn = state.pool.get().await?;
+    let Some(saga_id) = queries::find_order_saga_id(&mut conn, order_id).await? else {
+        return Err(ApiError::not_found(format!("Order {} not found", order_id)));
+    };
+    drop(conn);
+
+    let events = state.saga_manager.progress(saga_id, last_event_id).map(move |event| match event {
+        Ok(event) => Event::default()
+            .id(event.sequence.to_string())
+            .event(event.event.event_type())
+            .json_data(&event),
         Err(e) => {
-            tracing::error!("Failed to start saga: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to start order saga: {}", e),
-                }),
-            ))
+            tracing::error!("Event stream of saga {} failed: {}", saga_id, e);
+            Ok(Event::default().event("error").data("event stream unavailable"))
         }
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+pub async fn list_orders(
+    State(state): State<AppState>,
+    Query(filter): Query<OrderFilter>,
+) -> Result<Json<OrderPage>, ApiError> {
+    let mut conn = state.pool.get().await?;
+    Ok(Json(queries::list_orders(&mut conn, &filter).await?))
+}
+
+pub async fn get_saga(
+    State(state): State<AppState>,
+    Path(saga_id): Path<Uuid>,
+) -> Result<Json<SagaView>, ApiError> {
+    let mut conn = state.pool.get().await?;
+    queries::find_saga(&mut conn, saga_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Saga {} not found", saga_id)))
+}
+
+pub async fn list_sagas(
+    State(state): State<AppState>,
+    Query(filter): Query<SagaFilter>,
+) -> Result<Json<SagaPage>, ApiError> {
+    let mut conn = state.pool.get().await?;
+    Ok(Json(queries::list_sagas(&mut conn, &filter).await?))
+}
+
+pub async fn get_saga_events(
+    State(state): State<AppState>,
+    Path(saga_id): Path<Uuid>,
+) -> Result<Json<SagaHistory>, ApiError> {
+    let mut conn = state.pool.get().await?;
+    queries::saga_history(&mut conn, saga_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Saga {} not found", saga_id)))
+}
+
+pub async fn intervene(
+    State(state): State<AppState>,
+    Path(saga_id): Path<Uuid>,
+    Json(request): Json<InterventionRequest>,
+) -> Result<Json<SagaView>, ApiError> {
+    if let Err(e) = state.saga_manager.intervene(saga_id, request.action, request.note).await {
+        return Err(match e.downcast_ref::<InterventionError>() {
+            Some(InterventionError::NotFound) => ApiError::not_found(e.to_string()),
+            Some(InterventionError::Rejected(_)) => ApiError::conflict(e.to_string()),
+            None => e.into(),
+        });
     }
+
+    get_saga(State(state), Path(saga_id)).await
+}
+
+pub async fn create_webhook(
+    State(state): State<AppState>,
+    Json(request): Json<CreateWebhookRequest>,
+) -> Result<(StatusCode, Json<SubscriptionView>), ApiError> {
+    request.validate()?;
+
+    let mut event_types = request.event_types;
+    event_types.sort();
+    event_types.dedup();
+
+    let mut conn = state.pool.get().await?;
+    let subscription = webhooks::create_subscription(
+        &mut conn,
+        NewWebhookSubscription {
+            id: Uuid::new_v4(),
+            url: request.url,
+            event_types,
+            secret: request.secret,
+        },
+    )
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(subscription)))
+}
+
+pub async fn list_webhooks(State(state): State<AppState>) -> Result<Json<Vec<SubscriptionView>>, ApiError> {
+    let mut conn = state.pool.get().await?;
+    Ok(Json(webhooks::list_subscriptions(&mut conn).await?))
+}
+
+pub async fn delete_webhook(
+    State(state): State<AppState>,
+    Path(subscription_id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    let mut conn = state.pool.get().await?;
+    if webhooks::deactivate_subscription(&mut conn, subscription_id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::not_found(format!("Webhook {} not found", subscription_id)))
+    }
+}
+
+pub async fn get_webhook_deliveries(
+    State(state): State<AppState>,
+    Path(subscription_id): Path<Uuid>,
+    Query(params): Query<DeliveryLogParams>,
+) -> Result<Json<Vec<DeliveryView>>, ApiError> {
+    let limit = params.limit.unwrap_or(queries::DEFAULT_PAGE_SIZE).clamp(1, queries::MAX_PAGE_SIZE);
+
+    let mut conn = state.pool.get().await?;
+    webhooks::delivery_log(&mut conn, subscription_id, limit)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Webhook {} not found", subscription_id)))
 }
 
 pub async fn health_check() -> &'static str {
     "OK"
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ClientError;
+    use crate::test_support::{catalog_stub, test_pool, UNPRICED_PRODUCT};
+
+    async fn app_state(pool: DbPool) -> AppState {
+        app_state_with_catalog(pool, &catalog_stub().await)
+    }
+
+    fn app_state_with_catalog(pool: DbPool, catalog_url: &str) -> AppState {
+        AppState {
+            saga_manager: Arc::new(SagaManager::new(pool.clone(), Arc::new(sagas::registry()))),
+            pool,
+            catalog: Arc::new(CatalogClient::new(catalog_url).unwrap()),
+        }
+    }
+
+    fn order_request(quantity: i32) -> CreateOrderRequest {
+        CreateOrderRequest {
+            customer_id: Uuid::new_v4(),
+            lines: Vec::new(),
+            product_id: Some(Uuid::new_v4()),
+            quantity: Some(quantity),
+            total_amount: None,
+            ship_to: None,
+            allocation_strategy: None,
+        }
+    }
+
+    fn multi_line_request(quantities: &[i32]) -> CreateOrderRequest {
+        CreateOrderRequest {
+            lines: quantities
+                .iter()
+                .map(|&quantity| OrderLineRequest {
+                    product_id: Uuid::new_v4(),
+                    quantity,
+                })
+                .collect(),
+            product_id: None,
+            quantity: None,
+            ..order_request(1)
+        }
+    }
+
+    fn with_total(request: CreateOrderRequest, amount: &str) -> CreateOrderRequest {
+        CreateOrderRequest {
+            total_amount: Some(Money::new(amount.parse().unwrap(), Currency::USD)),
+            ..request
+        }
+    }
+
+    fn invalid_fields(error: ApiError) -> Vec<String> {
+        let ApiError::Client(ClientError::Validation(errors)) = error else {
+            panic!("expected a validation error, got {:?}", error);
+        };
+        errors.into_iter().map(|e| e.field).collect()
+    }
+
+    fn with_key(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("idempotency-key", key.parse().unwrap());
+        headers
+    }
+
+    async fn post_order(
+        state: &AppState,
+        headers: HeaderMap,
+        request: CreateOrderRequest,
+    ) -> Result<CreateOrderResponse, StatusCode> {
+        create_order(State(state.clone()), Query(CreateOrderParams::default()), headers, Json(request))
+            .await
+            .map(|(_, Json(response))| response)
+            .map_err(|error| error.status())
+    }
+
+    fn params(wait: Option<&str>) -> CreateOrderParams {
+        CreateOrderParams {
+            wait: wait.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn wait_is_read_from_query_or_prefer_header() {
+        let no_headers = HeaderMap::new();
+        assert_eq!(requested_wait(&params(None), &no_headers).unwrap(), None);
+        assert_eq!(requested_wait(&params(Some("5s")), &no_headers).unwrap(), Some(Duration::from_secs(5)));
+        assert_eq!(requested_wait(&params(Some("250ms")), &no_headers).unwrap(), Some(Duration::from_millis(250)));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("prefer", "respond-async, wait=10".parse().unwrap());
+        assert_eq!(requested_wait(&params(None), &headers).unwrap(), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn wait_is_capped_and_validated() {
+        let headers = HeaderMap::new();
+        assert_eq!(requested_wait(&params(Some("3600")), &headers).unwrap(), Some(MAX_WAIT));
+        assert!(requested_wait(&params(Some("soon")), &headers).is_err());
+    }
+
+    #[test]
+    fn invalid_order_fields_are_all_reported() {
+        let request = CreateOrderRequest {
+            customer_id: Uuid::nil(),
+            lines: Vec::new(),
+            product_id: Some(Uuid::new_v4()),
+            quantity: Some(0),
+            total_amount: Some(Money::new("-5".parse().unwrap(), Currency::USD)),
+            ship_to: Some(GeoPoint { latitude: 0.0, longitude: 200.0 }),
+            allocation_strategy: None,
+        };
+        assert_eq!(
+            invalid_fields(request.validate().unwrap_err()),
+            ["customer_id", "quantity", "total_amount.amount", "ship_to"]
+        );
+
+        assert!(order_request(1).validate().is_ok());
+        assert!(with_total(order_request(1), "99.99").validate().is_ok());
+        assert!(with_total(order_request(1), "99.999").validate().is_err());
+    }
+
+    #[test]
+    fn order_lines_are_validated_by_position() {
+        assert!(multi_line_request(&[1, 2]).validate().is_ok());
+
+        let mut request = multi_line_request(&[1, 0, 1]);
+        request.lines[2].product_id = request.lines[0].product_id;
+        assert_eq!(
+            invalid_fields(request.validate().unwrap_err()),
+            ["lines[1].quantity", "lines[2].product_id"]
+        );
+
+        assert_eq!(invalid_fields(multi_line_request(&[]).validate().unwrap_err()), ["lines"]);
+        let both = CreateOrderRequest {
+            product_id: Some(Uuid::new_v4()),
+            quantity: Some(1),
+            ..multi_line_request(&[1])
+        };
+        assert_eq!(invalid_fields(both.validate().unwrap_err()), ["lines"]);
+    }
+
+    #[tokio::test]
+    async fn order_is_priced_from_the_catalog() {
+        let Some(pool) = test_pool().await else { return };
+        let state = app_state(pool).await;
+        let expected = Money::new("149.97".parse().unwrap(), Currency::USD);
+
+        assert_eq!(price_order(&state, &order_request(3)).await.unwrap().1, expected);
+        // The client's total is accepted when it is the same amount, whatever its scale
+        let request = with_total(order_request(3), "149.970");
+        assert_eq!(price_order(&state, &request).await.unwrap().1, expected);
+    }
+
+    #[tokio::test]
+    async fn each_order_line_is_priced_and_summed() {
+        let Some(pool) = test_pool().await else { return };
+        let state = app_state(pool).await;
+        let request = multi_line_request(&[1, 2]);
+
+        let (lines, total) = price_order(&state, &request).await.unwrap();
+        let amounts: Vec<_> = lines.iter().map(|line| line.amount.to_string()).collect();
+        assert_eq!(amounts, ["49.99 USD", "99.98 USD"]);
+        assert_eq!(lines[1].product_id, request.lines[1].product_id);
+        assert_eq!(total, Money::new("149.97".parse().unwrap(), Currency::USD));
+
+        let mut request = multi_line_request(&[1, 1]);
+        request.lines[1].product_id = UNPRICED_PRODUCT;
+        assert_eq!(invalid_fields(price_order(&state, &request).await.unwrap_err()), ["lines[1].product_id"]);
+    }
+
+    #[tokio::test]
+    async fn order_with_wrong_total_or_unknown_product_is_rejected() {
+        let Some(pool) = test_pool().await else { return };
+        let state = app_state(pool).await;
+
+        let request = with_total(order_request(3), "0.01");
+        assert_eq!(invalid_fields(price_order(&state, &request).await.unwrap_err()), ["total_amount"]);
+
+        let request = CreateOrderRequest {
+            product_id: Some(UNPRICED_PRODUCT),
+            ..order_request(1)
+        };
+        assert_eq!(invalid_fields(price_order(&state, &request).await.unwrap_err()), ["product_id"]);
+    }
+
+    #[tokio::test]
+    async fn order_is_not_started_while_the_catalog_is_down() {
+        let Some(pool) = test_pool().await else { return };
+        // Nothing listens on the discard port
+        let state = app_state_with_catalog(pool, "http://127.0.0.1:9");
+
+        let status = post_order(&state, HeaderMap::new(), order_request(1)).await.unwrap_err();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn retry_with_same_idempotency_key_returns_the_original_order() {
+        let Some(pool) = test_pool().await else { return };
+        let state = app_state(pool).await;
+        let key = Uuid::new_v4().to_string();
+        let request = order_request(1);
+
+        let first = post_order(&state, with_key(&key), request.clone()).await.unwrap();
+        let second = post_order(&state, with_key(&key), request).await.unwrap();
+
+        assert_eq!(second.order_id, first.order_id);
+        assert_eq!(second.saga_id, first.saga_id);
+    }
+
+    #[tokio::test]
+    async fn idempotency_key_reused_for_another_request_conflicts() {
+        let Some(pool) = test_pool().await else { return };
+        let state = app_state(pool).await;
+        let key = Uuid::new_v4().to_string();
+        let request = order_request(1);
+
+        post_order(&state, with_key(&key), request.clone()).await.unwrap();
+        let changed = CreateOrderRequest { quantity: Some(2), ..request };
+        let status = post_order(&state, with_key(&key), changed).await.unwrap_err();
+
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn retry_with_the_total_at_another_scale_returns_the_original_order() {
+        let Some(pool) = test_pool().await else { return };
+        let state = app_state(pool).await;
+        let key = Uuid::new_v4().to_string();
+
+        let request = order_request(3);
+
+        let first = post_order(&state, with_key(&key), with_total(request.clone(), "149.97")).await.unwrap();
+        let second = post_order(&state, with_key(&key), with_total(request, "149.970")).await.unwrap();
+
+        assert_eq!(second.order_id, first.order_id);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_with_one_key_start_one_saga() {
+        let Some(pool) = test_pool().await else { return };
+        let state = app_state(pool).await;
+        let key = Uuid::new_v4().to_string();
+        let request = order_request(1);
+
+        let responses = futures::future::join_all(
+            (0..4).map(|_| post_order(&state, with_key(&key), request.clone())),
+        )
+        .await;
+
+        let saga_ids: std::collections::HashSet<_> = responses.into_iter().map(|r| r.unwrap().saga_id).collect();
+        assert_eq!(saga_ids.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn order_events_follow_a_saga_that_ends_before_the_order_is_created() {
+        let Some(pool) = test_pool().await else { return };
+        let state = app_state(pool.clone()).await;
+        let started = post_order(&state, HeaderMap::new(), order_request(1)).await.unwrap();
+
+        // Nothing has handled CreateOrder, so there is no order row yet
+        let mut conn = pool.get().await.unwrap();
+        assert!(queries::find_order(&mut conn, started.order_id).await.unwrap().is_none());
+        drop(conn);
+
+        let events = order_events(State(state.clone()), Path(started.order_id), HeaderMap::new())
+            .await
+            .map_err(|error| error.status())
+            .unwrap();
+        state
+            .saga_manager
+            .intervene(started.saga_id, InterventionKind::Abort, None)
+            .await
+            .unwrap();
+
+        let response = axum::response::IntoResponse::into_response(events);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("event: SagaStarted"));
+        assert!(body.contains("event: ManualIntervention"));
+    }
+
+    #[tokio::test]
+    async fn events_of_an_unknown_order_are_not_found() {
+        let Some(pool) = test_pool().await else { return };
+        let state = app_state(pool).await;
+
+        let result = order_events(State(state), Path(Uuid::new_v4()), HeaderMap::new()).await;
+        assert_eq!(result.err().map(|error| error.status()), Some(StatusCode::NOT_FOUND));
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use shared::*;
+use std::time::Duration;
+use uuid::Uuid;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+struct ProductPrice {
+    unit_price: Money,
+}
+
+/// Reads product prices from the catalog the inventory service serves.
+pub struct CatalogClient {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl CatalogClient {
+    pub fn new(base_url: impl Into<String>) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        Ok(Self {
+            client,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Price of one unit of `product_id`, or `None` if the catalog has no
+    /// price for it.
+    pub async fn unit_price(&self, product_id: Uuid) -> Result<Option<Money>> {
+        let url = format!("{}/products/{}/price", self.base_url, product_id);
+        let response = self.client.get(&url).send().await.context("Catalog is unreachable")?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            anyhow::bail!("Catalog responded with {} for {}", response.status(), url);
+        }
+
+        let body = response.bytes().await?;
+        let price: ProductPrice = serde_json::from_slice(&body).context("Invalid catalog price")?;
+        Ok(Some(price.unit_price))
+    }
+}
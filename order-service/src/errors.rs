@@ -0,0 +1,332 @@
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        FromRequest, FromRequestParts,
+    },
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use diesel_async::pooled_connection::PoolError;
+use serde::Serialize;
+use std::fmt;
+
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// A request field with an invalid value. `field` is the path of the field
+/// in the request body, e.g. `total_amount.amount`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// A request the client has to change before retrying it.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The body is well-formed, but some of its fields are not valid.
+    Validation(Vec<FieldError>),
+    /// The body, query, path or a header could not be parsed.
+    Malformed(String),
+    UnsupportedMediaType(String),
+    NotFound(String),
+    Conflict(String),
+}
+
+/// A failure of the service or what it depends on. The request may succeed
+/// when retried unchanged; the cause is logged but not returned.
+#[derive(Debug)]
+pub enum InfrastructureError {
+    /// No database connection could be obtained.
+    Unavailable(anyhow::Error),
+    Failed(anyhow::Error),
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    Client(ClientError),
+    Infrastructure(InfrastructureError),
+}
+
+/// An RFC 7807 problem details body.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl ApiError {
+    pub fn validation(errors: Vec<FieldError>) -> Self {
+        ApiError::Client(ClientError::Validation(errors))
+    }
+
+    pub fn malformed(detail: impl Into<String>) -> Self {
+        ApiError::Client(ClientError::Malformed(detail.into()))
+    }
+
+    pub fn not_found(detail: impl Into<String>) -> Self {
+        ApiError::Client(ClientError::NotFound(detail.into()))
+    }
+
+    pub fn conflict(detail: impl Into<String>) -> Self {
+        ApiError::Client(ClientError::Conflict(detail.into()))
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Client(ClientError::Validation(_)) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Client(ClientError::Malformed(_)) => StatusCode::BAD_REQUEST,
+            ApiError::Client(ClientError::UnsupportedMediaType(_)) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Client(ClientError::NotFound(_)) => StatusCode::NOT_FOUND,
+            ApiError::Client(ClientError::Conflict(_)) => StatusCode::CONFLICT,
+            ApiError::Infrastructure(InfrastructureError::Unavailable(_)) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Infrastructure(InfrastructureError::Failed(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn problem(&self) -> Problem {
+        let (problem_type, title, detail, errors) = match self {
+            ApiError::Client(ClientError::Validation(errors)) => (
+                "/problems/validation-failed",
+                "Validation failed",
+                "The request has invalid fields".to_string(),
+                errors.clone(),
+            ),
+            ApiError::Client(ClientError::Malformed(detail)) => {
+                ("/problems/malformed-request", "Malformed request", detail.clone(), Vec::new())
+            }
+            ApiError::Client(ClientError::UnsupportedMediaType(detail)) => {
+                ("/problems/unsupported-media-type", "Unsupported media type", detail.clone(), Vec::new())
+            }
+            ApiError::Client(ClientError::NotFound(detail)) => {
+                ("/problems/not-found", "Not found", detail.clone(), Vec::new())
+            }
+            ApiError::Client(ClientError::Conflict(detail)) => {
+                ("/problems/conflict", "Conflict", detail.clone(), Vec::new())
+            }
+            ApiError::Infrastructure(InfrastructureError::Unavailable(_)) => (
+                "/problems/service-unavailable",
+                "Service unavailable",
+                "The service is temporarily unable to handle the request".to_string(),
+                Vec::new(),
+            ),
+            ApiError::Infrastructure(InfrastructureError::Failed(_)) => (
+                "/problems/internal-error",
+                "Internal error",
+                "The request failed because of an internal error".to_string(),
+                Vec::new(),
+            ),
+        };
+
+        Problem {
+            problem_type,
+            title,
+            status: self.status().as_u16(),
+            detail,
+            errors,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Client(ClientError::Validation(errors)) => {
+                let fields: Vec<_> = errors.iter().map(|e| format!("{}: {}", e.field, e.message)).collect();
+                write!(f, "Invalid request: {}", fields.join(", "))
+            }
+            ApiError::Client(
+                ClientError::Malformed(detail)
+                | ClientError::UnsupportedMediaType(detail)
+                | ClientError::NotFound(detail)
+                | ClientError::Conflict(detail),
+            ) => f.write_str(detail),
+            ApiError::Infrastructure(InfrastructureError::Unavailable(e)) => write!(f, "Service unavailable: {}", e),
+            ApiError::Infrastructure(InfrastructureError::Failed(e)) => write!(f, "Internal error: {}", e),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let ApiError::Infrastructure(_) = &self {
+            tracing::error!("{}", self);
+        }
+
+        let status = self.status();
+        let body = serde_json::to_vec(&self.problem()).expect("problem details serialize");
+        (status, [(header::CONTENT_TYPE, PROBLEM_CONTENT_TYPE)], body).into_response()
+    }
+}
+
+impl From<ClientError> for ApiError {
+    fn from(error: ClientError) -> Self {
+        ApiError::Client(error)
+    }
+}
+
+impl From<InfrastructureError> for ApiError {
+    fn from(error: InfrastructureError) -> Self {
+        ApiError::Infrastructure(error)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        ApiError::Infrastructure(InfrastructureError::Failed(error))
+    }
+}
+
+impl From<bb8::RunError<PoolError>> for ApiError {
+    fn from(error: bb8::RunError<PoolError>) -> Self {
+        ApiError::Infrastructure(InfrastructureError::Unavailable(error.into()))
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            JsonRejection::JsonDataError(e) => ApiError::validation(vec![field_error(&e)]),
+            JsonRejection::MissingJsonContentType(e) => {
+                ApiError::Client(ClientError::UnsupportedMediaType(e.body_text()))
+            }
+            rejection => ApiError::malformed(rejection.body_text()),
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::malformed(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::malformed(rejection.body_text())
+    }
+}
+
+/// The field a JSON body failed to deserialize at, found through the
+/// `serde_path_to_error` error axum keeps as the rejection's source.
+fn field_error(rejection: &(dyn std::error::Error + 'static)) -> FieldError {
+    let mut source = rejection.source();
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<serde_path_to_error::Error<serde_json::Error>>() {
+            let message = error.inner().to_string();
+            // Missing fields are reported at their parent
+            let field = match message.strip_prefix("missing field `").and_then(|m| m.split('`').next()) {
+                Some(missing) if error.path().to_string() == "." => missing.to_string(),
+                Some(missing) => format!("{}.{}", error.path(), missing),
+                None => error.path().to_string(),
+            };
+            let message = message.split(" at line ").next().unwrap_or_default().to_string();
+            return FieldError::new(field, message);
+        }
+        source = error.source();
+    }
+    FieldError::new(".", rejection.to_string())
+}
+
+/// `axum::Json` that rejects with a problem response.
+#[derive(Debug, Clone, FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// `axum::extract::Query` that rejects with a problem response.
+#[derive(Debug, Clone, FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct Query<T>(pub T);
+
+/// `axum::extract::Path` that rejects with a problem response.
+#[derive(Debug, Clone, FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(ApiError))]
+pub struct Path<T>(pub T);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    async fn extract_json(body: &str) -> Result<(), ApiError> {
+        #[derive(Debug, serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Order {
+            quantity: i32,
+            total_amount: shared::Money,
+        }
+
+        let request = Request::post("/")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        Json::<Order>::from_request(request, &()).await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn validation_error_is_a_problem_with_field_details() {
+        let error = ApiError::validation(vec![FieldError::new("quantity", "must be greater than zero")]);
+        let response = error.into_response();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], PROBLEM_CONTENT_TYPE);
+        let body = body_json(response).await;
+        assert_eq!(body["status"], 422);
+        assert_eq!(body["errors"][0]["field"], "quantity");
+    }
+
+    #[tokio::test]
+    async fn infrastructure_error_hides_its_cause() {
+        let response = ApiError::from(anyhow::anyhow!("connection reset by db-1.internal")).into_response();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = body_json(response).await;
+        assert!(!body["detail"].as_str().unwrap().contains("db-1"));
+    }
+
+    #[tokio::test]
+    async fn undeserializable_field_is_reported_by_path() {
+        let error = extract_json(r#"{"quantity": 1, "total_amount": {"amount": "NaN", "currency": "USD"}}"#)
+            .await
+            .unwrap_err();
+        match error {
+            ApiError::Client(ClientError::Validation(errors)) => {
+                assert_eq!(errors[0].field, "total_amount.amount");
+            }
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+
+        let error = extract_json(r#"{"total_amount": {"amount": "1", "currency": "USD"}}"#).await.unwrap_err();
+        match error {
+            ApiError::Client(ClientError::Validation(errors)) => assert_eq!(errors[0].field, "quantity"),
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+
+        let error = extract_json("{not json").await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+    }
+}
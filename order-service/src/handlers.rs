@@ -1,29 +1,88 @@
 use anyhow::Result;
-use num_traits::ToPrimitive;
 use diesel::prelude::*;
 use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection, RunQueryDsl, AsyncConnection};
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 use rdkafka::consumer::{Consumer, StreamConsumer};
-use rdkafka::producer::{FutureProducer, FutureRecord};
 use rdkafka::Message;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::broadcast;
 use tracing::{error, info, warn};
 use uuid::Uuid;
 use shared::*;
+use shared::inbox::{CommandProcessor, Inbox};
+use shared::outbox::NewOutboxEvent;
+use shared::schema::outbox_events;
+use crate::idempotency::{self, OrderRequestKey};
 use crate::models::*;
+use crate::queries::{self, LoggedSagaEvent};
 use crate::schema::*;
+use crate::webhooks;
 
 type DbPool = Pool<AsyncPgConnection>;
 
+/// How many times a reply is re-applied after losing an optimistic concurrency
+/// race on `saga_transactions` before giving up.
+const MAX_CONFLICT_RETRIES: usize = 5;
+
+const DEADLINE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many saga updates a slow subscriber may fall behind before it misses some.
+const SAGA_UPDATES_CAPACITY: usize = 1024;
+
+/// How often a progress stream re-reads the event log when no update arrives,
+/// which picks up sagas advanced by another order-service instance.
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Published by a `SagaManager` every time it saves a saga.
+#[derive(Debug, Clone)]
+pub struct SagaUpdate {
+    pub saga_id: Uuid,
+    pub status: SagaStatus,
+}
+
+/// What an operator can do to a saga through `SagaManager::intervene`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterventionKind {
+    Resend,
+    Abort,
+}
+
+/// Why a manual intervention was not applied.
+#[derive(Debug)]
+pub enum InterventionError {
+    NotFound,
+    Rejected(String),
+}
+
+impl std::fmt::Display for InterventionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InterventionError::NotFound => write!(f, "Saga not found"),
+            InterventionError::Rejected(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for InterventionError {}
+
+#[derive(Debug, PartialEq)]
+enum SaveOutcome {
+    Saved,
+    Conflict,
+}
+
 pub struct CommandHandler {
     pool: DbPool,
-    producer: FutureProducer,
-    reply_topic: String,
+    inbox: Inbox,
 }
 
 impl CommandHandler {
-    pub fn new(pool: DbPool, producer: FutureProducer, reply_topic: String) -> Self {
-        Self { pool, producer, reply_topic }
+    pub fn new(pool: DbPool, reply_topic: String) -> Self {
+        Self { pool, inbox: Inbox::new(reply_topic) }
     }
 
     pub async fn run(&self, consumer: StreamConsumer) {
@@ -55,39 +114,7 @@ impl CommandHandler {
 
     async fn handle_command(&self, command: Command) -> Result<()> {
         let mut conn = self.pool.get().await?;
-
-        if let Some(existing) = self.check_idempotency(&mut conn, &command.idempotency_key).await? {
-            info!("Command already processed, returning cached result");
-            let reply = CommandReply {
-                id: Uuid::new_v4(),
-                command_id: command.id,
-                saga_id: command.saga_id,
-                status: CommandStatus::Success,
-                result: existing.result,
-                error: None,
-                created_at: chrono::Utc::now(),
-            };
-            self.send_reply(reply).await?;
-            return Ok(());
-        }
-
-        let reply = match command.command_type {
-            CommandType::CreateOrder => self.handle_create_order(&mut conn, &command).await?,
-            CommandType::ApproveOrder => self.handle_approve_order(&mut conn, &command).await?,
-            CommandType::CancelOrder => self.handle_cancel_order(&mut conn, &command).await?,
-            _ => {
-                warn!("Unsupported command type: {:?}", command.command_type);
-                CommandReply::failed(
-                    command.id,
-                    command.saga_id,
-                    "Unsupported command type".to_string(),
-                )
-            }
-        };
-
-        self.store_processed_command(&mut conn, &command, &reply).await?;
-        self.send_reply(reply).await?;
-
+        self.inbox.handle(&mut conn, self, &command).await?;
         Ok(())
     }
 
@@ -97,12 +124,19 @@ impl CommandHandler {
         let new_order = NewOrder {
             id: order_data.order_id,
             customer_id: order_data.customer_id,
-            product_id: order_data.product_id,
-            quantity: order_data.quantity,
-            total_amount: bigdecimal::BigDecimal::from(order_data.total_amount.to_i64().unwrap()),
+            total_amount: order_data.total_amount.amount.clone(),
+            currency: order_data.total_amount.currency.code().to_string(),
             status: "created".to_string(),
+            saga_id: Some(command.saga_id),
         };
 
+        let lines: Vec<_> = order_data
+            .lines
+            .iter()
+            .zip(1..)
+            .map(|(line, line_number)| OrderLineRecord::new(order_data.order_id, line_number, line))
+            .collect();
+
         let order_data_clone = order_data.clone();
         conn.transaction::<_, anyhow::Error, _>(|conn| {
             Box::pin(async move {
@@ -111,11 +145,17 @@ impl CommandHandler {
                     .execute(conn)
                     .await?;
 
+                diesel::insert_into(order_lines::table)
+                    .values(&lines)
+                    .execute(conn)
+                    .await?;
+
                 let outbox_event = NewOutboxEvent {
                     id: Uuid::new_v4(),
                     aggregate_id: order_data_clone.order_id,
                     event_type: "OrderCreated".to_string(),
                     event_data: serde_json::to_value(&order_data_clone)?,
+                    topic: None,
                 };
 
                 diesel::insert_into(outbox_events::table)
@@ -167,54 +207,89 @@ impl CommandHandler {
             Some(serde_json::to_value(&order_data)?),
         ))
     }
+}
 
-    async fn check_idempotency(&self, conn: &mut AsyncPgConnection, key: &str) -> Result<Option<ProcessedCommand>> {
-        let result = processed_commands::table
-            .filter(processed_commands::idempotency_key.eq(key))
-            .first::<ProcessedCommand>(conn)
-            .await
-            .optional()?;
-        Ok(result)
+impl CommandProcessor for CommandHandler {
+    async fn process(&self, conn: &mut AsyncPgConnection, command: &Command) -> Result<CommandReply> {
+        let reply = match command.command_type {
+            CommandType::CreateOrder => self.handle_create_order(conn, command).await?,
+            CommandType::ApproveOrder => self.handle_approve_order(conn, command).await?,
+            CommandType::CancelOrder => self.handle_cancel_order(conn, command).await?,
+            _ => {
+                warn!("Unsupported command type: {:?}", command.command_type);
+                CommandReply::failed(
+                    command.id,
+                    command.saga_id,
+                    "Unsupported command type".to_string(),
+                )
+            }
+        };
+
+        Ok(reply)
     }
+}
 
-    async fn store_processed_command(&self, conn: &mut AsyncPgConnection, command: &Command, reply: &CommandReply) -> Result<()> {
-        let processed_command = ProcessedCommand {
-            idempotency_key: command.idempotency_key.clone(),
-            command_id: command.id,
-            result: reply.result.clone(),
-            processed_at: Some(chrono::Utc::now()),
-        };
+pub struct SagaManager {
+    pool: DbPool,
+    registry: Arc<SagaRegistry>,
+    updates: broadcast::Sender<SagaUpdate>,
+}
 
-        diesel::insert_into(processed_commands::table)
-            .values(&processed_command)
-            .execute(conn)
-            .await?;
+impl SagaManager {
+    pub fn new(pool: DbPool, registry: Arc<SagaRegistry>) -> Self {
+        let (updates, _) = broadcast::channel(SAGA_UPDATES_CAPACITY);
+        Self { pool, registry, updates }
+    }
 
-        Ok(())
+    /// Updates to sagas saved by this manager from now on. Callers subscribe
+    /// before they read a saga, so that no update can slip in between.
+    pub fn subscribe(&self) -> broadcast::Receiver<SagaUpdate> {
+        self.updates.subscribe()
     }
 
-    async fn send_reply(&self, reply: CommandReply) -> Result<()> {
-        let json = serde_json::to_string(&reply)?;
-        let key = reply.saga_id.to_string();
-        let record = FutureRecord::to(&self.reply_topic)
-            .payload(&json)
-            .key(&key);
+    /// Waits until the saga finishes, woken by the updates this manager
+    /// publishes, and returns its final status, or None if `timeout` expires
+    /// first.
+    pub async fn wait_for_completion(&self, saga_id: Uuid, timeout: Duration) -> Result<Option<SagaStatus>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut updates = self.subscribe();
+        let mut status = self.load_status(saga_id).await?;
 
-        self.producer.send(record, Duration::from_secs(5)).await
-            .map_err(|(e, _)| anyhow::anyhow!("Failed to send reply: {}", e))?;
+        loop {
+            if status.is_terminal() {
+                return Ok(Some(status));
+            }
 
-        Ok(())
+            match tokio::time::timeout_at(deadline, updates.recv()).await {
+                Err(_) => return Ok(None),
+                Ok(Ok(update)) if update.saga_id == saga_id => status = update.status,
+                Ok(Ok(_)) => {}
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => status = self.load_status(saga_id).await?,
+                Ok(Err(broadcast::error::RecvError::Closed)) => return Ok(None),
+            }
+        }
     }
-}
 
-pub struct SagaManager {
-    pool: DbPool,
-    producer: FutureProducer,
-}
+    async fn load_status(&self, saga_id: Uuid) -> Result<SagaStatus> {
+        let mut conn = self.pool.get().await?;
+        load_status(&mut conn, saga_id).await
+    }
 
-impl SagaManager {
-    pub fn new(pool: DbPool, producer: FutureProducer) -> Self {
-        Self { pool, producer }
+    /// The logged events of the saga with a sequence greater than `after`,
+    /// followed by new ones as they are recorded. The stream ends once the
+    /// saga has finished and all of its events have been yielded.
+    pub fn progress(&self, saga_id: Uuid, after: Option<i64>) -> impl Stream<Item = Result<LoggedSagaEvent>> + Send + 'static {
+        let progress = Progress {
+            pool: self.pool.clone(),
+            saga_id,
+            updates: self.subscribe(),
+            last_sequence: after,
+            pending: VecDeque::new(),
+            finished: false,
+        };
+        futures::stream::unfold(progress, |mut progress| async move {
+            progress.next().await.map(|item| (item, progress))
+        })
     }
 
     pub async fn run_reply_handler(&self, consumer: StreamConsumer) {
@@ -245,6 +320,48 @@ impl SagaManager {
     }
 
     async fn handle_reply(&self, reply: CommandReply) -> Result<()> {
+        self.record_reply(&reply).await?;
+        self.retry_on_conflict(reply.saga_id, || self.try_handle_reply(&reply)).await
+    }
+
+    /// Keeps the reply for inspection, whether or not it ends up being applied.
+    async fn record_reply(&self, reply: &CommandReply) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        diesel::insert_into(saga_replies::table)
+            .values(&SagaReply::from(reply))
+            .on_conflict_do_nothing()
+            .execute(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Another reply or the deadline scheduler may update the same saga
+    /// concurrently; if it saves first, reload the saga and apply the change
+    /// on top of it.
+    async fn retry_on_conflict<F, Fut>(&self, saga_id: Uuid, mut apply: F) -> Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<SaveOutcome>>,
+    {
+        for attempt in 1..=MAX_CONFLICT_RETRIES {
+            match apply().await? {
+                SaveOutcome::Saved => return Ok(()),
+                SaveOutcome::Conflict => {
+                    warn!(
+                        "Saga {} was modified concurrently (attempt {}/{})",
+                        saga_id, attempt, MAX_CONFLICT_RETRIES
+                    );
+                }
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "Gave up updating saga {} after {} conflicting updates",
+            saga_id, MAX_CONFLICT_RETRIES
+        ))
+    }
+
+    async fn try_handle_reply(&self, reply: &CommandReply) -> Result<SaveOutcome> {
         let mut conn = self.pool.get().await?;
         
         // Load the saga from database
@@ -254,6 +371,23 @@ impl SagaManager {
             .await?;
 
         let mut saga = SagaTransaction::try_from(saga_data)?;
+
+        // Redelivered or late replies must not move the saga a second time.
+        // Sagas started before commands were tracked wait with no pending
+        // command; the reply they get is taken for their current step
+        let untracked = saga.pending_command_id.is_none() && !saga.status.is_terminal();
+        if saga.pending_command_id != Some(reply.command_id) && !untracked {
+            warn!(
+                "Ignoring reply {} for command {} of saga {} (status {:?}): expected a reply for {:?}",
+                reply.id, reply.command_id, saga.id, saga.status, saga.pending_command_id
+            );
+            return Ok(SaveOutcome::Saved);
+        }
+        // Clears the pending command and, on success, moves past the step
+        // or the compensation the reply is for
+        saga.record(SagaEvent::reply_received(reply));
+
+        let mut outgoing = Vec::new();
         
         match reply.status {
             CommandStatus::Success => {
@@ -261,211 +395,1445 @@ impl SagaManager {
                 
                 // Check if we're in compensation mode
                 if saga.status == shared::SagaStatus::Compensating {
-                    // Move to next compensation step
-                    if let Some(compensation_index_val) = saga.context.get("compensation_index") {
-                        let compensation_index: usize = serde_json::from_value(compensation_index_val.clone())?;
-                        saga.context.insert("compensation_index".to_string(), serde_json::to_value(compensation_index + 1)?);
-                        
-                        // Process next compensation step
-                        self.process_next_compensation(&mut saga).await?;
-                    } else {
-                        // No compensation tracking, mark as completed
-                        saga.status = shared::SagaStatus::Compensated;
-                        info!("Saga {} compensation completed successfully", saga.id);
-                    }
+                    // Process next compensation step
+                    outgoing.extend(self.process_next_compensation(&mut saga)?);
                 } else {
-                    // Normal forward flow
-                    saga.advance_step();
-                    
+                    // Sagas started from an earlier version carry on with the
+                    // steps a later one adds, e.g. committing the reservation
+                    if saga.next_step().is_none() {
+                        if let Some(definition) = self.registry.extension_of(&saga) {
+                            info!(
+                                "Saga {} carries on as {} v{}",
+                                saga.id, definition.name(), definition.version()
+                            );
+                            saga.record(SagaEvent::DefinitionUpgraded {
+                                definition_version: definition.version(),
+                                steps: definition.steps(),
+                            });
+                        }
+                    }
                     // Try to process next step
                     if let Some(step) = saga.next_step().cloned() {
                         let command = self.create_command_for_step(&saga, &step)?;
-                        self.send_command(&command, &step.service_name).await?;
-                        info!("Sent command {} to {} for saga {}", command.id, step.service_name, saga.id);
+                        saga.await_reply(&command, saga.current_step);
+                        outgoing.push((command, step.service_name));
                     } else {
                         // Saga completed successfully
-                        saga.status = shared::SagaStatus::Completed;
+                        saga.record(SagaEvent::SagaCompleted);
                         info!("Saga {} completed successfully", saga.id);
                     }
                 }
             }
             CommandStatus::Failed => {
                 error!("Command {} failed for saga {}: {:?}", reply.command_id, reply.saga_id, reply.error);
-                saga.status = shared::SagaStatus::Compensating;
-                // Start compensation process
-                self.start_compensation(&mut saga).await?;
+                // Start compensation process; the failed step itself did nothing to undo
+                outgoing.extend(self.start_compensation(&mut saga, false)?);
             }
-            CommandStatus::Compensated => {
+            CommandStatus::Compensated if saga.status == SagaStatus::Compensating => {
                 info!("Command {} compensated for saga {}", reply.command_id, reply.saga_id);
-                // Continue compensation if needed
-                self.continue_compensation(&mut saga).await?;
+                outgoing.extend(self.process_next_compensation(&mut saga)?);
+            }
+            CommandStatus::Compensated => {
+                warn!(
+                    "Command {} of saga {} reported a compensation while the saga is {:?}",
+                    reply.command_id, reply.saga_id, saga.status
+                );
             }
         }
         
-        // Update saga in database
-        let updated_saga = crate::models::DbSagaTransaction::from(saga);
-        diesel::update(saga_transactions::table.filter(saga_transactions::id.eq(reply.saga_id)))
-            .set(&updated_saga)
-            .execute(&mut conn)
+        self.persist(&mut conn, saga, outgoing, None).await
+    }
+
+    /// Updates the saga in database, unless someone else got there first, and
+    /// queues the resulting commands in the same transaction. `resend` is an
+    /// already queued command to publish once more.
+    async fn persist(
+        &self,
+        conn: &mut AsyncPgConnection,
+        mut saga: SagaTransaction,
+        outgoing: Vec<(Command, String)>,
+        resend: Option<Uuid>,
+    ) -> Result<SaveOutcome> {
+        let update = SagaUpdate {
+            saga_id: saga.id,
+            status: saga.status.clone(),
+        };
+
+        let outcome = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            Box::pin(async move {
+                if self.save_saga(conn, &mut saga).await? == SaveOutcome::Conflict {
+                    return Ok(SaveOutcome::Conflict);
+                }
+
+                for (command, service_name) in &outgoing {
+                    enqueue_command(conn, command, service_name).await?;
+                    info!("Queued command {} to {} for saga {}", command.id, service_name, saga.id);
+                }
+
+                if let Some(command_id) = resend {
+                    requeue_command(conn, command_id).await?;
+                }
+
+                Ok(SaveOutcome::Saved)
+            })
+        }).await?;
+
+        if outcome == SaveOutcome::Saved {
+            // Nobody may be listening, which is fine
+            let _ = self.updates.send(update);
+        }
+        Ok(outcome)
+    }
+
+    pub async fn run_deadline_scheduler(&self) {
+        let mut interval = tokio::time::interval(DEADLINE_CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = self.handle_expired_deadlines().await {
+                error!("Error handling expired saga deadlines: {}", e);
+            }
+        }
+    }
+
+    async fn handle_expired_deadlines(&self) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+
+        let expired = saga_transactions::table
+            .filter(saga_transactions::status.eq_any(unfinished_statuses()))
+            .filter(saga_transactions::deadline.lt(chrono::Utc::now()))
+            .select(saga_transactions::id)
+            .load::<Uuid>(&mut conn)
             .await?;
-        
+
+        for saga_id in expired {
+            if let Err(e) = self.retry_on_conflict(saga_id, || self.try_handle_expired_deadline(saga_id)).await {
+                error!("Error handling expired deadline of saga {}: {}", saga_id, e);
+            }
+        }
+
         Ok(())
     }
 
-    async fn start_compensation(&self, saga: &mut SagaTransaction) -> Result<()> {
-        let compensation_steps = saga.get_compensation_steps();
-        
-        // Convert to owned values to store in context
-        let owned_steps: Vec<SagaStep> = compensation_steps.into_iter().cloned().collect();
+    /// Resends the pending command of a saga whose deadline passed while its
+    /// step has retries left, and otherwise fails the step and compensates.
+    async fn try_handle_expired_deadline(&self, saga_id: Uuid) -> Result<SaveOutcome> {
+        let mut conn = self.pool.get().await?;
+
+        let saga_data = saga_transactions::table
+            .filter(saga_transactions::id.eq(saga_id))
+            .first::<DbSagaTransaction>(&mut conn)
+            .await?;
+        let mut saga = SagaTransaction::try_from(saga_data)?;
+
+        // The reply may have arrived since the expired sagas were listed
+        let (Some(command_id), Some(deadline)) = (saga.pending_command_id, saga.deadline) else {
+            return Ok(SaveOutcome::Saved);
+        };
+        if deadline > chrono::Utc::now() || saga.status.is_terminal() {
+            return Ok(SaveOutcome::Saved);
+        }
+
+        let step_index = self.in_flight_step(&saga)?;
+        let max_retries = saga.steps[step_index].timeout.map_or(0, |t| t.max_retries);
+        let compensating = saga.status == SagaStatus::Compensating;
+
+        if compensating || saga.attempts <= max_retries {
+            warn!(
+                "Command {} of saga {} timed out, resending it (attempt {})",
+                command_id, saga.id, saga.attempts + 1
+            );
+            saga.record(SagaEvent::CommandTimedOut {
+                command_id,
+                attempts: saga.attempts,
+                retry: true,
+            });
+            saga.retry_pending(step_index);
+            return self.persist(&mut conn, saga, Vec::new(), Some(command_id)).await;
+        }
+
+        error!(
+            "Command {} of saga {} timed out after {} attempts",
+            command_id, saga.id, saga.attempts
+        );
+        saga.record(SagaEvent::CommandTimedOut {
+            command_id,
+            attempts: saga.attempts,
+            retry: false,
+        });
+        // The participant may have handled the command without us hearing
+        // back, so the timed out step is compensated as well
+        let outgoing = self.start_compensation(&mut saga, true)?;
+        self.persist(&mut conn, saga, outgoing, None).await
+    }
+
+    /// Index of the step whose command, forward or compensation, is in flight.
+    fn in_flight_step(&self, saga: &SagaTransaction) -> Result<usize> {
+        if saga.status == SagaStatus::Compensating {
+            saga.compensation
+                .as_ref()
+                .and_then(|c| c.current_step())
+                .ok_or_else(|| anyhow::anyhow!("Saga {} has no compensation in flight", saga.id))
+        } else {
+            Ok(saga.current_step)
+        }
+    }
+
+    /// Writes the saga back only if nobody else has updated it since it was
+    /// loaded, bumping its version on success.
+    async fn save_saga(&self, conn: &mut AsyncPgConnection, saga: &mut SagaTransaction) -> Result<SaveOutcome> {
+        let loaded_version = saga.version as i32;
+        saga.version += 1;
+        saga.updated_at = chrono::Utc::now();
+
+        let updated_saga = crate::models::DbSagaTransaction::from(saga.clone());
+        let updated_rows = diesel::update(
+            saga_transactions::table
+                .filter(saga_transactions::id.eq(saga.id))
+                .filter(saga_transactions::version.eq(loaded_version)),
+        )
+        .set(&updated_saga)
+        .execute(conn)
+        .await?;
+
+        if updated_rows == 0 {
+            saga.version -= 1;
+            return Ok(SaveOutcome::Conflict);
+        }
+
+        let finished = saga.uncommitted_events.iter().any(SagaEvent::finishes_saga);
+        append_events(conn, saga).await?;
+        if finished {
+            let queued = webhooks::enqueue_deliveries(conn, saga).await?;
+            if queued > 0 {
+                info!("Queued {} webhook deliveries for saga {}", queued, saga.id);
+            }
+        }
+        Ok(SaveOutcome::Saved)
+    }
+
+    fn start_compensation(&self, saga: &mut SagaTransaction, include_current_step: bool) -> Result<Vec<(Command, String)>> {
+        // Rolling back past a step that cannot be undone would, e.g., cancel
+        // and refund an approved order, so an operator has to step in instead
+        if let Some(irreversible_step) = saga.irreversible_step() {
+            error!(
+                "Step {} of saga {} failed after step {}, which cannot be undone, marking the saga failed",
+                saga.current_step, saga.id, irreversible_step
+            );
+            saga.record(SagaEvent::SagaFailed {
+                step_index: saga.current_step,
+            });
+            return Ok(Vec::new());
+        }
+
+        let mut compensation_steps = saga.compensation_step_indices();
+        let current_step = saga.current_step;
+        if include_current_step && saga.steps.get(current_step).is_some_and(|s| s.compensation_type.is_some()) {
+            compensation_steps.insert(0, current_step);
+        }
         
-        // Store all compensation commands to process them in sequence
-        saga.context.insert("compensation_steps".to_string(), serde_json::to_value(&owned_steps)?);
-        saga.context.insert("compensation_index".to_string(), serde_json::to_value(0)?);
+        // Starting over after a failed compensation must not be answered from
+        // the participants' idempotency cache
+        let round = saga.compensation.as_ref().map_or(0, |c| c.round + 1);
+
+        saga.record(SagaEvent::CompensationStarted {
+            steps: compensation_steps,
+            round,
+        });
         
         // Start with the first compensation step
-        self.process_next_compensation(saga).await?;
-        
-        Ok(())
+        self.process_next_compensation(saga)
     }
 
-    async fn process_next_compensation(&self, saga: &mut SagaTransaction) -> Result<()> {
-        let compensation_steps: Vec<SagaStep> = serde_json::from_value(
-            saga.context.get("compensation_steps").unwrap().clone()
-        )?;
-        let compensation_index: usize = serde_json::from_value(
-            saga.context.get("compensation_index").unwrap().clone()
-        )?;
+    fn process_next_compensation(&self, saga: &mut SagaTransaction) -> Result<Vec<(Command, String)>> {
+        let compensation = saga
+            .compensation
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Saga {} has not started compensating", saga.id))?;
+        let mut outgoing = Vec::new();
 
-        if let Some(step) = compensation_steps.get(compensation_index) {
+        if let Some(step_index) = compensation.current_step() {
+            let step = saga.steps[step_index].clone();
             if let Some(compensation_type) = &step.compensation_type {
-                let payload = match compensation_type {
-                    CommandType::CancelOrder => {
-                        let order_data: OrderData = serde_json::from_value(
-                            saga.context.get("order_data").unwrap().clone()
-                        )?;
-                        serde_json::to_value(order_data)?
-                    }
-                    CommandType::CompensatePayment => {
-                        let order_data: OrderData = serde_json::from_value(
-                            saga.context.get("order_data").unwrap().clone()
-                        )?;
-                        let payment_data = PaymentData {
-                            order_id: order_data.order_id,
-                            amount: order_data.total_amount,
-                            payment_method: "credit_card".to_string(),
-                        };
-                        serde_json::to_value(payment_data)?
-                    }
-                    CommandType::CompensateInventory => {
-                        let order_data: OrderData = serde_json::from_value(
-                            saga.context.get("order_data").unwrap().clone()
-                        )?;
-                        let inventory_data = InventoryData {
-                            product_id: order_data.product_id,
-                            quantity: order_data.quantity,
-                            order_id: order_data.order_id,
-                        };
-                        serde_json::to_value(inventory_data)?
-                    }
-                    _ => saga.context.get("order_data").unwrap().clone(),
-                };
-                
+                let definition = self.registry.resolve(saga)?;
+                let payload = definition.compensation_payload(saga, step_index)?;
+
                 let compensation_command = Command::new(
                     saga.id,
+                    step_index,
+                    compensation.round,
                     compensation_type.clone(),
                     payload,
                 );
-                self.send_command(&compensation_command, &step.service_name).await?;
-                info!("Started compensation step {} for saga {}", compensation_index, saga.id);
+                saga.await_reply(&compensation_command, step_index);
+                outgoing.push((compensation_command, step.service_name));
+                info!("Started compensation step {} for saga {}", compensation.completed, saga.id);
             }
         } else {
             // No more compensation steps
-            saga.status = shared::SagaStatus::Compensated;
+            saga.record(SagaEvent::CompensationCompleted);
             info!("All compensations completed for saga {}", saga.id);
         }
-        Ok(())
+        Ok(outgoing)
     }
 
-    async fn continue_compensation(&self, saga: &mut SagaTransaction) -> Result<()> {
-        let compensation_steps = saga.get_compensation_steps();
-        // This is a simplified compensation flow
-        // In a real implementation, you'd track which compensations have been completed
-        if compensation_steps.is_empty() {
-            saga.status = shared::SagaStatus::Compensated;
-            info!("Compensation completed for saga {}", saga.id);
+    /// Re-issues the in-flight command of every saga that has not finished yet,
+    /// e.g. after the service was stopped mid-saga. The command is re-published
+    /// unchanged, so participants see the original idempotency key.
+    pub async fn recover_sagas(&self) -> Result<usize> {
+        let mut conn = self.pool.get().await?;
+
+        let in_flight = saga_transactions::table
+            .filter(saga_transactions::status.eq_any(unfinished_statuses()))
+            .filter(saga_transactions::pending_command_id.is_not_null())
+            .select((saga_transactions::id, saga_transactions::pending_command_id))
+            .load::<(Uuid, Option<Uuid>)>(&mut conn)
+            .await?;
+
+        // Their command was never stored, so only the participant's reply can move them on
+        let untracked: i64 = saga_transactions::table
+            .filter(saga_transactions::status.eq_any(unfinished_statuses()))
+            .filter(saga_transactions::pending_command_id.is_null())
+            .count()
+            .get_result(&mut conn)
+            .await?;
+        if untracked > 0 {
+            warn!("{} unfinished sagas have no tracked command to re-issue", untracked);
+        }
+
+        let mut recovered = 0;
+        for (saga_id, command_id) in in_flight {
+            let Some(command_id) = command_id else { continue };
+
+            if !requeue_command(&mut conn, command_id).await? {
+                warn!("No outbox record for in-flight command {} of saga {}, cannot re-issue it", command_id, saga_id);
+                continue;
+            }
+            info!("Re-issued in-flight command {} for saga {}", command_id, saga_id);
+            recovered += 1;
+        }
+
+        Ok(recovered)
+    }
+
+    pub async fn run_inventory_event_handler(&self, consumer: StreamConsumer) {
+        let mut message_stream = consumer.stream();
+
+        while let Some(message) = message_stream.next().await {
+            match message {
+                Ok(m) => {
+                    if let Some(payload) = m.payload_view::<str>() {
+                        match payload {
+                            Ok(json_str) => {
+                                if let Ok(event) = serde_json::from_str::<InventoryEvent>(json_str) {
+                                    if let Err(e) = self.handle_inventory_event(event).await {
+                                        error!("Error handling inventory event: {}", e);
+                                    }
+                                }
+                            }
+                            Err(e) => error!("Error parsing inventory event payload: {}", e),
+                        }
+                    }
+                    if let Err(e) = consumer.commit_message(&m, rdkafka::consumer::CommitMode::Async) {
+                        error!("Error committing inventory event message: {}", e);
+                    }
+                }
+                Err(e) => error!("Error receiving inventory event message: {}", e),
+            }
         }
-        Ok(())
     }
 
-    pub async fn start_saga(&self, mut saga: SagaTransaction) -> Result<()> {
+    async fn handle_inventory_event(&self, event: InventoryEvent) -> Result<()> {
+        match event {
+            InventoryEvent::ReservationExpired {
+                reservation_id,
+                saga_id: Some(saga_id),
+                ..
+            } => {
+                self.retry_on_conflict(saga_id, || self.try_handle_reservation_expired(saga_id, reservation_id))
+                    .await
+            }
+            InventoryEvent::ReservationExpired { reservation_id, order_id, .. } => {
+                warn!("Reservation {} of order {} expired without a saga to notify", reservation_id, order_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Compensates a saga that is still running when its inventory
+    /// reservation expires. The stock is already released, so compensating
+    /// the inventory step is a no-op; payment and order are undone. A saga
+    /// past a step that cannot be undone fails instead.
+    async fn try_handle_reservation_expired(&self, saga_id: Uuid, reservation_id: Uuid) -> Result<SaveOutcome> {
         let mut conn = self.pool.get().await?;
 
-        let db_saga = DbSagaTransaction::from(saga.clone());
-        diesel::insert_into(saga_transactions::table)
-            .values(&db_saga)
-            .execute(&mut conn)
-            .await?;
+        let Some(saga_data) = saga_transactions::table
+            .filter(saga_transactions::id.eq(saga_id))
+            .first::<DbSagaTransaction>(&mut conn)
+            .await
+            .optional()?
+        else {
+            warn!("Reservation {} expired for unknown saga {}", reservation_id, saga_id);
+            return Ok(SaveOutcome::Saved);
+        };
+        let mut saga = SagaTransaction::try_from(saga_data)?;
 
-        let step_option = {
-            let saga_ref = &mut saga;
-            saga_ref.next_step().cloned()
+        match saga.status {
+            SagaStatus::Completed => {
+                error!(
+                    "Reservation {} of saga {} expired after the order was approved",
+                    reservation_id, saga.id
+                );
+                return Ok(SaveOutcome::Saved);
+            }
+            // Compensation releases the reservation anyway
+            SagaStatus::Compensating | SagaStatus::Compensated | SagaStatus::Failed => {
+                return Ok(SaveOutcome::Saved);
+            }
+            SagaStatus::Started | SagaStatus::InProgress => {}
+        }
+
+        warn!("Reservation {} of saga {} expired, compensating the saga", reservation_id, saga.id);
+        saga.record(SagaEvent::ReservationExpired { reservation_id });
+        let outgoing = self.start_compensation(&mut saga, false)?;
+        self.persist(&mut conn, saga, outgoing, None).await
+    }
+
+    /// Applies an operator's action to a stuck saga and records it in the
+    /// saga's event log. Fails with an `InterventionError` if the saga does
+    /// not exist or the action does not fit its state.
+    pub async fn intervene(&self, saga_id: Uuid, kind: InterventionKind, note: Option<String>) -> Result<()> {
+        self.retry_on_conflict(saga_id, || self.try_intervene(saga_id, kind, note.clone())).await
+    }
+
+    async fn try_intervene(&self, saga_id: Uuid, kind: InterventionKind, note: Option<String>) -> Result<SaveOutcome> {
+        let mut conn = self.pool.get().await?;
+
+        let Some(saga_data) = saga_transactions::table
+            .filter(saga_transactions::id.eq(saga_id))
+            .first::<DbSagaTransaction>(&mut conn)
+            .await
+            .optional()?
+        else {
+            return Err(InterventionError::NotFound.into());
         };
-        
-        if let Some(step) = step_option {
-            let command = self.create_command_for_step(&saga, &step)?;
-            self.send_command(&command, &step.service_name).await?;
+        let mut saga = SagaTransaction::try_from(saga_data)?;
+
+        if saga.status.is_terminal() {
+            return Err(InterventionError::Rejected(format!(
+                "Saga {} already finished as {:?}",
+                saga.id, saga.status
+            ))
+            .into());
         }
 
-        Ok(())
+        match kind {
+            InterventionKind::Resend => {
+                let Some(command_id) = saga.pending_command_id else {
+                    return Err(InterventionError::Rejected(format!("Saga {} has no command in flight", saga.id)).into());
+                };
+                let deadline = saga.step_deadline(self.in_flight_step(&saga)?);
+                warn!("Manually resending command {} of saga {}", command_id, saga.id);
+                saga.record(SagaEvent::ManualIntervention {
+                    intervention: Intervention::Resend { command_id, deadline },
+                    note,
+                });
+                self.persist(&mut conn, saga, Vec::new(), Some(command_id)).await
+            }
+            InterventionKind::Abort => {
+                warn!("Manually aborting saga {}", saga.id);
+                saga.record(SagaEvent::ManualIntervention {
+                    intervention: Intervention::Abort,
+                    note,
+                });
+                self.persist(&mut conn, saga, Vec::new(), None).await
+            }
+        }
+    }
+
+    /// Starts a new instance of the latest version of the named saga and
+    /// returns its id. The idempotency key of the request that asked for it,
+    /// if any, is stored in the same transaction, so a stored key always
+    /// refers to a started saga. Fails with a unique violation if the key has
+    /// been stored already.
+    pub async fn start_saga(
+        &self,
+        definition_name: &str,
+        context: HashMap<String, serde_json::Value>,
+        request_key: Option<OrderRequestKey>,
+    ) -> Result<Uuid> {
+        let definition = self
+            .registry
+            .latest(definition_name)
+            .ok_or_else(|| anyhow::anyhow!("No saga definition registered for {}", definition_name))?;
+        let mut saga = definition.start(context);
+
+        let mut conn = self.pool.get().await?;
+
+        let first_command = match saga.next_step().cloned() {
+            Some(step) => {
+                let command = self.create_command_for_step(&saga, &step)?;
+                saga.await_reply(&command, saga.current_step);
+                Some((command, step.service_name))
+            }
+            None => None,
+        };
+
+        let saga_id = saga.id;
+        let db_saga = DbSagaTransaction::from(saga.clone());
+        let request_key = request_key.map(|key| key.into_record(saga_id));
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            Box::pin(async move {
+                diesel::insert_into(saga_transactions::table)
+                    .values(&db_saga)
+                    .execute(conn)
+                    .await?;
+                append_events(conn, &mut saga).await?;
+
+                if let Some(request_key) = &request_key {
+                    idempotency::insert_key(conn, request_key).await?;
+                }
+
+                if let Some((command, service_name)) = &first_command {
+                    enqueue_command(conn, command, service_name).await?;
+                }
+
+                Ok(())
+            })
+        }).await?;
+
+        Ok(saga_id)
     }
 
     fn create_command_for_step(&self, saga: &SagaTransaction, step: &SagaStep) -> Result<Command> {
-        let payload = match step.command_type {
-            CommandType::CreateOrder | CommandType::ApproveOrder | CommandType::CancelOrder => {
-                let order_data: OrderData = serde_json::from_value(
-                    saga.context.get("order_data").unwrap().clone()
-                )?;
-                serde_json::to_value(order_data)?
+        let definition = self.registry.resolve(saga)?;
+        let payload = definition.command_payload(saga, saga.current_step)?;
+
+        Ok(Command::new(saga.id, saga.current_step, 0, step.command_type.clone(), payload))
+    }
+}
+
+/// Writes a command to the outbox; `OutboxProcessor` publishes it to the
+/// target service's command topic. The outbox record shares the command's id
+/// so that an in-flight command can later be re-published as is.
+async fn enqueue_command(conn: &mut AsyncPgConnection, command: &Command, service_name: &str) -> Result<()> {
+    let outbox_event = NewOutboxEvent {
+        id: command.id,
+        aggregate_id: command.saga_id,
+        event_type: format!("{:?}", command.command_type),
+        event_data: serde_json::to_value(command)?,
+        topic: Some(format!("{}-commands", service_name)),
+    };
+
+    diesel::insert_into(outbox_events::table)
+        .values(&outbox_event)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Appends the events recorded on the saga since it was loaded to its log,
+/// tagged with the version they produced.
+async fn append_events(conn: &mut AsyncPgConnection, saga: &mut SagaTransaction) -> Result<()> {
+    let events = std::mem::take(&mut saga.uncommitted_events)
+        .into_iter()
+        .map(|event| {
+            Ok(NewSagaEvent {
+                saga_id: saga.id,
+                saga_version: saga.version as i32,
+                event_type: event.event_type().to_string(),
+                data: serde_json::to_value(&event)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    diesel::insert_into(saga_events::table)
+        .values(&events)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Marks a command queued by `enqueue_command` for publishing once more.
+/// Returns false if the command is not in the outbox.
+async fn requeue_command(conn: &mut AsyncPgConnection, command_id: Uuid) -> Result<bool> {
+    let requeued = diesel::update(outbox_events::table.filter(outbox_events::id.eq(command_id)))
+        .set(outbox_events::processed.eq(false))
+        .execute(conn)
+        .await?;
+
+    Ok(requeued > 0)
+}
+
+fn unfinished_statuses() -> Vec<String> {
+    [SagaStatus::Started, SagaStatus::InProgress, SagaStatus::Compensating]
+        .iter()
+        .map(|status| format!("{:?}", status))
+        .collect()
+}
+
+async fn load_status(conn: &mut AsyncPgConnection, saga_id: Uuid) -> Result<SagaStatus> {
+    let db_saga = saga_transactions::table
+        .filter(saga_transactions::id.eq(saga_id))
+        .first::<DbSagaTransaction>(conn)
+        .await?;
+    Ok(SagaTransaction::try_from(db_saga)?.status)
+}
+
+/// State of a stream returned by `SagaManager::progress`.
+struct Progress {
+    pool: DbPool,
+    saga_id: Uuid,
+    updates: broadcast::Receiver<SagaUpdate>,
+    last_sequence: Option<i64>,
+    pending: VecDeque<LoggedSagaEvent>,
+    finished: bool,
+}
+
+impl Progress {
+    async fn next(&mut self) -> Option<Result<LoggedSagaEvent>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
             }
-            CommandType::ProcessPayment => {
-                let order_data: OrderData = serde_json::from_value(
-                    saga.context.get("order_data").unwrap().clone()
-                )?;
-                let payment_data = PaymentData {
-                    order_id: order_data.order_id,
-                    amount: order_data.total_amount,
-                    payment_method: "credit_card".to_string(),
-                };
-                serde_json::to_value(payment_data)?
+            if self.finished {
+                return None;
             }
-            CommandType::ReserveInventory => {
-                let order_data: OrderData = serde_json::from_value(
-                    saga.context.get("order_data").unwrap().clone()
-                )?;
-                let inventory_data = InventoryData {
-                    product_id: order_data.product_id,
-                    quantity: order_data.quantity,
-                    order_id: order_data.order_id,
-                };
-                serde_json::to_value(inventory_data)?
+
+            if let Err(e) = self.catch_up().await {
+                self.finished = true;
+                return Some(Err(e));
+            }
+            if self.pending.is_empty() && !self.finished {
+                self.wait_for_update().await;
+            }
+        }
+    }
+
+    async fn catch_up(&mut self) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        // The status is read first: a saga's events are committed together
+        // with its status, so once it reads as finished the events loaded
+        // below are the last ones.
+        let status = load_status(&mut conn, self.saga_id).await?;
+        let events = queries::saga_events_after(&mut conn, self.saga_id, self.last_sequence).await?;
+
+        if let Some(last) = events.last() {
+            self.last_sequence = Some(last.sequence);
+        }
+        self.pending.extend(events);
+        self.finished = status.is_terminal();
+        Ok(())
+    }
+
+    async fn wait_for_update(&mut self) {
+        loop {
+            match tokio::time::timeout(PROGRESS_POLL_INTERVAL, self.updates.recv()).await {
+                Ok(Ok(update)) if update.saga_id != self.saga_id => {}
+                Ok(Err(broadcast::error::RecvError::Closed)) => {
+                    self.finished = true;
+                    return;
+                }
+                // Our saga was saved, updates were missed or it is time to poll
+                _ => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sagas;
+    use crate::test_support::{order_context, test_pool};
+    use shared::outbox::DbOutboxEvent;
+
+    fn saga_manager(pool: DbPool) -> SagaManager {
+        SagaManager::new(pool, Arc::new(sagas::registry()))
+    }
+
+    /// Index of the step of the order saga that sends `command_type`.
+    fn step_of(command_type: CommandType) -> usize {
+        sagas::create_order_saga()
+            .steps()
+            .iter()
+            .position(|step| step.command_type == command_type)
+            .unwrap()
+    }
+
+    /// Inserts an order saga that is waiting for the reply to its last step.
+    async fn insert_saga_awaiting_last_reply(pool: &DbPool) -> SagaTransaction {
+        insert_saga_awaiting_reply(pool, sagas::create_order_saga().steps().len() - 1).await
+    }
+
+    async fn insert_saga_awaiting_reply(pool: &DbPool, step: usize) -> SagaTransaction {
+        insert_saga_of(pool, &sagas::create_order_saga(), step).await
+    }
+
+    /// Inserts a saga started from `definition` that is waiting for the reply
+    /// to `step`.
+    async fn insert_saga_of(pool: &DbPool, definition: &SagaDefinition, step: usize) -> SagaTransaction {
+        let mut saga = definition.start(order_context());
+        saga.current_step = step;
+        saga.status = SagaStatus::InProgress;
+        saga.pending_command_id = Some(Uuid::new_v4());
+
+        let mut conn = pool.get().await.unwrap();
+        diesel::insert_into(saga_transactions::table)
+            .values(&DbSagaTransaction::from(saga.clone()))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        saga
+    }
+
+    async fn load_saga(pool: &DbPool, id: Uuid) -> SagaTransaction {
+        let mut conn = pool.get().await.unwrap();
+        let db_saga = saga_transactions::table
+            .filter(saga_transactions::id.eq(id))
+            .first::<DbSagaTransaction>(&mut conn)
+            .await
+            .unwrap();
+        SagaTransaction::try_from(db_saga).unwrap()
+    }
+
+    #[tokio::test]
+    async fn stale_saga_update_is_rejected() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_last_reply(&pool).await;
+
+        let mut first = load_saga(&pool, saga.id).await;
+        let mut second = load_saga(&pool, saga.id).await;
+        first.status = SagaStatus::Completed;
+        second.status = SagaStatus::Compensating;
+
+        let mut conn = pool.get().await.unwrap();
+        assert_eq!(manager.save_saga(&mut conn, &mut first).await.unwrap(), SaveOutcome::Saved);
+        assert_eq!(manager.save_saga(&mut conn, &mut second).await.unwrap(), SaveOutcome::Conflict);
+
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::Completed);
+        assert_eq!(stored.version, 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_duplicate_replies_are_applied_once() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = Arc::new(saga_manager(pool.clone()));
+        let saga = insert_saga_awaiting_last_reply(&pool).await;
+        let reply = CommandReply::success(saga.pending_command_id.unwrap(), saga.id, None);
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let manager = manager.clone();
+                let reply = reply.clone();
+                tokio::spawn(async move { manager.handle_reply(reply).await })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::Completed);
+        assert_eq!(stored.current_step, saga.steps.len());
+        assert_eq!(stored.pending_command_id, None);
+        assert_eq!(stored.version, 1);
+    }
+
+    #[tokio::test]
+    async fn reply_racing_a_timeout_is_applied_on_top_of_it() {
+        use diesel_async::SimpleAsyncConnection;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_last_reply(&pool).await;
+        let mut conn = pool.get().await.unwrap();
+        diesel::update(saga_transactions::table.find(saga.id))
+            .set((
+                saga_transactions::deadline.eq(chrono::Utc::now() - chrono::Duration::seconds(1)),
+                saga_transactions::attempts.eq(1),
+            ))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        // Holding the saga row lets both transitions load version 0 and then
+        // queue up behind the lock to save it
+        let mut lock = pool.get().await.unwrap();
+        lock.batch_execute(&format!("BEGIN; SELECT 1 FROM saga_transactions WHERE id = '{}' FOR UPDATE", saga.id))
+            .await
+            .unwrap();
+        let lock_pid: i32 = diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>("pg_backend_pid()"))
+            .get_result(&mut lock)
+            .await
+            .unwrap();
+
+        let applied = AtomicUsize::new(0);
+        let count = || applied.fetch_add(1, Ordering::SeqCst);
+        let reply = CommandReply::success(saga.pending_command_id.unwrap(), saga.id, None);
+        let race = async {
+            tokio::join!(
+                manager.retry_on_conflict(saga.id, || {
+                    count();
+                    manager.try_handle_reply(&reply)
+                }),
+                manager.retry_on_conflict(saga.id, || {
+                    count();
+                    manager.try_handle_expired_deadline(saga.id)
+                }),
+            )
+        };
+        let release = async {
+            // The second writer waits behind the first one rather than the lock
+            let blocked = format!(
+                "(SELECT count(*) FROM pg_stat_activity WHERE {0} = ANY(pg_blocking_pids(pid)) \
+                 OR EXISTS (SELECT 1 FROM unnest(pg_blocking_pids(pid)) AS waiter WHERE {0} = ANY(pg_blocking_pids(waiter))))",
+                lock_pid
+            );
+            loop {
+                let waiting: i64 = diesel::select(diesel::dsl::sql::<diesel::sql_types::BigInt>(&blocked))
+                    .get_result(&mut conn)
+                    .await
+                    .unwrap();
+                if waiting == 2 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
             }
-            _ => return Err(anyhow::anyhow!("Unsupported command type")),
+            lock.batch_execute("COMMIT").await.unwrap();
         };
+        let ((replied, timed_out), ()) = tokio::time::timeout(Duration::from_secs(10), async { tokio::join!(race, release) })
+            .await
+            .unwrap();
+        replied.unwrap();
+        timed_out.unwrap();
+
+        // Whichever saved second found version 0 gone and was applied again
+        assert_eq!(applied.into_inner(), 3);
 
-        Ok(Command::new(saga.id, step.command_type.clone(), payload))
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::Completed);
+        let events: Vec<_> = queries::saga_events(&mut conn, saga.id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.event.event_type())
+            .collect();
+        assert_eq!(events.iter().filter(|&&t| t == "ReplyReceived").count(), 1);
+        // The timeout only changes the saga if it saved before the reply
+        // completed it; each change bumps the version once
+        let resent = events.contains(&"CommandResent");
+        assert_eq!(stored.version, if resent { 2 } else { 1 });
     }
 
-    async fn send_command(&self, command: &Command, service_name: &str) -> Result<()> {
-        let topic = format!("{}-commands", service_name);
-        let json = serde_json::to_string(command)?;
-        let key = command.saga_id.to_string();
-        let record = FutureRecord::to(&topic)
-            .payload(&json)
-            .key(&key);
+    #[tokio::test]
+    async fn compensated_reply_moves_on_to_the_next_compensation() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_reply(&pool, step_of(CommandType::ReserveInventory)).await;
+        let failure = CommandReply::failed(saga.pending_command_id.unwrap(), saga.id, "Insufficient inventory".to_string());
+        manager.handle_reply(failure).await.unwrap();
+        let refund = load_saga(&pool, saga.id).await.pending_command_id.unwrap();
 
-        self.producer.send(record, Duration::from_secs(5)).await
-            .map_err(|(e, _)| anyhow::anyhow!("Failed to send command: {}", e))?;
+        let mut compensated = CommandReply::success(refund, saga.id, None);
+        compensated.status = CommandStatus::Compensated;
+        manager.handle_reply(compensated).await.unwrap();
 
-        Ok(())
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::Compensating);
+        assert_eq!(stored.compensation.as_ref().unwrap().completed, 1);
+        assert_ne!(stored.pending_command_id, Some(refund));
+        assert!(stored.pending_command_id.is_some());
+    }
+
+    #[tokio::test]
+    async fn reply_to_another_command_is_ignored() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_last_reply(&pool).await;
+
+        manager
+            .handle_reply(CommandReply::success(Uuid::new_v4(), saga.id, None))
+            .await
+            .unwrap();
+
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::InProgress);
+        assert_eq!(stored.pending_command_id, saga.pending_command_id);
+        assert_eq!(stored.version, 0);
+    }
+
+    #[tokio::test]
+    async fn saga_without_a_tracked_command_takes_the_reply_for_its_current_step() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let mut saga = sagas::create_order_saga().start(order_context());
+        saga.current_step = saga.steps.len() - 1;
+        saga.status = SagaStatus::InProgress;
+
+        let mut conn = pool.get().await.unwrap();
+        diesel::insert_into(saga_transactions::table)
+            .values(&DbSagaTransaction::from(saga.clone()))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        let reply = CommandReply::success(Uuid::new_v4(), saga.id, None);
+        manager.handle_reply(reply.clone()).await.unwrap();
+        assert_eq!(load_saga(&pool, saga.id).await.status, SagaStatus::Completed);
+
+        // Once it has moved on, the same reply is stale
+        manager.handle_reply(reply).await.unwrap();
+        assert_eq!(load_saga(&pool, saga.id).await.version, 1);
+    }
+
+    #[tokio::test]
+    async fn next_command_is_queued_in_outbox() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_reply(&pool, 0).await;
+        let reply = CommandReply::success(saga.pending_command_id.unwrap(), saga.id, None);
+
+        manager.handle_reply(reply).await.unwrap();
+
+        let stored = load_saga(&pool, saga.id).await;
+        let mut conn = pool.get().await.unwrap();
+        let events = outbox_events::table
+            .filter(outbox_events::aggregate_id.eq(saga.id))
+            .load::<DbOutboxEvent>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].topic.as_deref(), Some("payment-service-commands"));
+
+        let command: Command = serde_json::from_value(events[0].event_data.clone()).unwrap();
+        assert!(matches!(command.command_type, CommandType::ProcessPayment));
+        assert_eq!(stored.pending_command_id, Some(command.id));
+    }
+
+    #[tokio::test]
+    async fn recovery_requeues_in_flight_command() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga_id = manager
+            .start_saga(sagas::CREATE_ORDER_SAGA, order_context(), None)
+            .await
+            .unwrap();
+
+        let mut conn = pool.get().await.unwrap();
+        diesel::update(outbox_events::table.filter(outbox_events::aggregate_id.eq(saga_id)))
+            .set(outbox_events::processed.eq(true))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        manager.recover_sagas().await.unwrap();
+
+        let saga = load_saga(&pool, saga_id).await;
+        let event = outbox_events::table
+            .filter(outbox_events::id.eq(saga.pending_command_id.unwrap()))
+            .first::<DbOutboxEvent>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(event.processed, Some(false));
+    }
+
+    #[tokio::test]
+    async fn saga_view_lists_commands_and_every_reply() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga_id = manager
+            .start_saga(sagas::CREATE_ORDER_SAGA, order_context(), None)
+            .await
+            .unwrap();
+        let first_command_id = load_saga(&pool, saga_id).await.pending_command_id.unwrap();
+        let reply = CommandReply::success(first_command_id, saga_id, None);
+
+        manager.handle_reply(reply.clone()).await.unwrap();
+        let late_reply = CommandReply::failed(first_command_id, saga_id, "late".to_string());
+        manager.handle_reply(late_reply).await.unwrap();
+        // The same delivery again is only recorded once
+        manager.handle_reply(reply).await.unwrap();
+
+        let mut conn = pool.get().await.unwrap();
+        let view = crate::queries::find_saga(&mut conn, saga_id).await.unwrap().unwrap();
+        assert_eq!(view.summary.current_step, 1);
+        let command_types: Vec<_> = view.commands.iter().map(|c| c.command_type.clone()).collect();
+        assert!(matches!(command_types[..], [CommandType::CreateOrder, CommandType::ProcessPayment]));
+        assert_eq!(view.replies.len(), 2);
+        assert_eq!(view.replies[1].error.as_deref(), Some("late"));
+        assert!(view.compensation.is_none());
+    }
+
+    async fn expire_deadline(pool: &DbPool, saga_id: Uuid, attempts: i32) {
+        let mut conn = pool.get().await.unwrap();
+        diesel::update(saga_transactions::table.filter(saga_transactions::id.eq(saga_id)))
+            .set((
+                saga_transactions::deadline.eq(chrono::Utc::now() - chrono::Duration::seconds(1)),
+                saga_transactions::attempts.eq(attempts),
+            ))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_deadline_resends_command_while_retries_remain() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga_id = manager
+            .start_saga(sagas::CREATE_ORDER_SAGA, order_context(), None)
+            .await
+            .unwrap();
+        let mut conn = pool.get().await.unwrap();
+        diesel::update(outbox_events::table.filter(outbox_events::aggregate_id.eq(saga_id)))
+            .set(outbox_events::processed.eq(true))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        expire_deadline(&pool, saga_id, 1).await;
+
+        manager.retry_on_conflict(saga_id, || manager.try_handle_expired_deadline(saga_id)).await.unwrap();
+
+        let saga = load_saga(&pool, saga_id).await;
+        assert_eq!(saga.status, SagaStatus::Started);
+        assert_eq!(saga.attempts, 2);
+        assert!(saga.deadline.unwrap() > chrono::Utc::now());
+        let event = outbox_events::table
+            .filter(outbox_events::id.eq(saga.pending_command_id.unwrap()))
+            .first::<DbOutboxEvent>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(event.processed, Some(false));
+    }
+
+    #[tokio::test]
+    async fn expired_deadline_without_retries_compensates_in_flight_step() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        // Waiting for ProcessPayment, which may or may not have been taken
+        let saga = insert_saga_awaiting_reply(&pool, 1).await;
+        let max_retries = saga.steps[1].timeout.unwrap().max_retries as i32;
+        expire_deadline(&pool, saga.id, max_retries + 1).await;
+
+        manager.retry_on_conflict(saga.id, || manager.try_handle_expired_deadline(saga.id)).await.unwrap();
+
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::Compensating);
+        assert_eq!(stored.compensation.as_ref().unwrap().steps, vec![1, 0]);
+
+        let mut conn = pool.get().await.unwrap();
+        let event = outbox_events::table
+            .filter(outbox_events::id.eq(stored.pending_command_id.unwrap()))
+            .first::<DbOutboxEvent>(&mut conn)
+            .await
+            .unwrap();
+        let command: Command = serde_json::from_value(event.event_data).unwrap();
+        assert!(matches!(command.command_type, CommandType::CompensatePayment));
+    }
+
+    #[tokio::test]
+    async fn redelivered_command_replays_original_reply() {
+        let Some(pool) = test_pool().await else { return };
+        let handler = CommandHandler::new(pool.clone(), "order-replies".to_string());
+        // Order service doesn't handle payments, so the command fails
+        let command = Command::new(Uuid::new_v4(), 1, 0, CommandType::ProcessPayment, serde_json::json!({}));
+
+        handler.handle_command(command.clone()).await.unwrap();
+        handler.handle_command(command.clone()).await.unwrap();
+
+        let mut conn = pool.get().await.unwrap();
+        let processed = shared::schema::processed_commands::table
+            .filter(shared::schema::processed_commands::idempotency_key.eq(&command.idempotency_key))
+            .count()
+            .get_result::<i64>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(processed, 1);
+
+        let replies = outbox_events::table
+            .filter(outbox_events::aggregate_id.eq(command.saga_id))
+            .load::<DbOutboxEvent>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(replies.len(), 2);
+        for event in replies {
+            assert_eq!(event.topic.as_deref(), Some("order-replies"));
+            let reply: CommandReply = serde_json::from_value(event.event_data).unwrap();
+            assert_eq!(reply.status, CommandStatus::Failed);
+            assert_eq!(reply.error.as_deref(), Some("Unsupported command type"));
+        }
+    }
+
+    #[tokio::test]
+    async fn create_order_stores_exact_amount() {
+        let Some(pool) = test_pool().await else { return };
+        let handler = CommandHandler::new(pool.clone(), "order-replies".to_string());
+        let saga = insert_saga_awaiting_reply(&pool, 0).await;
+        let order_data: OrderData = saga.context_value("order_data").unwrap();
+        let command = Command::new(
+            saga.id,
+            0,
+            0,
+            CommandType::CreateOrder,
+            serde_json::to_value(&order_data).unwrap(),
+        );
+
+        handler.handle_command(command).await.unwrap();
+
+        let mut conn = pool.get().await.unwrap();
+        let order = orders::table
+            .find(order_data.order_id)
+            .first::<Order>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(Money::new(order.total_amount, order.currency.parse().unwrap()), order_data.total_amount);
+        assert_eq!(order.saga_id, Some(saga.id));
+    }
+
+    #[tokio::test]
+    async fn event_log_rebuilds_the_stored_saga() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga_id = manager
+            .start_saga(sagas::CREATE_ORDER_SAGA, order_context(), None)
+            .await
+            .unwrap();
+        let create_order = load_saga(&pool, saga_id).await.pending_command_id.unwrap();
+        manager
+            .handle_reply(CommandReply::success(create_order, saga_id, None))
+            .await
+            .unwrap();
+        let payment = load_saga(&pool, saga_id).await.pending_command_id.unwrap();
+        manager
+            .handle_reply(CommandReply::failed(payment, saga_id, "declined".to_string()))
+            .await
+            .unwrap();
+
+        let stored = load_saga(&pool, saga_id).await;
+        let mut conn = pool.get().await.unwrap();
+        let history = crate::queries::saga_history(&mut conn, saga_id).await.unwrap().unwrap();
+        let event_types: Vec<_> = history.events.iter().map(|e| e.event.event_type()).collect();
+        assert_eq!(
+            event_types,
+            [
+                "SagaStarted",
+                "CommandSent",
+                "ReplyReceived",
+                "CommandSent",
+                "ReplyReceived",
+                "CompensationStarted",
+                "CommandSent",
+            ]
+        );
+
+        let rebuilt = history.rebuilt;
+        assert_eq!(rebuilt.status, SagaStatus::Compensating);
+        assert_eq!(rebuilt.current_step, stored.current_step);
+        assert_eq!(rebuilt.pending_command_id, stored.pending_command_id);
+        assert_eq!(rebuilt.deadline, stored.deadline);
+        assert_eq!(rebuilt.attempts, stored.attempts);
+        assert_eq!(rebuilt.compensation, stored.compensation);
+        assert_eq!(rebuilt.version, stored.version);
+    }
+
+    #[tokio::test]
+    async fn manual_abort_fails_the_saga_once() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_reply(&pool, 1).await;
+
+        manager
+            .intervene(saga.id, InterventionKind::Abort, Some("customer called".to_string()))
+            .await
+            .unwrap();
+
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::Failed);
+        assert_eq!(stored.pending_command_id, None);
+
+        let mut conn = pool.get().await.unwrap();
+        let events = crate::queries::saga_events(&mut conn, saga.id).await.unwrap();
+        assert!(matches!(
+            &events[..],
+            [e] if matches!(&e.event, SagaEvent::ManualIntervention { intervention: Intervention::Abort, note: Some(_) })
+        ));
+
+        let again = manager.intervene(saga.id, InterventionKind::Abort, None).await.unwrap_err();
+        assert!(matches!(again.downcast_ref(), Some(InterventionError::Rejected(_))));
+    }
+
+    #[tokio::test]
+    async fn waiter_is_woken_when_the_saga_finishes() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = Arc::new(saga_manager(pool.clone()));
+        let saga = insert_saga_awaiting_last_reply(&pool).await;
+
+        let waiter = {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.wait_for_completion(saga.id, Duration::from_secs(5)).await })
+        };
+        // Let the waiter subscribe before the reply is applied
+        tokio::task::yield_now().await;
+        manager
+            .handle_reply(CommandReply::success(saga.pending_command_id.unwrap(), saga.id, None))
+            .await
+            .unwrap();
+
+        assert_eq!(waiter.await.unwrap().unwrap(), Some(SagaStatus::Completed));
+    }
+
+    #[tokio::test]
+    async fn waiting_gives_up_after_the_timeout() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_last_reply(&pool).await;
+
+        let status = manager.wait_for_completion(saga.id, Duration::from_millis(50)).await.unwrap();
+        assert_eq!(status, None);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn progress_streams_new_events_until_the_saga_finishes() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = Arc::new(saga_manager(pool.clone()));
+        let saga = insert_saga_awaiting_last_reply(&pool).await;
+
+        let streamed = tokio::spawn(manager.progress(saga.id, None).collect::<Vec<_>>());
+        tokio::task::yield_now().await;
+        manager
+            .handle_reply(CommandReply::success(saga.pending_command_id.unwrap(), saga.id, None))
+            .await
+            .unwrap();
+
+        let streamed = tokio::time::timeout(Duration::from_secs(5), streamed).await.unwrap().unwrap();
+        let types: Vec<_> = streamed.iter().map(|e| e.as_ref().unwrap().event.event_type()).collect();
+        assert_eq!(types, ["ReplyReceived", "SagaCompleted"]);
+
+        // Resuming after the first event yields only the rest
+        let resume_after = streamed[0].as_ref().unwrap().sequence;
+        let resumed: Vec<_> = manager.progress(saga.id, Some(resume_after)).collect().await;
+        assert_eq!(resumed.len(), 1);
+        assert_eq!(resumed[0].as_ref().unwrap().event.event_type(), "SagaCompleted");
+    }
+
+    #[tokio::test]
+    async fn finishing_a_saga_queues_webhook_deliveries() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_last_reply(&pool).await;
+
+        let mut conn = pool.get().await.unwrap();
+        let subscription = webhooks::create_subscription(
+            &mut conn,
+            NewWebhookSubscription {
+                id: Uuid::new_v4(),
+                url: "http://127.0.0.1:9/hook".to_string(),
+                event_types: vec![webhooks::ORDER_APPROVED.to_string()],
+                secret: "test-secret".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+
+        manager
+            .handle_reply(CommandReply::success(saga.pending_command_id.unwrap(), saga.id, None))
+            .await
+            .unwrap();
+
+        let log = webhooks::delivery_log(&mut conn, subscription.id, 100).await.unwrap().unwrap();
+        webhooks::deactivate_subscription(&mut conn, subscription.id).await.unwrap();
+
+        let deliveries: Vec<_> = log
+            .iter()
+            .filter(|d| d.payload["data"]["saga_id"] == saga.id.to_string())
+            .collect();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].event_type, webhooks::ORDER_APPROVED);
+    }
+
+    fn reservation_expired(saga_id: Uuid) -> InventoryEvent {
+        InventoryEvent::ReservationExpired {
+            reservation_id: Uuid::new_v4(),
+            saga_id: Some(saga_id),
+            order_id: Uuid::new_v4(),
+            product_id: Uuid::new_v4(),
+            quantity: 1,
+            expired_at: chrono::Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_reservation_compensates_a_running_saga() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_reply(&pool, step_of(CommandType::CommitInventory)).await;
+
+        manager.handle_inventory_event(reservation_expired(saga.id)).await.unwrap();
+
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::Compensating);
+
+        // The commit is no longer awaited; the inventory step is undone first
+        let mut conn = pool.get().await.unwrap();
+        let event = outbox_events::table
+            .filter(outbox_events::id.eq(stored.pending_command_id.unwrap()))
+            .first::<DbOutboxEvent>(&mut conn)
+            .await
+            .unwrap();
+        let command: Command = serde_json::from_value(event.event_data).unwrap();
+        assert!(matches!(command.command_type, CommandType::CompensateInventory));
+
+        let late_commit = CommandReply::success(saga.pending_command_id.unwrap(), saga.id, None);
+        manager.handle_reply(late_commit).await.unwrap();
+        assert_eq!(load_saga(&pool, saga.id).await.status, SagaStatus::Compensating);
+    }
+
+    #[tokio::test]
+    async fn failure_after_the_reservation_is_committed_does_not_cancel_the_order() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_reply(&pool, step_of(CommandType::ApproveOrder)).await;
+
+        let failure = CommandReply::failed(saga.pending_command_id.unwrap(), saga.id, "Order not found".to_string());
+        manager.handle_reply(failure).await.unwrap();
+
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::Failed);
+        assert_eq!(stored.pending_command_id, None);
+        assert!(stored.compensation.is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_reservation_after_approval_does_not_cancel_the_order() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        // Version 2 commits the reservation after approving the order
+        let v2 = sagas::registry().get(sagas::CREATE_ORDER_SAGA, 2).unwrap();
+        let saga = insert_saga_of(&pool, &v2, v2.steps().len() - 1).await;
+
+        manager.handle_inventory_event(reservation_expired(saga.id)).await.unwrap();
+
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::Failed);
+        assert!(stored.compensation.is_none());
+        let mut conn = pool.get().await.unwrap();
+        let types: Vec<_> = queries::saga_events(&mut conn, saga.id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.event.event_type())
+            .collect();
+        assert_eq!(types, ["ReservationExpired", "SagaFailed"]);
+    }
+
+    #[tokio::test]
+    async fn expired_reservation_of_a_completed_saga_changes_nothing() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_last_reply(&pool).await;
+        manager
+            .handle_reply(CommandReply::success(saga.pending_command_id.unwrap(), saga.id, None))
+            .await
+            .unwrap();
+
+        manager.handle_inventory_event(reservation_expired(saga.id)).await.unwrap();
+
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::Completed);
+        assert_eq!(stored.version, 1);
+    }
+
+    #[tokio::test]
+    async fn reservation_is_committed_before_the_order_is_approved() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_reply(&pool, step_of(CommandType::ReserveInventory)).await;
+
+        manager
+            .handle_reply(CommandReply::success(saga.pending_command_id.unwrap(), saga.id, None))
+            .await
+            .unwrap();
+
+        let stored = load_saga(&pool, saga.id).await;
+        let mut conn = pool.get().await.unwrap();
+        let event = outbox_events::table
+            .filter(outbox_events::id.eq(stored.pending_command_id.unwrap()))
+            .first::<DbOutboxEvent>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(event.topic.as_deref(), Some("inventory-service-commands"));
+        let command: Command = serde_json::from_value(event.event_data).unwrap();
+        assert!(matches!(command.command_type, CommandType::CommitInventory));
+
+        manager
+            .handle_reply(CommandReply::success(command.id, saga.id, None))
+            .await
+            .unwrap();
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.steps[stored.current_step].command_type, CommandType::ApproveOrder);
+    }
+
+    #[tokio::test]
+    async fn commit_names_the_locations_the_reservation_was_allocated() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let saga = insert_saga_awaiting_reply(&pool, step_of(CommandType::ReserveInventory)).await;
+        let allocation = Allocation {
+            product_id: Uuid::new_v4(),
+            warehouse_id: Uuid::new_v4(),
+            quantity: 1,
+        };
+        let result = serde_json::json!({"reserved": true, "allocations": [allocation]});
+
+        manager
+            .handle_reply(CommandReply::success(saga.pending_command_id.unwrap(), saga.id, Some(result)))
+            .await
+            .unwrap();
+
+        let stored = load_saga(&pool, saga.id).await;
+        let mut conn = pool.get().await.unwrap();
+        let event = outbox_events::table
+            .filter(outbox_events::id.eq(stored.pending_command_id.unwrap()))
+            .first::<DbOutboxEvent>(&mut conn)
+            .await
+            .unwrap();
+        let command: Command = serde_json::from_value(event.event_data).unwrap();
+        assert!(matches!(command.command_type, CommandType::CommitInventory));
+        let payload: InventoryData = serde_json::from_value(command.payload).unwrap();
+        assert_eq!(payload.allocations, [allocation]);
+    }
+
+    #[tokio::test]
+    async fn saga_started_from_version_1_commits_its_reservation_after_approval() {
+        let Some(pool) = test_pool().await else { return };
+        let manager = saga_manager(pool.clone());
+        let v1 = sagas::registry().get(sagas::CREATE_ORDER_SAGA, 1).unwrap();
+        let saga = insert_saga_of(&pool, &v1, v1.steps().len() - 1).await;
+
+        manager
+            .handle_reply(CommandReply::success(saga.pending_command_id.unwrap(), saga.id, None))
+            .await
+            .unwrap();
+
+        let stored = load_saga(&pool, saga.id).await;
+        assert_eq!(stored.status, SagaStatus::InProgress);
+        assert_eq!(stored.definition_version, 2);
+        assert_eq!(stored.steps[stored.current_step].command_type, CommandType::CommitInventory);
+
+        manager
+            .handle_reply(CommandReply::success(stored.pending_command_id.unwrap(), saga.id, None))
+            .await
+            .unwrap();
+        assert_eq!(load_saga(&pool, saga.id).await.status, SagaStatus::Completed);
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::Result;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::models::IdempotencyKey;
+use crate::schema::order_idempotency_keys;
+
+pub const MAX_KEY_LENGTH: usize = 255;
+
+/// An `Idempotency-Key` sent with an order request, stored together with
+/// the saga the request starts.
+#[derive(Debug, Clone)]
+pub struct OrderRequestKey {
+    pub key: String,
+    pub request_hash: String,
+    pub order_id: Uuid,
+}
+
+impl OrderRequestKey {
+    pub fn into_record(self, saga_id: Uuid) -> IdempotencyKey {
+        IdempotencyKey {
+            idempotency_key: self.key,
+            request_hash: self.request_hash,
+            order_id: self.order_id,
+            saga_id,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// Hex encoded SHA-256 of the request's JSON serialization. Requests that
+/// deserialize to the same values hash the same regardless of formatting;
+/// values with several spellings, such as amounts, are normalized first.
+pub fn request_hash(request: &impl Serialize) -> Result<String> {
+    let digest = Sha256::digest(serde_json::to_vec(request)?);
+    Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+pub async fn find_key(conn: &mut AsyncPgConnection, key: &str) -> Result<Option<IdempotencyKey>> {
+    let stored = order_idempotency_keys::table
+        .find(key)
+        .first::<IdempotencyKey>(conn)
+        .await
+        .optional()?;
+    Ok(stored)
+}
+
+pub async fn insert_key(conn: &mut AsyncPgConnection, key: &IdempotencyKey) -> Result<()> {
+    diesel::insert_into(order_idempotency_keys::table)
+        .values(key)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Whether `error` is a unique violation, which is what storing a key that
+/// a concurrent request has just stored fails with.
+pub fn is_unique_violation(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<DieselError>(),
+        Some(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _))
+    )
+}
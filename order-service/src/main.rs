@@ -1,8 +1,15 @@
 mod schema;
 mod models;
 mod handlers;
-mod outbox;
+mod idempotency;
 mod api;
+mod catalog;
+mod errors;
+mod sagas;
+mod queries;
+mod webhooks;
+#[cfg(test)]
+mod test_support;
 
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use diesel::PgConnection;
@@ -16,6 +23,7 @@ use diesel::Connection;
 use rdkafka::config::ClientConfig;
 use rdkafka::consumer::{Consumer, StreamConsumer};
 use rdkafka::producer::FutureProducer;
+use std::sync::Arc;
 use tracing::info;
 
 #[derive(Parser)]
@@ -33,8 +41,15 @@ struct Args {
     #[arg(long, default_value = "order-replies")]
     reply_topic: String,
     
+    #[arg(long, default_value = "inventory-events")]
+    inventory_events_topic: String,
+    
     #[arg(long, env = "PORT", default_value = "3001")]
     port: u16,
+    
+    /// Base URL of the product price catalog served by the inventory service
+    #[arg(long, env = "CATALOG_URL", default_value = "http://localhost:3003")]
+    catalog_url: String,
 }
 
 
@@ -73,12 +88,27 @@ async fn main() -> Result<()> {
         .set("enable.auto.commit", "true")
         .create()?;
 
+    let inventory_event_consumer: StreamConsumer = ClientConfig::new()
+        .set("group.id", "order-service-inventory-events")
+        .set("bootstrap.servers", &args.kafka_brokers)
+        .set("enable.partition.eof", "false")
+        .set("session.timeout.ms", "6000")
+        .set("enable.auto.commit", "true")
+        .create()?;
+
     consumer.subscribe(&[&args.command_topic])?;
     reply_consumer.subscribe(&[&args.reply_topic])?;
+    inventory_event_consumer.subscribe(&[&args.inventory_events_topic])?;
+
+    let registry = Arc::new(sagas::registry());
+
+    let outbox_processor = shared::outbox::OutboxProcessor::new(pool.clone(), producer.clone());
+    let command_handler = handlers::CommandHandler::new(pool.clone(), args.reply_topic.clone());
+    let saga_manager = Arc::new(handlers::SagaManager::new(pool.clone(), registry.clone()));
 
-    let outbox_processor = outbox::OutboxProcessor::new(pool.clone(), producer.clone());
-    let command_handler = handlers::CommandHandler::new(pool.clone(), producer.clone(), args.reply_topic.clone());
-    let saga_manager = handlers::SagaManager::new(pool.clone(), producer.clone());
+    // Pick up sagas that were interrupted by a previous shutdown or crash
+    let recovered = saga_manager.recover_sagas().await?;
+    info!("Re-issued in-flight commands for {} unfinished sagas", recovered);
 
     tokio::spawn(async move {
         outbox_processor.run().await;
@@ -88,14 +118,32 @@ async fn main() -> Result<()> {
         command_handler.run(consumer).await;
     });
 
+    let reply_saga_manager = saga_manager.clone();
+    tokio::spawn(async move {
+        reply_saga_manager.run_reply_handler(reply_consumer).await;
+    });
+
+    // Expired inventory reservations compensate the sagas still holding them
+    let inventory_event_saga_manager = saga_manager.clone();
+    tokio::spawn(async move {
+        inventory_event_saga_manager.run_inventory_event_handler(inventory_event_consumer).await;
+    });
+
+    let deadline_saga_manager = saga_manager.clone();
+    tokio::spawn(async move {
+        deadline_saga_manager.run_deadline_scheduler().await;
+    });
+
+    let webhook_dispatcher = webhooks::WebhookDispatcher::new(pool.clone(), webhooks::RetryPolicy::default())?;
     tokio::spawn(async move {
-        saga_manager.run_reply_handler(reply_consumer).await;
+        webhook_dispatcher.run().await;
     });
 
     // Start the web server
     let app_state = api::AppState {
         pool: pool.clone(),
-        producer: producer.clone(),
+        saga_manager: saga_manager.clone(),
+        catalog: Arc::new(catalog::CatalogClient::new(args.catalog_url.clone())?),
     };
     
     let app = api::create_router(app_state);
@@ -9,12 +9,12 @@ use shared::*;
 pub struct Order {
     pub id: Uuid,
     pub customer_id: Uuid,
-    pub product_id: Uuid,
-    pub quantity: i32,
     pub total_amount: bigdecimal::BigDecimal,
     pub status: String,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
+    pub currency: String,
+    pub saga_id: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -22,34 +22,49 @@ pub struct Order {
 pub struct NewOrder {
     pub id: Uuid,
     pub customer_id: Uuid,
-    pub product_id: Uuid,
-    pub quantity: i32,
     pub total_amount: bigdecimal::BigDecimal,
+    pub currency: String,
     pub status: String,
+    pub saga_id: Option<Uuid>,
 }
 
-#[derive(Debug, Clone, Queryable, Insertable, Serialize, Deserialize)]
-#[diesel(table_name = crate::schema::outbox_events)]
-pub struct DbOutboxEvent {
-    pub id: Uuid,
-    pub aggregate_id: Uuid,
-    pub event_type: String,
-    pub event_data: serde_json::Value,
-    pub processed: Option<bool>,
-    pub created_at: Option<DateTime<Utc>>,
+/// Line `line_number` of an order, numbered from 1.
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = crate::schema::order_lines)]
+pub struct OrderLineRecord {
+    pub order_id: Uuid,
+    pub line_number: i32,
+    pub product_id: Uuid,
+    pub quantity: i32,
+    pub amount: bigdecimal::BigDecimal,
+    pub currency: String,
 }
 
-#[derive(Debug, Clone, Insertable)]
-#[diesel(table_name = crate::schema::outbox_events)]
-pub struct NewOutboxEvent {
-    pub id: Uuid,
-    pub aggregate_id: Uuid,
-    pub event_type: String,
-    pub event_data: serde_json::Value,
+impl OrderLineRecord {
+    pub fn new(order_id: Uuid, line_number: i32, line: &OrderLine) -> Self {
+        Self {
+            order_id,
+            line_number,
+            product_id: line.product_id,
+            quantity: line.quantity,
+            amount: line.amount.amount.clone(),
+            currency: line.amount.currency.code().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = crate::schema::order_idempotency_keys)]
+pub struct IdempotencyKey {
+    pub idempotency_key: String,
+    pub request_hash: String,
+    pub order_id: Uuid,
+    pub saga_id: Uuid,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Queryable, Insertable, AsChangeset, Serialize, Deserialize)]
-#[diesel(table_name = crate::schema::saga_transactions)]
+#[diesel(table_name = crate::schema::saga_transactions, treat_none_as_null = true)]
 pub struct DbSagaTransaction {
     pub id: Uuid,
     pub steps: serde_json::Value,
@@ -58,15 +73,125 @@ pub struct DbSagaTransaction {
     pub context: serde_json::Value,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
+    pub definition_name: String,
+    pub definition_version: i32,
+    pub pending_command_id: Option<Uuid>,
+    pub version: i32,
+    pub deadline: Option<DateTime<Utc>>,
+    pub attempts: i32,
+    pub compensation: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Queryable, Insertable)]
-#[diesel(table_name = crate::schema::processed_commands)]
-pub struct ProcessedCommand {
-    pub idempotency_key: String,
+#[diesel(table_name = crate::schema::saga_replies)]
+pub struct SagaReply {
+    pub id: Uuid,
+    pub saga_id: Uuid,
     pub command_id: Uuid,
+    pub status: String,
     pub result: Option<serde_json::Value>,
-    pub processed_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+    pub replied_at: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+}
+
+impl From<&CommandReply> for SagaReply {
+    fn from(reply: &CommandReply) -> Self {
+        Self {
+            id: reply.id,
+            saga_id: reply.saga_id,
+            command_id: reply.command_id,
+            status: reply.status.to_string(),
+            result: reply.result.clone(),
+            error: reply.error.clone(),
+            replied_at: reply.created_at,
+            received_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Queryable, Serialize)]
+#[diesel(table_name = crate::schema::saga_events)]
+pub struct SagaEventRecord {
+    pub id: i64,
+    pub saga_id: Uuid,
+    pub saga_version: i32,
+    pub event_type: String,
+    pub data: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = crate::schema::saga_events)]
+pub struct NewSagaEvent {
+    pub saga_id: Uuid,
+    pub saga_version: i32,
+    pub event_type: String,
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Queryable)]
+#[diesel(table_name = crate::schema::webhook_subscriptions)]
+pub struct WebhookSubscription {
+    pub id: Uuid,
+    pub url: String,
+    pub event_types: Vec<String>,
+    pub secret: String,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = crate::schema::webhook_subscriptions)]
+pub struct NewWebhookSubscription {
+    pub id: Uuid,
+    pub url: String,
+    pub event_types: Vec<String>,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Queryable)]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub subscription_id: Uuid,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+pub struct NewWebhookDelivery {
+    pub id: Uuid,
+    pub subscription_id: Uuid,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Queryable, Serialize)]
+#[diesel(table_name = crate::schema::webhook_delivery_attempts)]
+pub struct WebhookDeliveryAttempt {
+    pub id: i64,
+    #[serde(skip)]
+    pub delivery_id: Uuid,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub attempted_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = crate::schema::webhook_delivery_attempts)]
+pub struct NewWebhookDeliveryAttempt {
+    pub delivery_id: Uuid,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
 }
 
 impl From<SagaTransaction> for DbSagaTransaction {
@@ -79,6 +204,13 @@ impl From<SagaTransaction> for DbSagaTransaction {
             context: serde_json::to_value(saga.context).unwrap(),
             created_at: Some(saga.created_at),
             updated_at: Some(saga.updated_at),
+            definition_name: saga.definition_name,
+            definition_version: saga.definition_version as i32,
+            pending_command_id: saga.pending_command_id,
+            version: saga.version as i32,
+            deadline: saga.deadline,
+            attempts: saga.attempts as i32,
+            compensation: saga.compensation.map(|c| serde_json::to_value(c).unwrap()),
         }
     }
 }
@@ -98,28 +230,24 @@ impl TryFrom<DbSagaTransaction> for SagaTransaction {
             _ => SagaStatus::Failed,
         };
         let context = serde_json::from_value(db_saga.context)?;
+        let compensation = db_saga.compensation.map(serde_json::from_value).transpose()?;
 
         Ok(Self {
             id: db_saga.id,
+            definition_name: db_saga.definition_name,
+            definition_version: db_saga.definition_version as u32,
             steps,
             current_step: db_saga.current_step as usize,
             status,
+            pending_command_id: db_saga.pending_command_id,
+            deadline: db_saga.deadline,
+            attempts: db_saga.attempts as u32,
             context,
-            created_at: db_saga.created_at.unwrap_or_else(|| Utc::now()),
-            updated_at: db_saga.updated_at.unwrap_or_else(|| Utc::now()),
+            compensation,
+            version: db_saga.version as u32,
+            created_at: db_saga.created_at.unwrap_or_else(Utc::now),
+            updated_at: db_saga.updated_at.unwrap_or_else(Utc::now),
+            uncommitted_events: Vec::new(),
         })
     }
-}
-
-impl From<OutboxEvent> for DbOutboxEvent {
-    fn from(event: OutboxEvent) -> Self {
-        Self {
-            id: event.id,
-            aggregate_id: event.aggregate_id,
-            event_type: event.event_type,
-            event_data: event.event_data,
-            processed: Some(event.processed),
-            created_at: Some(event.created_at),
-        }
-    }
 }
\ No newline at end of file
@@ -0,0 +1,649 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use shared::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use shared::outbox::DbOutboxEvent;
+use shared::schema::outbox_events;
+use crate::models::{DbSagaTransaction, Order, OrderLineRecord, SagaEventRecord, SagaReply};
+use crate::schema::{order_lines, orders, saga_events, saga_replies, saga_transactions};
+
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+/// An order with its lines, together with the saga that created it.
+#[derive(Debug, Serialize)]
+pub struct OrderView {
+    pub order_id: Uuid,
+    pub customer_id: Uuid,
+    pub lines: Vec<OrderLine>,
+    pub total_amount: Money,
+    pub status: String,
+    pub saga_id: Option<Uuid>,
+    pub saga_status: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<(Order, Option<String>, Vec<OrderLine>)> for OrderView {
+    type Error = anyhow::Error;
+
+    fn try_from((order, saga_status, lines): (Order, Option<String>, Vec<OrderLine>)) -> Result<Self, Self::Error> {
+        Ok(Self {
+            order_id: order.id,
+            customer_id: order.customer_id,
+            lines,
+            total_amount: Money::new(order.total_amount, order.currency.parse()?),
+            status: order.status,
+            saga_id: order.saga_id,
+            saga_status,
+            created_at: order.created_at,
+            updated_at: order.updated_at,
+        })
+    }
+}
+
+/// Filters for listing orders. Orders are returned newest first; `cursor` is
+/// the `next_cursor` of the previous page.
+#[derive(Debug, Default, Deserialize)]
+pub struct OrderFilter {
+    pub customer_id: Option<Uuid>,
+    pub status: Option<String>,
+    /// Inclusive lower bound on `created_at`.
+    pub created_after: Option<DateTime<Utc>>,
+    /// Exclusive upper bound on `created_at`.
+    pub created_before: Option<DateTime<Utc>>,
+    pub cursor: Option<PageCursor>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrderPage {
+    pub orders: Vec<OrderView>,
+    pub next_cursor: Option<PageCursor>,
+}
+
+/// Position of the last row of a page, as `<created_at micros>_<id>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PageCursor {
+    created_at: DateTime<Utc>,
+    id: Uuid,
+}
+
+impl fmt::Display for PageCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.created_at.timestamp_micros(), self.id)
+    }
+}
+
+impl FromStr for PageCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (micros, id) = s
+            .split_once('_')
+            .ok_or_else(|| anyhow::anyhow!("Invalid cursor: {}", s))?;
+        let created_at = DateTime::from_timestamp_micros(micros.parse()?)
+            .ok_or_else(|| anyhow::anyhow!("Invalid cursor: {}", s))?;
+        Ok(Self {
+            created_at,
+            id: id.parse()?,
+        })
+    }
+}
+
+impl TryFrom<String> for PageCursor {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PageCursor> for String {
+    fn from(cursor: PageCursor) -> Self {
+        cursor.to_string()
+    }
+}
+
+pub async fn find_order(conn: &mut AsyncPgConnection, order_id: Uuid) -> Result<Option<OrderView>> {
+    let row = orders::table
+        .left_join(saga_transactions::table)
+        .filter(orders::id.eq(order_id))
+        .select((orders::all_columns, saga_transactions::status.nullable()))
+        .first::<(Order, Option<String>)>(conn)
+        .await
+        .optional()?;
+
+    let Some((order, saga_status)) = row else {
+        return Ok(None);
+    };
+    let lines = load_order_lines(conn, &[order.id]).await?.remove(&order.id).unwrap_or_default();
+    OrderView::try_from((order, saga_status, lines)).map(Some)
+}
+
+/// The saga started for `order_id`. It is looked up by the order in its
+/// context, as the order row is only created by the saga's first step.
+pub async fn find_order_saga_id(conn: &mut AsyncPgConnection, order_id: Uuid) -> Result<Option<Uuid>> {
+    Ok(saga_transactions::table
+        .filter(
+            diesel::dsl::sql::<diesel::sql_types::Bool>("context -> 'order_data' ->> 'order_id' = ")
+                .bind::<diesel::sql_types::Text, _>(order_id.to_string()),
+        )
+        .order(saga_transactions::created_at.desc())
+        .select(saga_transactions::id)
+        .first::<Uuid>(conn)
+        .await
+        .optional()?)
+}
+
+/// The lines of each of `order_ids`, in line number order.
+async fn load_order_lines(conn: &mut AsyncPgConnection, order_ids: &[Uuid]) -> Result<HashMap<Uuid, Vec<OrderLine>>> {
+    let records = order_lines::table
+        .filter(order_lines::order_id.eq_any(order_ids))
+        .order((order_lines::order_id, order_lines::line_number))
+        .load::<OrderLineRecord>(conn)
+        .await?;
+
+    let mut lines: HashMap<Uuid, Vec<OrderLine>> = HashMap::new();
+    for record in records {
+        lines.entry(record.order_id).or_default().push(OrderLine {
+            product_id: record.product_id,
+            quantity: record.quantity,
+            amount: Money::new(record.amount, record.currency.parse()?),
+        });
+    }
+    Ok(lines)
+}
+
+pub async fn list_orders(conn: &mut AsyncPgConnection, filter: &OrderFilter) -> Result<OrderPage> {
+    let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let mut query = orders::table
+        .left_join(saga_transactions::table)
+        .select((orders::all_columns, saga_transactions::status.nullable()))
+        .into_boxed();
+
+    if let Some(customer_id) = filter.customer_id {
+        query = query.filter(orders::customer_id.eq(customer_id));
+    }
+    if let Some(status) = &filter.status {
+        query = query.filter(orders::status.eq(status.clone()));
+    }
+    if let Some(created_after) = filter.created_after {
+        query = query.filter(orders::created_at.ge(created_after));
+    }
+    if let Some(created_before) = filter.created_before {
+        query = query.filter(orders::created_at.lt(created_before));
+    }
+    if let Some(cursor) = filter.cursor {
+        query = query.filter(
+            orders::created_at
+                .lt(cursor.created_at)
+                .or(orders::created_at.eq(cursor.created_at).and(orders::id.lt(cursor.id))),
+        );
+    }
+
+    // One extra row tells whether there is a next page
+    let mut rows = query
+        .order((orders::created_at.desc(), orders::id.desc()))
+        .limit(limit + 1)
+        .load::<(Order, Option<String>)>(conn)
+        .await?;
+
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+
+    let next_cursor = if has_more {
+        rows.last().and_then(|(order, _)| {
+            Some(PageCursor {
+                created_at: order.created_at?,
+                id: order.id,
+            })
+        })
+    } else {
+        None
+    };
+
+    let order_ids: Vec<_> = rows.iter().map(|(order, _)| order.id).collect();
+    let mut lines = load_order_lines(conn, &order_ids).await?;
+    let orders = rows
+        .into_iter()
+        .map(|(order, saga_status)| {
+            let order_lines = lines.remove(&order.id).unwrap_or_default();
+            OrderView::try_from((order, saga_status, order_lines))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(OrderPage { orders, next_cursor })
+}
+
+/// The definition, position and status of a saga.
+#[derive(Debug, Serialize)]
+pub struct SagaSummary {
+    pub saga_id: Uuid,
+    pub definition_name: String,
+    pub definition_version: u32,
+    pub steps: Vec<SagaStep>,
+    pub current_step: usize,
+    pub status: SagaStatus,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<&SagaTransaction> for SagaSummary {
+    fn from(saga: &SagaTransaction) -> Self {
+        Self {
+            saga_id: saga.id,
+            definition_name: saga.definition_name.clone(),
+            definition_version: saga.definition_version,
+            steps: saga.steps.clone(),
+            current_step: saga.current_step,
+            status: saga.status.clone(),
+            created_at: saga.created_at,
+            updated_at: saga.updated_at,
+        }
+    }
+}
+
+/// A saga with everything it sent and received so far.
+#[derive(Debug, Serialize)]
+pub struct SagaView {
+    #[serde(flatten)]
+    pub summary: SagaSummary,
+    pub pending_command_id: Option<Uuid>,
+    pub deadline: Option<DateTime<Utc>>,
+    pub attempts: u32,
+    pub compensation: Option<CompensationState>,
+    pub commands: Vec<SentCommand>,
+    pub replies: Vec<ReceivedReply>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SentCommand {
+    pub command_id: Uuid,
+    pub command_type: CommandType,
+    pub idempotency_key: String,
+    pub topic: Option<String>,
+    pub payload: serde_json::Value,
+    /// Whether the outbox has published the latest send of the command.
+    pub published: bool,
+    pub queued_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<DbOutboxEvent> for SentCommand {
+    type Error = anyhow::Error;
+
+    fn try_from(event: DbOutboxEvent) -> Result<Self, Self::Error> {
+        let command: Command = serde_json::from_value(event.event_data)?;
+        Ok(Self {
+            command_id: command.id,
+            command_type: command.command_type,
+            idempotency_key: command.idempotency_key,
+            topic: event.topic,
+            payload: command.payload,
+            published: event.processed.unwrap_or(false),
+            queued_at: event.created_at,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReceivedReply {
+    pub reply_id: Uuid,
+    pub command_id: Uuid,
+    pub status: CommandStatus,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub replied_at: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+}
+
+impl TryFrom<SagaReply> for ReceivedReply {
+    type Error = anyhow::Error;
+
+    fn try_from(reply: SagaReply) -> Result<Self, Self::Error> {
+        Ok(Self {
+            reply_id: reply.id,
+            command_id: reply.command_id,
+            status: reply.status.parse()?,
+            result: reply.result,
+            error: reply.error,
+            replied_at: reply.replied_at,
+            received_at: reply.received_at,
+        })
+    }
+}
+
+/// Filters for listing sagas, newest first.
+#[derive(Debug, Default, Deserialize)]
+pub struct SagaFilter {
+    pub status: Option<SagaStatus>,
+    pub cursor: Option<PageCursor>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SagaPage {
+    pub sagas: Vec<SagaSummary>,
+    pub next_cursor: Option<PageCursor>,
+}
+
+pub async fn find_saga(conn: &mut AsyncPgConnection, saga_id: Uuid) -> Result<Option<SagaView>> {
+    let Some(db_saga) = saga_transactions::table
+        .find(saga_id)
+        .first::<DbSagaTransaction>(conn)
+        .await
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    let saga = SagaTransaction::try_from(db_saga)?;
+
+    // Replies the order service sends for its own steps share the saga id
+    let commands = outbox_events::table
+        .filter(outbox_events::aggregate_id.eq(saga_id))
+        .filter(outbox_events::event_type.ne("CommandReply"))
+        .order(outbox_events::created_at.asc())
+        .load::<DbOutboxEvent>(conn)
+        .await?
+        .into_iter()
+        .map(SentCommand::try_from)
+        .collect::<Result<Vec<_>>>()?;
+
+    let replies = saga_replies::table
+        .filter(saga_replies::saga_id.eq(saga_id))
+        .order(saga_replies::received_at.asc())
+        .load::<SagaReply>(conn)
+        .await?
+        .into_iter()
+        .map(ReceivedReply::try_from)
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Some(SagaView {
+        summary: SagaSummary::from(&saga),
+        pending_command_id: saga.pending_command_id,
+        deadline: saga.deadline,
+        attempts: saga.attempts,
+        compensation: saga.compensation.clone(),
+        commands,
+        replies,
+    }))
+}
+
+/// An entry of a saga's event log. `sequence` orders events across all sagas.
+#[derive(Debug, Serialize)]
+pub struct LoggedSagaEvent {
+    pub sequence: i64,
+    pub saga_version: i32,
+    #[serde(flatten)]
+    pub event: SagaEvent,
+    pub created_at: DateTime<Utc>,
+}
+
+impl TryFrom<SagaEventRecord> for LoggedSagaEvent {
+    type Error = anyhow::Error;
+
+    fn try_from(record: SagaEventRecord) -> Result<Self, Self::Error> {
+        Ok(Self {
+            sequence: record.id,
+            saga_version: record.saga_version,
+            event: serde_json::from_value(record.data)?,
+            created_at: record.created_at,
+        })
+    }
+}
+
+/// The event log of a saga and the state obtained by replaying it, which
+/// should match the stored saga. Sagas started before the log existed have
+/// no `SagaStarted` to replay from, so their stored state is given instead.
+#[derive(Debug, Serialize)]
+pub struct SagaHistory {
+    pub events: Vec<LoggedSagaEvent>,
+    pub rebuilt: SagaTransaction,
+}
+
+pub async fn saga_events(conn: &mut AsyncPgConnection, saga_id: Uuid) -> Result<Vec<LoggedSagaEvent>> {
+    saga_events_after(conn, saga_id, None).await
+}
+
+/// Events of the saga with a sequence greater than `after`, oldest first.
+pub async fn saga_events_after(
+    conn: &mut AsyncPgConnection,
+    saga_id: Uuid,
+    after: Option<i64>,
+) -> Result<Vec<LoggedSagaEvent>> {
+    saga_events::table
+        .filter(saga_events::saga_id.eq(saga_id))
+        .filter(saga_events::id.gt(after.unwrap_or(0)))
+        .order(saga_events::id.asc())
+        .load::<SagaEventRecord>(conn)
+        .await?
+        .into_iter()
+        .map(LoggedSagaEvent::try_from)
+        .collect()
+}
+
+/// Returns None if the saga does not exist.
+pub async fn saga_history(conn: &mut AsyncPgConnection, saga_id: Uuid) -> Result<Option<SagaHistory>> {
+    let events = saga_events(conn, saga_id).await?;
+    if !matches!(events.as_slice(), [LoggedSagaEvent { event: SagaEvent::SagaStarted { .. }, .. }, ..]) {
+        let Some(db_saga) = saga_transactions::table
+            .find(saga_id)
+            .first::<DbSagaTransaction>(conn)
+            .await
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let stored = SagaTransaction::try_from(db_saga)?;
+        return Ok(Some(SagaHistory { events, rebuilt: stored }));
+    }
+
+    let mut rebuilt = SagaTransaction::rebuild(saga_id, events.iter().map(|e| &e.event))?;
+    let last = &events[events.len() - 1];
+    rebuilt.created_at = events[0].created_at;
+    rebuilt.updated_at = last.created_at;
+    rebuilt.version = last.saga_version as u32;
+
+    Ok(Some(SagaHistory { events, rebuilt }))
+}
+
+pub async fn list_sagas(conn: &mut AsyncPgConnection, filter: &SagaFilter) -> Result<SagaPage> {
+    let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let mut query = saga_transactions::table.into_boxed();
+
+    if let Some(status) = &filter.status {
+        query = query.filter(saga_transactions::status.eq(format!("{:?}", status)));
+    }
+    if let Some(cursor) = filter.cursor {
+        query = query.filter(
+            saga_transactions::created_at
+                .lt(cursor.created_at)
+                .or(saga_transactions::created_at
+                    .eq(cursor.created_at)
+                    .and(saga_transactions::id.lt(cursor.id))),
+        );
+    }
+
+    let mut rows = query
+        .order((saga_transactions::created_at.desc(), saga_transactions::id.desc()))
+        .limit(limit + 1)
+        .load::<DbSagaTransaction>(conn)
+        .await?;
+
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+
+    let next_cursor = if has_more {
+        rows.last().and_then(|saga| {
+            Some(PageCursor {
+                created_at: saga.created_at?,
+                id: saga.id,
+            })
+        })
+    } else {
+        None
+    };
+
+    // A saga that cannot be read is reported rather than failing the page
+    let sagas = rows
+        .into_iter()
+        .filter_map(|db_saga| {
+            let saga_id = db_saga.id;
+            match SagaTransaction::try_from(db_saga) {
+                Ok(saga) => Some(SagaSummary::from(&saga)),
+                Err(e) => {
+                    tracing::error!("Leaving unreadable saga {} out of the list: {}", saga_id, e);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    Ok(SagaPage { sagas, next_cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{NewOrder, OrderLineRecord};
+    use crate::sagas;
+    use crate::test_support::{order_data, test_pool};
+
+    /// Inserts an order and the completed saga that created it.
+    async fn insert_order(conn: &mut AsyncPgConnection, customer_id: Uuid) -> Uuid {
+        let order_data = order_data(customer_id);
+        let mut saga = sagas::create_order_saga().start(sagas::order_context(&order_data));
+        saga.status = SagaStatus::Completed;
+        diesel::insert_into(saga_transactions::table)
+            .values(&DbSagaTransaction::from(saga.clone()))
+            .execute(conn)
+            .await
+            .unwrap();
+
+        diesel::insert_into(orders::table)
+            .values(&NewOrder {
+                id: order_data.order_id,
+                customer_id,
+                total_amount: order_data.total_amount.amount,
+                currency: order_data.total_amount.currency.code().to_string(),
+                status: "approved".to_string(),
+                saga_id: Some(saga.id),
+            })
+            .execute(conn)
+            .await
+            .unwrap();
+        diesel::insert_into(order_lines::table)
+            .values(&OrderLineRecord::new(order_data.order_id, 1, &order_data.lines[0]))
+            .execute(conn)
+            .await
+            .unwrap();
+        order_data.order_id
+    }
+
+    #[tokio::test]
+    async fn order_includes_saga_status() {
+        let Some(pool) = test_pool().await else { return };
+        let mut conn = pool.get().await.unwrap();
+        let order_id = insert_order(&mut conn, Uuid::new_v4()).await;
+
+        let order = find_order(&mut conn, order_id).await.unwrap().unwrap();
+        assert!(order.saga_id.is_some());
+        assert_eq!(order.saga_status.as_deref(), Some("Completed"));
+        assert!(find_order(&mut conn, Uuid::new_v4()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn saga_older_than_the_event_log_has_its_stored_state_as_history() {
+        let Some(pool) = test_pool().await else { return };
+        let mut conn = pool.get().await.unwrap();
+        // Inserted without recording any events, like sagas from before the log
+        let order_id = insert_order(&mut conn, Uuid::new_v4()).await;
+        let saga_id = find_order(&mut conn, order_id).await.unwrap().unwrap().saga_id.unwrap();
+
+        let history = saga_history(&mut conn, saga_id).await.unwrap().unwrap();
+        assert!(history.events.is_empty());
+        assert_eq!(history.rebuilt.id, saga_id);
+        assert_eq!(history.rebuilt.status, SagaStatus::Completed);
+
+        assert!(saga_history(&mut conn, Uuid::new_v4()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn unreadable_saga_is_left_out_of_the_list() {
+        let Some(pool) = test_pool().await else { return };
+        let mut conn = pool.get().await.unwrap();
+        let readable = insert_order(&mut conn, Uuid::new_v4()).await;
+        let readable = find_order(&mut conn, readable).await.unwrap().unwrap().saga_id.unwrap();
+
+        // Compensation listing steps rather than their indices, as the
+        // baseline stored them
+        let mut saga = sagas::create_order_saga().start(HashMap::new());
+        saga.status = SagaStatus::Completed;
+        let mut unreadable = DbSagaTransaction::from(saga);
+        unreadable.compensation = Some(serde_json::json!({
+            "steps": [{"command_type": "CreateOrder", "compensation_type": "CancelOrder", "service_name": "order-service"}],
+            "completed": 0,
+            "round": 0,
+        }));
+        diesel::insert_into(saga_transactions::table)
+            .values(&unreadable)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        let filter = SagaFilter {
+            status: Some(SagaStatus::Completed),
+            limit: Some(MAX_PAGE_SIZE),
+            ..Default::default()
+        };
+        let listed: Vec<_> = list_sagas(&mut conn, &filter)
+            .await
+            .unwrap()
+            .sagas
+            .into_iter()
+            .map(|saga| saga.saga_id)
+            .collect();
+        assert!(listed.contains(&readable));
+        assert!(!listed.contains(&unreadable.id));
+    }
+
+    #[tokio::test]
+    async fn cursor_pages_through_customer_orders() {
+        let Some(pool) = test_pool().await else { return };
+        let mut conn = pool.get().await.unwrap();
+        let customer_id = Uuid::new_v4();
+        let mut inserted = Vec::new();
+        for _ in 0..5 {
+            inserted.push(insert_order(&mut conn, customer_id).await);
+        }
+        insert_order(&mut conn, Uuid::new_v4()).await;
+
+        let mut filter = OrderFilter {
+            customer_id: Some(customer_id),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let mut listed = Vec::new();
+        loop {
+            let page = list_orders(&mut conn, &filter).await.unwrap();
+            listed.extend(page.orders.iter().map(|o| o.order_id));
+            match page.next_cursor {
+                Some(cursor) => filter.cursor = Some(cursor),
+                None => break,
+            }
+        }
+
+        inserted.sort();
+        listed.sort();
+        assert_eq!(listed, inserted);
+    }
+}
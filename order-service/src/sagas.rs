@@ -0,0 +1,118 @@
+use anyhow::Result;
+use serde::Deserialize;
+use shared::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub const CREATE_ORDER_SAGA: &str = "create_order";
+
+/// All saga definitions the order service orchestrates. Earlier versions
+/// stay registered so that sagas started from them can finish.
+pub fn registry() -> SagaRegistry {
+    let mut registry = SagaRegistry::new();
+    registry.register(create_order_saga_v1());
+    registry.register(create_order_saga_v2());
+    registry.register(create_order_saga());
+    registry
+}
+
+/// How long a participant has to reply before the command is resent.
+const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+const STEP_MAX_RETRIES: u32 = 3;
+
+/// CreateOrder → ProcessPayment → ReserveInventory → CommitInventory → ApproveOrder
+///
+/// The reservation is committed before the order is approved, so that an
+/// order is only approved once its stock can no longer expire.
+pub fn create_order_saga() -> SagaDefinition {
+    reservation_steps(3)
+        .step("inventory-service", CommandType::CommitInventory, inventory_payload)
+        .with_timeout(STEP_TIMEOUT, STEP_MAX_RETRIES)
+        .step("order-service", CommandType::ApproveOrder, order_payload)
+        .with_timeout(STEP_TIMEOUT, STEP_MAX_RETRIES)
+        .build()
+}
+
+/// The second version, which committed the reservation after approving the
+/// order.
+fn create_order_saga_v2() -> SagaDefinition {
+    reservation_steps(2)
+        .step("order-service", CommandType::ApproveOrder, order_payload)
+        .with_timeout(STEP_TIMEOUT, STEP_MAX_RETRIES)
+        .step("inventory-service", CommandType::CommitInventory, inventory_payload)
+        .with_timeout(STEP_TIMEOUT, STEP_MAX_RETRIES)
+        .build()
+}
+
+/// The first version, which left the approved order's stock reserved.
+fn create_order_saga_v1() -> SagaDefinition {
+    reservation_steps(1)
+        .step("order-service", CommandType::ApproveOrder, order_payload)
+        .with_timeout(STEP_TIMEOUT, STEP_MAX_RETRIES)
+        .build()
+}
+
+/// CreateOrder → ProcessPayment → ReserveInventory
+fn reservation_steps(version: u32) -> SagaDefinitionBuilder {
+    SagaDefinition::builder(CREATE_ORDER_SAGA, version)
+        .step("order-service", CommandType::CreateOrder, order_payload)
+        .compensate_with(CommandType::CancelOrder, order_payload)
+        .with_timeout(STEP_TIMEOUT, STEP_MAX_RETRIES)
+        .step("payment-service", CommandType::ProcessPayment, payment_payload)
+        .compensate_with(CommandType::CompensatePayment, payment_payload)
+        .with_timeout(STEP_TIMEOUT, STEP_MAX_RETRIES)
+        .step("inventory-service", CommandType::ReserveInventory, inventory_payload)
+        .compensate_with(CommandType::CompensateInventory, inventory_payload)
+        .with_timeout(STEP_TIMEOUT, STEP_MAX_RETRIES)
+}
+
+pub fn order_context(order_data: &OrderData) -> HashMap<String, serde_json::Value> {
+    let mut context = HashMap::new();
+    context.insert("order_data".to_string(), serde_json::to_value(order_data).unwrap());
+    context
+}
+
+fn order_payload(saga: &SagaTransaction) -> Result<serde_json::Value> {
+    let order_data: OrderData = saga.context_value("order_data")?;
+    Ok(serde_json::to_value(order_data)?)
+}
+
+fn payment_payload(saga: &SagaTransaction) -> Result<serde_json::Value> {
+    let order_data: OrderData = saga.context_value("order_data")?;
+    let payment_data = PaymentData {
+        order_id: order_data.order_id,
+        currency: Some(order_data.total_amount.currency),
+        amount: order_data.total_amount,
+        payment_method: "credit_card".to_string(),
+    };
+    Ok(serde_json::to_value(payment_data)?)
+}
+
+/// What the inventory service replied to the reservation with.
+#[derive(Deserialize)]
+struct ReservedInventory {
+    #[serde(default)]
+    allocations: Vec<Allocation>,
+}
+
+fn inventory_payload(saga: &SagaTransaction) -> Result<serde_json::Value> {
+    let order_data: OrderData = saga.context_value("order_data")?;
+    // Committing and compensating release the locations the stock was
+    // reserved at, once the reservation's reply told which
+    let reserved: Option<ReservedInventory> = saga.step_result(&CommandType::ReserveInventory)?;
+    let inventory_data = InventoryData {
+        order_id: order_data.order_id,
+        lines: order_data
+            .lines
+            .iter()
+            .map(|line| InventoryLine {
+                product_id: line.product_id,
+                quantity: line.quantity,
+            })
+            .collect(),
+        ship_to: order_data.ship_to,
+        allocation_strategy: order_data.allocation_strategy,
+        allocations: reserved.map(|reserved| reserved.allocations).unwrap_or_default(),
+    };
+    Ok(serde_json::to_value(inventory_data)?)
+}
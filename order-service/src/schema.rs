@@ -1,51 +1,131 @@
+diesel::table! {
+    order_idempotency_keys (idempotency_key) {
+        idempotency_key -> Varchar,
+        request_hash -> Varchar,
+        order_id -> Uuid,
+        saga_id -> Uuid,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    order_lines (order_id, line_number) {
+        order_id -> Uuid,
+        line_number -> Int4,
+        product_id -> Uuid,
+        quantity -> Int4,
+        amount -> Numeric,
+        currency -> Varchar,
+    }
+}
+
 diesel::table! {
     orders (id) {
         id -> Uuid,
         customer_id -> Uuid,
-        product_id -> Uuid,
-        quantity -> Int4,
         total_amount -> Numeric,
         status -> Varchar,
         created_at -> Nullable<Timestamptz>,
         updated_at -> Nullable<Timestamptz>,
+        currency -> Varchar,
+        saga_id -> Nullable<Uuid>,
     }
 }
 
 diesel::table! {
-    outbox_events (id) {
+    saga_transactions (id) {
         id -> Uuid,
-        aggregate_id -> Uuid,
-        event_type -> Varchar,
-        event_data -> Jsonb,
-        processed -> Nullable<Bool>,
+        steps -> Jsonb,
+        current_step -> Int4,
+        status -> Varchar,
+        context -> Jsonb,
         created_at -> Nullable<Timestamptz>,
+        updated_at -> Nullable<Timestamptz>,
+        definition_name -> Varchar,
+        definition_version -> Int4,
+        pending_command_id -> Nullable<Uuid>,
+        version -> Int4,
+        deadline -> Nullable<Timestamptz>,
+        attempts -> Int4,
+        compensation -> Nullable<Jsonb>,
     }
 }
 
 diesel::table! {
-    processed_commands (idempotency_key) {
-        idempotency_key -> Varchar,
+    saga_events (id) {
+        id -> Int8,
+        saga_id -> Uuid,
+        saga_version -> Int4,
+        event_type -> Varchar,
+        data -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    saga_replies (id) {
+        id -> Uuid,
+        saga_id -> Uuid,
         command_id -> Uuid,
+        status -> Varchar,
         result -> Nullable<Jsonb>,
-        processed_at -> Nullable<Timestamptz>,
+        error -> Nullable<Text>,
+        replied_at -> Timestamptz,
+        received_at -> Timestamptz,
     }
 }
 
 diesel::table! {
-    saga_transactions (id) {
+    webhook_deliveries (id) {
         id -> Uuid,
-        steps -> Jsonb,
-        current_step -> Int4,
+        subscription_id -> Uuid,
+        event_type -> Varchar,
+        payload -> Jsonb,
         status -> Varchar,
-        context -> Jsonb,
-        created_at -> Nullable<Timestamptz>,
-        updated_at -> Nullable<Timestamptz>,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        created_at -> Timestamptz,
+        delivered_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    webhook_delivery_attempts (id) {
+        id -> Int8,
+        delivery_id -> Uuid,
+        attempt -> Int4,
+        status_code -> Nullable<Int4>,
+        error -> Nullable<Text>,
+        attempted_at -> Timestamptz,
     }
 }
 
+diesel::table! {
+    webhook_subscriptions (id) {
+        id -> Uuid,
+        url -> Text,
+        event_types -> Array<Text>,
+        secret -> Text,
+        active -> Bool,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::joinable!(order_idempotency_keys -> saga_transactions (saga_id));
+diesel::joinable!(order_lines -> orders (order_id));
+diesel::joinable!(orders -> saga_transactions (saga_id));
+diesel::joinable!(saga_events -> saga_transactions (saga_id));
+diesel::joinable!(webhook_deliveries -> webhook_subscriptions (subscription_id));
+diesel::joinable!(webhook_delivery_attempts -> webhook_deliveries (delivery_id));
+
 diesel::allow_tables_to_appear_in_same_query!(
+    order_idempotency_keys,
+    order_lines,
     orders,
-    outbox_events,
-    processed_commands,
+    saga_events,
+    saga_replies,
     saga_transactions,
+    webhook_deliveries,
+    webhook_delivery_attempts,
+    webhook_subscriptions,
 );
\ No newline at end of file
@@ -0,0 +1,83 @@
+use axum::{extract::Path, http::StatusCode, routing::get, Json, Router};
+use diesel::Connection;
+use diesel_async::pooled_connection::bb8::Pool;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::AsyncPgConnection;
+use diesel_migrations::MigrationHarness;
+use shared::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+use crate::sagas;
+
+static MIGRATED: Mutex<bool> = Mutex::new(false);
+
+/// Pool for the database in `ORDERS_TEST_DATABASE_URL`; tests that need a
+/// database are skipped when it isn't set.
+pub async fn test_pool() -> Option<Pool<AsyncPgConnection>> {
+    let Ok(url) = std::env::var("ORDERS_TEST_DATABASE_URL") else {
+        eprintln!("ORDERS_TEST_DATABASE_URL not set, skipping");
+        return None;
+    };
+
+    {
+        let mut migrated = MIGRATED.lock().unwrap();
+        if !*migrated {
+            let mut conn = diesel::PgConnection::establish(&url).unwrap();
+            conn.run_pending_migrations(crate::MIGRATIONS).unwrap();
+            *migrated = true;
+        }
+    }
+
+    let config = AsyncDieselConnectionManager::<AsyncPgConnection>::new(url);
+    Some(Pool::builder().build(config).await.unwrap())
+}
+
+pub fn order_data(customer_id: Uuid) -> OrderData {
+    OrderData {
+        order_id: Uuid::new_v4(),
+        customer_id,
+        lines: vec![OrderLine {
+            product_id: Uuid::new_v4(),
+            quantity: 1,
+            amount: Money::new("99.99".parse().unwrap(), Currency::USD),
+        }],
+        total_amount: Money::new("99.99".parse().unwrap(), Currency::USD),
+        ship_to: None,
+        allocation_strategy: AllocationStrategy::default(),
+    }
+}
+
+pub fn order_context() -> HashMap<String, serde_json::Value> {
+    sagas::order_context(&order_data(Uuid::new_v4()))
+}
+
+/// Product the catalog stub has no price for.
+pub const UNPRICED_PRODUCT: Uuid = Uuid::from_u128(0x0bad);
+
+/// Unit price the catalog stub quotes for every other product.
+pub fn catalog_unit_price() -> Money {
+    Money::new("49.99".parse().unwrap(), Currency::USD)
+}
+
+/// Serves the inventory service's price endpoint on a free local port and
+/// returns its base URL.
+pub async fn catalog_stub() -> String {
+    let app = Router::new().route(
+        "/products/:id/price",
+        get(|Path(product_id): Path<Uuid>| async move {
+            if product_id == UNPRICED_PRODUCT {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            Ok(Json(serde_json::json!({
+                "product_id": product_id,
+                "unit_price": catalog_unit_price(),
+            })))
+        }),
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    url
+}
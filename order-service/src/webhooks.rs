@@ -0,0 +1,575 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel_async::{pooled_connection::bb8::Pool, AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use shared::*;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use crate::models::*;
+use crate::schema::{webhook_deliveries, webhook_delivery_attempts, webhook_subscriptions};
+
+type DbPool = Pool<AsyncPgConnection>;
+
+pub const ORDER_APPROVED: &str = "order.approved";
+pub const ORDER_CANCELLED: &str = "order.cancelled";
+pub const ORDER_FAILED: &str = "order.failed";
+
+/// Event types a subscription can ask for.
+pub const EVENT_TYPES: &[&str] = &[ORDER_APPROVED, ORDER_CANCELLED, ORDER_FAILED];
+
+const PENDING: &str = "pending";
+const DELIVERED: &str = "delivered";
+const FAILED: &str = "failed";
+const CANCELLED: &str = "cancelled";
+
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(1);
+const DISPATCH_BATCH_SIZE: i64 = 50;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a claimed delivery is hidden from other dispatchers. It is
+/// picked up again after this if the dispatcher dies mid-attempt.
+const CLAIM_LEASE: Duration = Duration::from_secs(60);
+
+/// The webhook event type published when a saga finishes with `status`.
+pub fn event_type_for(status: &SagaStatus) -> Option<&'static str> {
+    match status {
+        SagaStatus::Completed => Some(ORDER_APPROVED),
+        SagaStatus::Compensated => Some(ORDER_CANCELLED),
+        SagaStatus::Failed => Some(ORDER_FAILED),
+        _ => None,
+    }
+}
+
+/// Body of a webhook call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    /// Id of the delivery; the same across retries, so receivers can use it
+    /// to drop duplicates.
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub created_at: DateTime<Utc>,
+    pub data: OrderOutcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderOutcome {
+    pub order_id: Uuid,
+    pub customer_id: Uuid,
+    pub saga_id: Uuid,
+    pub saga_status: SagaStatus,
+    pub total_amount: Money,
+}
+
+/// Queues a delivery of the saga's outcome to every active subscription
+/// that wants it. Called in the transaction that saves the saga as finished.
+pub async fn enqueue_deliveries(conn: &mut AsyncPgConnection, saga: &SagaTransaction) -> Result<usize> {
+    let Some(event_type) = event_type_for(&saga.status) else {
+        return Ok(0);
+    };
+    // Only order sagas have an outcome to publish
+    let Ok(order_data) = saga.context_value::<OrderData>("order_data") else {
+        return Ok(0);
+    };
+
+    let subscription_ids = webhook_subscriptions::table
+        .filter(webhook_subscriptions::active.eq(true))
+        .filter(webhook_subscriptions::event_types.contains(vec![event_type.to_string()]))
+        .select(webhook_subscriptions::id)
+        .load::<Uuid>(conn)
+        .await?;
+    if subscription_ids.is_empty() {
+        return Ok(0);
+    }
+
+    let outcome = OrderOutcome {
+        order_id: order_data.order_id,
+        customer_id: order_data.customer_id,
+        saga_id: saga.id,
+        saga_status: saga.status.clone(),
+        total_amount: order_data.total_amount,
+    };
+    let deliveries = subscription_ids
+        .into_iter()
+        .map(|subscription_id| {
+            let id = Uuid::new_v4();
+            let payload = WebhookPayload {
+                id,
+                event_type: event_type.to_string(),
+                created_at: saga.updated_at,
+                data: outcome.clone(),
+            };
+            Ok(NewWebhookDelivery {
+                id,
+                subscription_id,
+                event_type: event_type.to_string(),
+                payload: serde_json::to_value(payload)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    diesel::insert_into(webhook_deliveries::table)
+        .values(&deliveries)
+        .execute(conn)
+        .await?;
+
+    Ok(deliveries.len())
+}
+
+/// Signature sent in the `Webhook-Signature` header: the hex encoded
+/// HMAC-SHA256 of `{delivery id}.{timestamp}.{body}` keyed with the
+/// subscription's secret, prefixed with `v1=`.
+pub fn sign(secret: &str, delivery_id: Uuid, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}.", delivery_id, timestamp).as_bytes());
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("v1={}", hex)
+}
+
+/// When failed deliveries are retried. The wait doubles after every failed
+/// attempt, from `initial_backoff` up to `max_backoff`, and the delivery is
+/// given up on after `max_attempts`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(60 * 60),
+            max_attempts: 10,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before the next attempt once `attempts` attempts have failed.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Sends queued webhook deliveries, retrying failed ones with backoff.
+pub struct WebhookDispatcher {
+    pool: DbPool,
+    client: reqwest::Client,
+    policy: RetryPolicy,
+}
+
+impl WebhookDispatcher {
+    pub fn new(pool: DbPool, policy: RetryPolicy) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        Ok(Self { pool, client, policy })
+    }
+
+    pub async fn run(&self) {
+        let mut interval = tokio::time::interval(DISPATCH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = self.deliver_due().await {
+                error!("Error delivering webhooks: {}", e);
+            }
+        }
+    }
+
+    /// Makes one attempt at every delivery that is due. Returns how many
+    /// were attempted.
+    pub async fn deliver_due(&self) -> Result<usize> {
+        let due = self.claim_due().await?;
+        let attempted = due.len();
+
+        for (delivery, subscription) in due {
+            if let Err(e) = self.attempt(delivery, subscription).await {
+                error!("Error recording webhook delivery attempt: {}", e);
+            }
+        }
+
+        Ok(attempted)
+    }
+
+    /// Pushes the due deliveries' next attempt past the claim lease so that
+    /// other dispatchers leave them alone while they are being sent.
+    async fn claim_due(&self) -> Result<Vec<(WebhookDelivery, WebhookSubscription)>> {
+        let mut conn = self.pool.get().await?;
+        let now = Utc::now();
+        let lease_until = now + chrono::Duration::from_std(CLAIM_LEASE)?;
+
+        let claimed = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            Box::pin(async move {
+                let ids = webhook_deliveries::table
+                    .filter(webhook_deliveries::status.eq(PENDING))
+                    .filter(webhook_deliveries::next_attempt_at.le(now))
+                    .order(webhook_deliveries::next_attempt_at.asc())
+                    .limit(DISPATCH_BATCH_SIZE)
+                    .select(webhook_deliveries::id)
+                    .for_update()
+                    .skip_locked()
+                    .load::<Uuid>(conn)
+                    .await?;
+
+                diesel::update(webhook_deliveries::table.filter(webhook_deliveries::id.eq_any(&ids)))
+                    .set(webhook_deliveries::next_attempt_at.eq(lease_until))
+                    .execute(conn)
+                    .await?;
+                Ok(ids)
+            })
+        }).await?;
+
+        let due = webhook_deliveries::table
+            .inner_join(webhook_subscriptions::table)
+            .filter(webhook_deliveries::id.eq_any(&claimed))
+            .order(webhook_deliveries::created_at.asc())
+            .load::<(WebhookDelivery, WebhookSubscription)>(&mut conn)
+            .await?;
+        Ok(due)
+    }
+
+    async fn attempt(&self, delivery: WebhookDelivery, subscription: WebhookSubscription) -> Result<()> {
+        let body = serde_json::to_vec(&delivery.payload)?;
+        let timestamp = Utc::now().timestamp();
+        let signature = sign(&subscription.secret, delivery.id, timestamp, &body);
+
+        let response = self
+            .client
+            .post(&subscription.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("Webhook-Id", delivery.id.to_string())
+            .header("Webhook-Event", &delivery.event_type)
+            .header("Webhook-Timestamp", timestamp.to_string())
+            .header("Webhook-Signature", signature)
+            .body(body)
+            .send()
+            .await;
+
+        let (status_code, error) = match response {
+            Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                Some(format!("Endpoint responded with {}", response.status())),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        let attempt = delivery.attempts + 1;
+        let now = Utc::now();
+        let (status, next_attempt_at, delivered_at) = match &error {
+            None => {
+                info!("Delivered webhook {} to subscription {}", delivery.id, delivery.subscription_id);
+                (DELIVERED, now, Some(now))
+            }
+            Some(e) if attempt as u32 >= self.policy.max_attempts => {
+                warn!("Giving up on webhook {} after {} attempts: {}", delivery.id, attempt, e);
+                (FAILED, now, None)
+            }
+            Some(e) => {
+                let backoff = self.policy.backoff(attempt as u32);
+                warn!("Webhook {} failed, retrying in {:?}: {}", delivery.id, backoff, e);
+                (PENDING, now + chrono::Duration::from_std(backoff)?, None)
+            }
+        };
+
+        let mut conn = self.pool.get().await?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            Box::pin(async move {
+                diesel::insert_into(webhook_delivery_attempts::table)
+                    .values(&NewWebhookDeliveryAttempt {
+                        delivery_id: delivery.id,
+                        attempt,
+                        status_code: status_code.map(i32::from),
+                        error,
+                    })
+                    .execute(conn)
+                    .await?;
+
+                diesel::update(webhook_deliveries::table.filter(webhook_deliveries::id.eq(delivery.id)))
+                    .set((
+                        webhook_deliveries::status.eq(status),
+                        webhook_deliveries::attempts.eq(attempt),
+                        webhook_deliveries::next_attempt_at.eq(next_attempt_at),
+                        webhook_deliveries::delivered_at.eq(delivered_at),
+                    ))
+                    .execute(conn)
+                    .await?;
+                Ok(())
+            })
+        }).await
+    }
+}
+
+/// A subscription as shown through the API; the secret is never returned.
+#[derive(Debug, Serialize)]
+pub struct SubscriptionView {
+    pub id: Uuid,
+    pub url: String,
+    pub event_types: Vec<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<WebhookSubscription> for SubscriptionView {
+    fn from(subscription: WebhookSubscription) -> Self {
+        Self {
+            id: subscription.id,
+            url: subscription.url,
+            event_types: subscription.event_types,
+            active: subscription.active,
+            created_at: subscription.created_at,
+        }
+    }
+}
+
+/// A delivery and every attempt made at it, oldest first.
+#[derive(Debug, Serialize)]
+pub struct DeliveryView {
+    pub delivery_id: Uuid,
+    pub event_type: String,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub payload: serde_json::Value,
+    pub history: Vec<WebhookDeliveryAttempt>,
+}
+
+pub async fn create_subscription(
+    conn: &mut AsyncPgConnection,
+    subscription: NewWebhookSubscription,
+) -> Result<SubscriptionView> {
+    let subscription = diesel::insert_into(webhook_subscriptions::table)
+        .values(&subscription)
+        .get_result::<WebhookSubscription>(conn)
+        .await?;
+    Ok(subscription.into())
+}
+
+pub async fn list_subscriptions(conn: &mut AsyncPgConnection) -> Result<Vec<SubscriptionView>> {
+    let subscriptions = webhook_subscriptions::table
+        .filter(webhook_subscriptions::active.eq(true))
+        .order(webhook_subscriptions::created_at.asc())
+        .load::<WebhookSubscription>(conn)
+        .await?;
+    Ok(subscriptions.into_iter().map(SubscriptionView::from).collect())
+}
+
+/// Stops the subscription and cancels its pending deliveries. Returns false
+/// if there is no active subscription with this id.
+pub async fn deactivate_subscription(conn: &mut AsyncPgConnection, subscription_id: Uuid) -> Result<bool> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        Box::pin(async move {
+            let deactivated = diesel::update(
+                webhook_subscriptions::table
+                    .filter(webhook_subscriptions::id.eq(subscription_id))
+                    .filter(webhook_subscriptions::active.eq(true)),
+            )
+            .set(webhook_subscriptions::active.eq(false))
+            .execute(conn)
+            .await?;
+
+            diesel::update(
+                webhook_deliveries::table
+                    .filter(webhook_deliveries::subscription_id.eq(subscription_id))
+                    .filter(webhook_deliveries::status.eq(PENDING)),
+            )
+            .set(webhook_deliveries::status.eq(CANCELLED))
+            .execute(conn)
+            .await?;
+
+            Ok(deactivated > 0)
+        })
+    }).await
+}
+
+/// The subscription's most recent deliveries, newest first. Returns None if
+/// the subscription does not exist.
+pub async fn delivery_log(
+    conn: &mut AsyncPgConnection,
+    subscription_id: Uuid,
+    limit: i64,
+) -> Result<Option<Vec<DeliveryView>>> {
+    let exists = webhook_subscriptions::table
+        .find(subscription_id)
+        .select(webhook_subscriptions::id)
+        .first::<Uuid>(conn)
+        .await
+        .optional()?
+        .is_some();
+    if !exists {
+        return Ok(None);
+    }
+
+    let deliveries = webhook_deliveries::table
+        .filter(webhook_deliveries::subscription_id.eq(subscription_id))
+        .order(webhook_deliveries::created_at.desc())
+        .limit(limit)
+        .load::<WebhookDelivery>(conn)
+        .await?;
+
+    let delivery_ids: Vec<Uuid> = deliveries.iter().map(|d| d.id).collect();
+    let mut attempts = webhook_delivery_attempts::table
+        .filter(webhook_delivery_attempts::delivery_id.eq_any(&delivery_ids))
+        .order(webhook_delivery_attempts::id.asc())
+        .load::<WebhookDeliveryAttempt>(conn)
+        .await?;
+
+    let log = deliveries
+        .into_iter()
+        .map(|delivery| {
+            let (history, rest) = attempts.drain(..).partition(|a| a.delivery_id == delivery.id);
+            attempts = rest;
+            DeliveryView {
+                delivery_id: delivery.id,
+                next_attempt_at: (delivery.status == PENDING).then_some(delivery.next_attempt_at),
+                event_type: delivery.event_type,
+                status: delivery.status,
+                attempts: delivery.attempts,
+                created_at: delivery.created_at,
+                delivered_at: delivery.delivered_at,
+                payload: delivery.payload,
+                history,
+            }
+        })
+        .collect();
+
+    Ok(Some(log))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, extract::State, http::{HeaderMap, StatusCode}, routing::post, Router};
+    use std::sync::{Arc, Mutex};
+    use crate::test_support::{order_context, test_pool};
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+
+    /// Serves a webhook endpoint on a free local port that fails the first
+    /// call for each delivery and accepts the rest, and records every call.
+    async fn flaky_endpoint() -> (String, Received) {
+        let received = Received::default();
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(|State(received): State<Received>, headers: HeaderMap, body: Bytes| async move {
+                    let mut received = received.lock().unwrap();
+                    let retry = received.iter().any(|(h, _)| h["webhook-id"] == headers["webhook-id"]);
+                    received.push((headers, body));
+                    if !retry {
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    } else {
+                        StatusCode::NO_CONTENT
+                    }
+                }),
+            )
+            .with_state(received.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, received)
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(60),
+            max_attempts: 10,
+        };
+        assert_eq!(policy.backoff(1), Duration::from_secs(10));
+        assert_eq!(policy.backoff(2), Duration::from_secs(20));
+        assert_eq!(policy.backoff(3), Duration::from_secs(40));
+        assert_eq!(policy.backoff(4), Duration::from_secs(60));
+        assert_eq!(policy.backoff(40), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn signature_covers_delivery_timestamp_and_body() {
+        let id = Uuid::new_v4();
+        let signature = sign("secret", id, 1700000000, b"{}");
+
+        assert!(signature.starts_with("v1="));
+        assert_eq!(signature.len(), 3 + 64);
+        assert_eq!(signature, sign("secret", id, 1700000000, b"{}"));
+        assert_ne!(signature, sign("other secret", id, 1700000000, b"{}"));
+        assert_ne!(signature, sign("secret", id, 1700000001, b"{}"));
+        assert_ne!(signature, sign("secret", id, 1700000000, b"[]"));
+        assert_ne!(signature, sign("secret", Uuid::new_v4(), 1700000000, b"{}"));
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_retried_and_logged() {
+        let Some(pool) = test_pool().await else { return };
+        let (url, received) = flaky_endpoint().await;
+        let mut conn = pool.get().await.unwrap();
+
+        let subscription = create_subscription(
+            &mut conn,
+            NewWebhookSubscription {
+                id: Uuid::new_v4(),
+                url,
+                event_types: vec![ORDER_APPROVED.to_string()],
+                secret: "test-secret".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+
+        let mut saga = SagaTransaction::new("CreateOrder".to_string(), 1, Vec::new(), order_context());
+        saga.status = SagaStatus::Completed;
+        assert!(enqueue_deliveries(&mut conn, &saga).await.unwrap() >= 1);
+
+        let dispatcher = WebhookDispatcher::new(
+            pool.clone(),
+            RetryPolicy {
+                initial_backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+                max_attempts: 3,
+            },
+        )
+        .unwrap();
+        dispatcher.deliver_due().await.unwrap();
+        dispatcher.deliver_due().await.unwrap();
+
+        let log = delivery_log(&mut conn, subscription.id, 10).await.unwrap().unwrap();
+        deactivate_subscription(&mut conn, subscription.id).await.unwrap();
+
+        let delivery = log
+            .iter()
+            .find(|d| d.payload["data"]["saga_id"] == saga.id.to_string())
+            .unwrap();
+        assert_eq!(delivery.status, DELIVERED);
+        assert_eq!(delivery.event_type, ORDER_APPROVED);
+        let status_codes: Vec<_> = delivery.history.iter().map(|a| a.status_code).collect();
+        assert_eq!(status_codes, [Some(500), Some(204)]);
+
+        let received = received.lock().unwrap();
+        let (headers, body) = received
+            .iter()
+            .rev()
+            .find(|(h, _)| h["webhook-id"] == delivery.delivery_id.to_string().as_str())
+            .unwrap();
+        let header = |name: &str| headers[name].to_str().unwrap().to_string();
+        assert_eq!(header("webhook-id"), delivery.delivery_id.to_string());
+        let timestamp: i64 = header("webhook-timestamp").parse().unwrap();
+        assert_eq!(header("webhook-signature"), sign("test-secret", delivery.delivery_id, timestamp, body));
+
+        let payload: WebhookPayload = serde_json::from_slice(body).unwrap();
+        assert_eq!(payload.id, delivery.delivery_id);
+        assert_eq!(payload.data.saga_status, SagaStatus::Completed);
+    }
+}
@@ -0,0 +1,3 @@
+ALTER TABLE processed_commands
+    DROP COLUMN IF EXISTS error,
+    DROP COLUMN IF EXISTS status;
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Commands processed before this migration were only recorded when they succeeded
+ALTER TABLE processed_commands
+    ADD COLUMN status VARCHAR(50) NOT NULL DEFAULT 'Success',
+    ADD COLUMN error TEXT;
\ No newline at end of file
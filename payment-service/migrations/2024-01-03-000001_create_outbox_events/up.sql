@@ -0,0 +1,12 @@
+-- Replies are published through the transactional outbox
+CREATE TABLE outbox_events (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    aggregate_id UUID NOT NULL,
+    event_type VARCHAR(255) NOT NULL,
+    event_data JSONB NOT NULL,
+    processed BOOLEAN DEFAULT FALSE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    topic VARCHAR(255)
+);
+
+CREATE INDEX idx_outbox_events_processed ON outbox_events(processed, created_at);
\ No newline at end of file
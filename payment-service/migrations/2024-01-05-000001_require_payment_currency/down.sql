@@ -0,0 +1,3 @@
+ALTER TABLE payments
+    DROP CONSTRAINT IF EXISTS payments_currency_format,
+    ALTER COLUMN currency SET DEFAULT 'USD';
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Currency is always supplied by the saga, which validates it against ISO 4217
+ALTER TABLE payments
+    ALTER COLUMN currency DROP DEFAULT,
+    ADD CONSTRAINT payments_currency_format CHECK (currency ~ '^[A-Z]{3}$');
\ No newline at end of file
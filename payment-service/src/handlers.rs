@@ -1,15 +1,13 @@
 use anyhow::Result;
-use num_traits::ToPrimitive;
 use diesel::prelude::*;
 use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection, RunQueryDsl};
 use futures::StreamExt;
 use rdkafka::consumer::{Consumer, StreamConsumer};
-use rdkafka::producer::{FutureProducer, FutureRecord};
 use rdkafka::Message;
-use std::time::Duration;
 use tracing::{error, info, warn};
 use uuid::Uuid;
 use shared::*;
+use shared::inbox::{CommandProcessor, Inbox};
 use crate::models::*;
 use crate::schema::*;
 
@@ -17,13 +15,18 @@ type DbPool = Pool<AsyncPgConnection>;
 
 pub struct CommandHandler {
     pool: DbPool,
-    producer: FutureProducer,
-    reply_topic: String,
+    inbox: Inbox,
+    /// Share of payments the simulated processor accepts.
+    success_rate: f64,
 }
 
 impl CommandHandler {
-    pub fn new(pool: DbPool, producer: FutureProducer, reply_topic: String) -> Self {
-        Self { pool, producer, reply_topic }
+    pub fn new(pool: DbPool, reply_topic: String, success_rate: f64) -> Self {
+        Self {
+            pool,
+            inbox: Inbox::new(reply_topic),
+            success_rate,
+        }
     }
 
     pub async fn run(&self, consumer: StreamConsumer) {
@@ -55,38 +58,7 @@ impl CommandHandler {
 
     async fn handle_command(&self, command: Command) -> Result<()> {
         let mut conn = self.pool.get().await?;
-
-        if let Some(existing) = self.check_idempotency(&mut conn, &command.idempotency_key).await? {
-            info!("Command already processed, returning cached result");
-            let reply = CommandReply {
-                id: Uuid::new_v4(),
-                command_id: command.id,
-                saga_id: command.saga_id,
-                status: CommandStatus::Success,
-                result: existing.result,
-                error: None,
-                created_at: chrono::Utc::now(),
-            };
-            self.send_reply(reply).await?;
-            return Ok(());
-        }
-
-        let reply = match command.command_type {
-            CommandType::ProcessPayment => self.handle_process_payment(&mut conn, &command).await?,
-            CommandType::CompensatePayment => self.handle_compensate_payment(&mut conn, &command).await?,
-            _ => {
-                warn!("Unsupported command type: {:?}", command.command_type);
-                CommandReply::failed(
-                    command.id,
-                    command.saga_id,
-                    "Unsupported command type".to_string(),
-                )
-            }
-        };
-
-        self.store_processed_command(&mut conn, &command, &reply).await?;
-        self.send_reply(reply).await?;
-
+        self.inbox.handle(&mut conn, self, &command).await?;
         Ok(())
     }
 
@@ -99,6 +71,9 @@ impl CommandHandler {
             .await
             .optional()?;
 
+        if let Some(reply) = currency_mismatch(command, existing_payment.as_ref(), &payment_data) {
+            return Ok(reply);
+        }
         if let Some(payment) = existing_payment {
             if payment.status == "processed" {
                 return Ok(CommandReply::success(
@@ -109,8 +84,7 @@ impl CommandHandler {
             }
         }
 
-        let success_rate = 0.8;
-        let should_succeed = rand::random::<f64>() < success_rate;
+        let should_succeed = rand::random::<f64>() < self.success_rate;
 
         if !should_succeed {
             return Ok(CommandReply::failed(
@@ -123,7 +97,8 @@ impl CommandHandler {
         let new_payment = NewPayment {
             id: Uuid::new_v4(),
             order_id: payment_data.order_id,
-            amount: bigdecimal::BigDecimal::from(payment_data.amount.to_i64().unwrap()),
+            amount: payment_data.amount.amount,
+            currency: payment_data.amount.currency.code().to_string(),
             payment_method: payment_data.payment_method,
             status: "processed".to_string(),
         };
@@ -142,7 +117,17 @@ impl CommandHandler {
 
     async fn handle_compensate_payment(&self, conn: &mut AsyncPgConnection, command: &Command) -> Result<CommandReply> {
         let payment_data: PaymentData = serde_json::from_value(command.payload.clone())?;
-        
+
+        let existing_payment = payments::table
+            .filter(payments::order_id.eq(payment_data.order_id))
+            .first::<Payment>(conn)
+            .await
+            .optional()?;
+
+        if let Some(reply) = currency_mismatch(command, existing_payment.as_ref(), &payment_data) {
+            return Ok(reply);
+        }
+
         let updated_rows = diesel::update(payments::table.filter(payments::order_id.eq(payment_data.order_id)))
             .set(payments::status.eq("refunded"))
             .execute(conn)
@@ -155,45 +140,138 @@ impl CommandHandler {
         Ok(CommandReply::success(
             command.id,
             command.saga_id,
-            Some(serde_json::json!({"refunded": true})),
+            Some(serde_json::json!({"refunded": true, "amount": payment_data.amount})),
         ))
     }
+}
 
-    async fn check_idempotency(&self, conn: &mut AsyncPgConnection, key: &str) -> Result<Option<ProcessedCommand>> {
-        let result = processed_commands::table
-            .filter(processed_commands::idempotency_key.eq(key))
-            .first::<ProcessedCommand>(conn)
-            .await
-            .optional()?;
-        Ok(result)
-    }
+/// A failed reply if the command's amount is in another currency than the
+/// payment already recorded for the order or, before there is one, than the
+/// order itself.
+fn currency_mismatch(command: &Command, payment: Option<&Payment>, payment_data: &PaymentData) -> Option<CommandReply> {
+    let currency = payment_data.amount.currency;
+    let (recorded, expected) = match (payment, payment_data.currency) {
+        (Some(payment), _) if payment.currency != currency.code() => ("payment", payment.currency.clone()),
+        (None, Some(order_currency)) if order_currency != currency => ("order", order_currency.code().to_string()),
+        _ => return None,
+    };
+
+    warn!(
+        "Currency mismatch for order {}: {} is in {}, command is in {}",
+        payment_data.order_id, recorded, expected, currency
+    );
+    Some(CommandReply::failed(
+        command.id,
+        command.saga_id,
+        format!("Currency mismatch: {} is in {}, command is in {}", recorded, expected, currency),
+    ))
+}
 
-    async fn store_processed_command(&self, conn: &mut AsyncPgConnection, command: &Command, reply: &CommandReply) -> Result<()> {
-        let processed_command = ProcessedCommand {
-            idempotency_key: command.idempotency_key.clone(),
-            command_id: command.id,
-            result: reply.result.clone(),
-            processed_at: Some(chrono::Utc::now()),
+impl CommandProcessor for CommandHandler {
+    async fn process(&self, conn: &mut AsyncPgConnection, command: &Command) -> Result<CommandReply> {
+        let reply = match command.command_type {
+            CommandType::ProcessPayment => self.handle_process_payment(conn, command).await?,
+            CommandType::CompensatePayment => self.handle_compensate_payment(conn, command).await?,
+            _ => {
+                warn!("Unsupported command type: {:?}", command.command_type);
+                CommandReply::failed(
+                    command.id,
+                    command.saga_id,
+                    "Unsupported command type".to_string(),
+                )
+            }
         };
 
-        diesel::insert_into(processed_commands::table)
-            .values(&processed_command)
-            .execute(conn)
-            .await?;
+        Ok(reply)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+    use crate::test_support::test_pool;
 
-        Ok(())
+    async fn send(handler: &CommandHandler, command_type: CommandType, payload: &PaymentData) -> CommandReply {
+        let command = Command::new(Uuid::new_v4(), 1, 0, command_type, serde_json::to_value(payload).unwrap());
+        let mut conn = handler.pool.get().await.unwrap();
+        handler.inbox.handle(&mut conn, handler, &command).await.unwrap()
+    }
+
+    fn payment(amount: &str, currency: &str) -> PaymentData {
+        let currency: Currency = currency.parse().unwrap();
+        PaymentData {
+            order_id: Uuid::new_v4(),
+            amount: Money::new(BigDecimal::from_str(amount).unwrap(), currency),
+            payment_method: "credit_card".to_string(),
+            currency: Some(currency),
+        }
+    }
+
+    fn always_accepting(pool: &DbPool) -> CommandHandler {
+        CommandHandler::new(pool.clone(), "order-replies".to_string(), 1.0)
     }
 
-    async fn send_reply(&self, reply: CommandReply) -> Result<()> {
-        let json = serde_json::to_string(&reply)?;
-        let key = reply.saga_id.to_string();
-        let record = FutureRecord::to(&self.reply_topic)
-            .payload(&json)
-            .key(&key);
+    #[tokio::test]
+    async fn retry_in_another_currency_is_rejected() {
+        let Some(pool) = test_pool().await else { return };
+        let handler = always_accepting(&pool);
+        let mut data = payment("49.99", "USD");
 
-        self.producer.send(record, Duration::from_secs(5)).await
-            .map_err(|(e, _)| anyhow::anyhow!("Failed to send reply: {}", e))?;
+        let reply = send(&handler, CommandType::ProcessPayment, &data).await;
+        assert_eq!(reply.status, CommandStatus::Success);
 
-        Ok(())
+        data.amount = Money::new(data.amount.amount.clone(), "EUR".parse().unwrap());
+        data.currency = Some(data.amount.currency);
+        let reply = send(&handler, CommandType::ProcessPayment, &data).await;
+        assert_eq!(reply.status, CommandStatus::Failed);
+        assert_eq!(reply.error.as_deref(), Some("Currency mismatch: payment is in USD, command is in EUR"));
+    }
+
+    #[tokio::test]
+    async fn first_payment_in_another_currency_than_the_order_is_rejected() {
+        let Some(pool) = test_pool().await else { return };
+        let handler = always_accepting(&pool);
+        let mut data = payment("49.99", "EUR");
+        data.currency = Some(Currency::USD);
+
+        let reply = send(&handler, CommandType::ProcessPayment, &data).await;
+        assert_eq!(reply.status, CommandStatus::Failed);
+        assert_eq!(reply.error.as_deref(), Some("Currency mismatch: order is in USD, command is in EUR"));
+
+        let mut conn = pool.get().await.unwrap();
+        let recorded: i64 = payments::table
+            .filter(payments::order_id.eq(data.order_id))
+            .count()
+            .get_result(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(recorded, 0);
+    }
+
+    #[tokio::test]
+    async fn refund_reply_carries_amount_and_currency() {
+        let Some(pool) = test_pool().await else { return };
+        let handler = always_accepting(&pool);
+        let data = payment("1500", "JPY");
+
+        send(&handler, CommandType::ProcessPayment, &data).await;
+        let reply = send(&handler, CommandType::CompensatePayment, &data).await;
+        assert_eq!(reply.status, CommandStatus::Success);
+
+        let result = reply.result.unwrap();
+        assert_eq!(result["refunded"], true);
+        let refunded: Money = serde_json::from_value(result["amount"].clone()).unwrap();
+        assert_eq!(refunded, data.amount);
+        assert_eq!(refunded.currency.code(), "JPY");
+
+        let mut conn = pool.get().await.unwrap();
+        let status: String = payments::table
+            .filter(payments::order_id.eq(data.order_id))
+            .select(payments::status)
+            .first(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(status, "refunded");
     }
-}
\ No newline at end of file
+}
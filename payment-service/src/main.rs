@@ -1,6 +1,8 @@
 mod schema;
 mod models;
 mod handlers;
+#[cfg(test)]
+mod test_support;
 
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use diesel::PgConnection;
@@ -32,6 +34,10 @@ struct Args {
     
     #[arg(long, default_value = "order-replies")]
     reply_topic: String,
+    
+    /// Share of payments the simulated processor accepts, from 0 to 1
+    #[arg(long, env = "PAYMENT_SUCCESS_RATE", default_value = "0.8")]
+    payment_success_rate: f64,
 }
 
 
@@ -64,7 +70,16 @@ async fn main() -> Result<()> {
 
     consumer.subscribe(&[&args.command_topic])?;
 
-    let command_handler = handlers::CommandHandler::new(pool.clone(), producer.clone(), args.reply_topic.clone());
+    let command_handler = handlers::CommandHandler::new(
+        pool.clone(),
+        args.reply_topic.clone(),
+        args.payment_success_rate,
+    );
+    let outbox_processor = shared::outbox::OutboxProcessor::new(pool.clone(), producer.clone());
+
+    tokio::spawn(async move {
+        outbox_processor.run().await;
+    });
 
     tokio::spawn(async move {
         command_handler.run(consumer).await;
@@ -14,6 +14,7 @@ pub struct Payment {
     pub processed_at: Option<DateTime<Utc>>,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
+    pub currency: String,
 }
 
 #[derive(Debug, Clone, Insertable, Serialize)]
@@ -22,15 +23,7 @@ pub struct NewPayment {
     pub id: Uuid,
     pub order_id: Uuid,
     pub amount: bigdecimal::BigDecimal,
+    pub currency: String,
     pub payment_method: String,
     pub status: String,
-}
-
-#[derive(Debug, Clone, Queryable, Insertable)]
-#[diesel(table_name = crate::schema::processed_commands)]
-pub struct ProcessedCommand {
-    pub idempotency_key: String,
-    pub command_id: Uuid,
-    pub result: Option<serde_json::Value>,
-    pub processed_at: Option<DateTime<Utc>>,
 }
\ No newline at end of file
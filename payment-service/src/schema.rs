@@ -8,19 +8,10 @@ diesel::table! {
         processed_at -> Nullable<Timestamptz>,
         created_at -> Nullable<Timestamptz>,
         updated_at -> Nullable<Timestamptz>,
-    }
-}
-
-diesel::table! {
-    processed_commands (idempotency_key) {
-        idempotency_key -> Varchar,
-        command_id -> Uuid,
-        result -> Nullable<Jsonb>,
-        processed_at -> Nullable<Timestamptz>,
+        currency -> Varchar,
     }
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
     payments,
-    processed_commands,
 );
\ No newline at end of file
@@ -0,0 +1,29 @@
+use diesel::Connection;
+use diesel_async::pooled_connection::bb8::Pool;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::AsyncPgConnection;
+use diesel_migrations::MigrationHarness;
+use std::sync::Mutex;
+
+static MIGRATED: Mutex<bool> = Mutex::new(false);
+
+/// Pool for the database in `PAYMENTS_TEST_DATABASE_URL`; tests that need a
+/// database are skipped when it isn't set.
+pub async fn test_pool() -> Option<Pool<AsyncPgConnection>> {
+    let Ok(url) = std::env::var("PAYMENTS_TEST_DATABASE_URL") else {
+        eprintln!("PAYMENTS_TEST_DATABASE_URL not set, skipping");
+        return None;
+    };
+
+    {
+        let mut migrated = MIGRATED.lock().unwrap();
+        if !*migrated {
+            let mut conn = diesel::PgConnection::establish(&url).unwrap();
+            conn.run_pending_migrations(crate::MIGRATIONS).unwrap();
+            *migrated = true;
+        }
+    }
+
+    let config = AsyncDieselConnectionManager::<AsyncPgConnection>::new(url);
+    Some(Pool::builder().build(config).await.unwrap())
+}
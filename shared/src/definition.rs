@@ -0,0 +1,291 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::{CommandType, SagaStep, SagaTransaction, StepTimeout};
+
+/// Builds the JSON payload of a command from the current saga state.
+pub type PayloadBuilder = Arc<dyn Fn(&SagaTransaction) -> Result<serde_json::Value> + Send + Sync>;
+
+#[derive(Clone)]
+struct StepDefinition {
+    step: SagaStep,
+    payload: PayloadBuilder,
+    compensation_payload: Option<PayloadBuilder>,
+}
+
+/// A named, versioned description of a saga: its steps, their compensations,
+/// the services that execute them and how each command payload is built.
+#[derive(Clone)]
+pub struct SagaDefinition {
+    name: String,
+    version: u32,
+    steps: Vec<StepDefinition>,
+}
+
+impl SagaDefinition {
+    pub fn builder(name: impl Into<String>, version: u32) -> SagaDefinitionBuilder {
+        SagaDefinitionBuilder {
+            name: name.into(),
+            version,
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn steps(&self) -> Vec<SagaStep> {
+        self.steps.iter().map(|s| s.step.clone()).collect()
+    }
+
+    /// Creates a new saga instance of this definition with the given initial context.
+    pub fn start(&self, context: HashMap<String, serde_json::Value>) -> SagaTransaction {
+        SagaTransaction::new(self.name.clone(), self.version, self.steps(), context)
+    }
+
+    pub fn command_payload(&self, saga: &SagaTransaction, step_index: usize) -> Result<serde_json::Value> {
+        let step = self.step(step_index)?;
+        (step.payload)(saga)
+    }
+
+    pub fn compensation_payload(&self, saga: &SagaTransaction, step_index: usize) -> Result<serde_json::Value> {
+        let step = self.step(step_index)?;
+        match &step.compensation_payload {
+            Some(payload) => payload(saga),
+            None => Err(anyhow::anyhow!(
+                "Step {} of saga {} v{} has no compensation",
+                step_index, self.name, self.version
+            )),
+        }
+    }
+
+    fn step(&self, step_index: usize) -> Result<&StepDefinition> {
+        self.steps.get(step_index).ok_or_else(|| {
+            anyhow::anyhow!("Saga {} v{} has no step {}", self.name, self.version, step_index)
+        })
+    }
+}
+
+pub struct SagaDefinitionBuilder {
+    name: String,
+    version: u32,
+    steps: Vec<StepDefinition>,
+}
+
+impl SagaDefinitionBuilder {
+    /// Appends a step executed by `service_name`.
+    pub fn step<F>(mut self, service_name: impl Into<String>, command_type: CommandType, payload: F) -> Self
+    where
+        F: Fn(&SagaTransaction) -> Result<serde_json::Value> + Send + Sync + 'static,
+    {
+        self.steps.push(StepDefinition {
+            step: SagaStep {
+                command_type,
+                compensation_type: None,
+                service_name: service_name.into(),
+                timeout: None,
+            },
+            payload: Arc::new(payload),
+            compensation_payload: None,
+        });
+        self
+    }
+
+    /// Sets the compensation of the most recently added step. The compensation
+    /// is sent to the same service as the step itself.
+    pub fn compensate_with<F>(mut self, command_type: CommandType, payload: F) -> Self
+    where
+        F: Fn(&SagaTransaction) -> Result<serde_json::Value> + Send + Sync + 'static,
+    {
+        let step = self
+            .steps
+            .last_mut()
+            .expect("compensate_with must follow a step");
+        step.step.compensation_type = Some(command_type);
+        step.compensation_payload = Some(Arc::new(payload));
+        self
+    }
+
+    /// Sets how long the most recently added step, and its compensation, may
+    /// wait for a reply before the command is resent, up to `max_retries`
+    /// times, and the step is then treated as failed.
+    pub fn with_timeout(mut self, timeout: Duration, max_retries: u32) -> Self {
+        let step = self
+            .steps
+            .last_mut()
+            .expect("with_timeout must follow a step");
+        step.step.timeout = Some(StepTimeout {
+            seconds: timeout.as_secs(),
+            max_retries,
+        });
+        self
+    }
+
+    pub fn build(self) -> SagaDefinition {
+        SagaDefinition {
+            name: self.name,
+            version: self.version,
+            steps: self.steps,
+        }
+    }
+}
+
+/// The set of saga definitions a saga manager is able to execute.
+#[derive(Clone, Default)]
+pub struct SagaRegistry {
+    definitions: HashMap<(String, u32), Arc<SagaDefinition>>,
+}
+
+impl SagaRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, definition: SagaDefinition) {
+        let key = (definition.name.clone(), definition.version);
+        self.definitions.insert(key, Arc::new(definition));
+    }
+
+    pub fn get(&self, name: &str, version: u32) -> Option<Arc<SagaDefinition>> {
+        self.definitions.get(&(name.to_string(), version)).cloned()
+    }
+
+    /// Returns the highest registered version of the named saga.
+    pub fn latest(&self, name: &str) -> Option<Arc<SagaDefinition>> {
+        self.definitions
+            .values()
+            .filter(|d| d.name == name)
+            .max_by_key(|d| d.version)
+            .cloned()
+    }
+
+    /// Returns the definition a saga instance was started from.
+    pub fn resolve(&self, saga: &SagaTransaction) -> Result<Arc<SagaDefinition>> {
+        self.get(&saga.definition_name, saga.definition_version).ok_or_else(|| {
+            anyhow::anyhow!(
+                "No saga definition registered for {} v{}",
+                saga.definition_name, saga.definition_version
+            )
+        })
+    }
+
+    /// Returns the highest version of the saga's definition that runs the
+    /// saga's steps first and adds more after them, which a saga that has run
+    /// out of steps carries on with.
+    pub fn extension_of(&self, saga: &SagaTransaction) -> Option<Arc<SagaDefinition>> {
+        let same_step = |a: &SagaStep, b: &SagaStep| {
+            a.command_type == b.command_type && a.compensation_type == b.compensation_type
+        };
+        self.definitions
+            .values()
+            .filter(|d| d.name == saga.definition_name && d.version > saga.definition_version)
+            .filter(|d| {
+                d.steps.len() > saga.steps.len()
+                    && d.steps.iter().zip(&saga.steps).all(|(defined, run)| same_step(&defined.step, run))
+            })
+            .max_by_key(|d| d.version)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SagaStatus;
+
+    fn payload(_: &SagaTransaction) -> Result<serde_json::Value> {
+        Ok(serde_json::json!({"built": true}))
+    }
+
+    fn definition(version: u32) -> SagaDefinition {
+        SagaDefinition::builder("test", version)
+            .step("order-service", CommandType::CreateOrder, payload)
+            .compensate_with(CommandType::CancelOrder, payload)
+            .with_timeout(Duration::from_secs(30), 3)
+            .step("order-service", CommandType::ApproveOrder, payload)
+            .build()
+    }
+
+    #[test]
+    fn builder_attaches_compensation_and_timeout_to_the_last_step() {
+        let steps = definition(1).steps();
+
+        assert_eq!(steps.len(), 2);
+        assert!(matches!(steps[0].compensation_type, Some(CommandType::CancelOrder)));
+        assert_eq!(steps[0].timeout.as_ref().map(|t| (t.seconds, t.max_retries)), Some((30, 3)));
+        assert!(steps[1].compensation_type.is_none());
+        assert!(steps[1].timeout.is_none());
+        assert_eq!(steps[1].service_name, "order-service");
+    }
+
+    #[test]
+    fn started_saga_records_its_definition() {
+        let saga = definition(2).start(HashMap::new());
+
+        assert_eq!((saga.definition_name.as_str(), saga.definition_version), ("test", 2));
+        assert_eq!(saga.steps.len(), 2);
+        assert_eq!(saga.status, SagaStatus::Started);
+    }
+
+    #[test]
+    fn compensation_payload_requires_a_compensation() {
+        let definition = definition(1);
+        let saga = definition.start(HashMap::new());
+
+        assert_eq!(definition.compensation_payload(&saga, 0).unwrap()["built"], true);
+        assert!(definition.compensation_payload(&saga, 1).is_err());
+        assert!(definition.command_payload(&saga, 2).is_err());
+    }
+
+    #[test]
+    fn registry_resolves_the_version_a_saga_was_started_from() {
+        let mut registry = SagaRegistry::new();
+        registry.register(definition(1));
+        registry.register(definition(2));
+
+        assert_eq!(registry.latest("test").unwrap().version(), 2);
+        assert!(registry.latest("other").is_none());
+
+        let saga = registry.get("test", 1).unwrap().start(HashMap::new());
+        assert_eq!(registry.resolve(&saga).unwrap().version(), 1);
+
+        let mut unknown = saga.clone();
+        unknown.definition_version = 3;
+        assert!(registry.resolve(&unknown).is_err());
+    }
+
+    #[test]
+    fn saga_is_extended_by_a_later_version_that_adds_steps_after_its_own() {
+        let mut registry = SagaRegistry::new();
+        registry.register(definition(1));
+        registry.register(
+            SagaDefinition::builder("test", 2)
+                .step("order-service", CommandType::CreateOrder, payload)
+                .compensate_with(CommandType::CancelOrder, payload)
+                .step("order-service", CommandType::ApproveOrder, payload)
+                .step("inventory-service", CommandType::CommitInventory, payload)
+                .build(),
+        );
+        // Reorders the steps, so a saga that has run them cannot carry on with it
+        registry.register(
+            SagaDefinition::builder("test", 3)
+                .step("order-service", CommandType::CreateOrder, payload)
+                .compensate_with(CommandType::CancelOrder, payload)
+                .step("inventory-service", CommandType::CommitInventory, payload)
+                .step("order-service", CommandType::ApproveOrder, payload)
+                .build(),
+        );
+
+        let saga = registry.get("test", 1).unwrap().start(HashMap::new());
+        assert_eq!(registry.extension_of(&saga).unwrap().version(), 2);
+
+        let latest = registry.get("test", 3).unwrap().start(HashMap::new());
+        assert!(registry.extension_of(&latest).is_none());
+    }
+}
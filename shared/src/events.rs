@@ -0,0 +1,286 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::{CommandReply, CommandStatus, CommandType, CompensationState, SagaStatus, SagaStep, SagaTransaction};
+
+/// A state transition of a saga. Every change to a saga is made by recording
+/// one of these, so the saga can be rebuilt by replaying its events.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SagaEvent {
+    SagaStarted {
+        definition_name: String,
+        definition_version: u32,
+        steps: Vec<SagaStep>,
+        context: HashMap<String, serde_json::Value>,
+    },
+    /// A forward or compensation command of `step_index` was queued.
+    CommandSent {
+        step_index: usize,
+        command_id: Uuid,
+        command_type: CommandType,
+        deadline: Option<DateTime<Utc>>,
+    },
+    /// A reply to the pending command was applied.
+    ReplyReceived {
+        reply_id: Uuid,
+        command_id: Uuid,
+        status: CommandStatus,
+        result: Option<serde_json::Value>,
+        error: Option<String>,
+    },
+    /// The pending command got no reply before its deadline. It is either
+    /// resent, see `CommandResent`, or given up on.
+    CommandTimedOut {
+        command_id: Uuid,
+        attempts: u32,
+        retry: bool,
+    },
+    CommandResent {
+        command_id: Uuid,
+        deadline: Option<DateTime<Utc>>,
+    },
+    CompensationStarted {
+        steps: Vec<usize>,
+        round: u32,
+    },
+    CompensationCompleted,
+    SagaCompleted,
+    /// The saga ran out of steps and carries on as a later version of its
+    /// definition, which adds `steps` after the ones it has run.
+    DefinitionUpgraded {
+        definition_version: u32,
+        steps: Vec<SagaStep>,
+    },
+    /// Step `step_index` failed after a step that cannot be undone had
+    /// succeeded, so the saga stops for an operator instead of compensating.
+    SagaFailed {
+        step_index: usize,
+    },
+    /// The inventory service released the saga's reservation because it was
+    /// held longer than its TTL. A pending command's reply is no longer
+    /// awaited; the saga compensates instead, or fails if it is past a step
+    /// that cannot be undone.
+    ReservationExpired {
+        reservation_id: Uuid,
+    },
+    ManualIntervention {
+        intervention: Intervention,
+        note: Option<String>,
+    },
+}
+
+/// An operator action on a saga that is stuck or should not continue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Intervention {
+    /// Publish the pending command once more and restart its timeout.
+    Resend {
+        command_id: Uuid,
+        deadline: Option<DateTime<Utc>>,
+    },
+    /// Stop the saga where it is and mark it failed.
+    Abort,
+}
+
+impl SagaEvent {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            SagaEvent::SagaStarted { .. } => "SagaStarted",
+            SagaEvent::CommandSent { .. } => "CommandSent",
+            SagaEvent::ReplyReceived { .. } => "ReplyReceived",
+            SagaEvent::CommandTimedOut { .. } => "CommandTimedOut",
+            SagaEvent::CommandResent { .. } => "CommandResent",
+            SagaEvent::CompensationStarted { .. } => "CompensationStarted",
+            SagaEvent::CompensationCompleted => "CompensationCompleted",
+            SagaEvent::SagaCompleted => "SagaCompleted",
+            SagaEvent::DefinitionUpgraded { .. } => "DefinitionUpgraded",
+            SagaEvent::SagaFailed { .. } => "SagaFailed",
+            SagaEvent::ReservationExpired { .. } => "ReservationExpired",
+            SagaEvent::ManualIntervention { .. } => "ManualIntervention",
+        }
+    }
+
+    /// Whether applying this event puts the saga in a terminal status.
+    pub fn finishes_saga(&self) -> bool {
+        matches!(
+            self,
+            SagaEvent::SagaCompleted
+                | SagaEvent::SagaFailed { .. }
+                | SagaEvent::CompensationCompleted
+                | SagaEvent::ManualIntervention {
+                    intervention: Intervention::Abort,
+                    ..
+                }
+        )
+    }
+
+    pub fn reply_received(reply: &CommandReply) -> Self {
+        SagaEvent::ReplyReceived {
+            reply_id: reply.id,
+            command_id: reply.command_id,
+            status: reply.status.clone(),
+            result: reply.result.clone(),
+            error: reply.error.clone(),
+        }
+    }
+}
+
+impl SagaTransaction {
+    /// Applies `event` and keeps it in `uncommitted_events` until the saga is
+    /// saved.
+    pub fn record(&mut self, event: SagaEvent) {
+        self.apply(&event);
+        self.uncommitted_events.push(event);
+    }
+
+    pub fn apply(&mut self, event: &SagaEvent) {
+        match event {
+            SagaEvent::SagaStarted {
+                definition_name,
+                definition_version,
+                steps,
+                context,
+            } => {
+                self.definition_name = definition_name.clone();
+                self.definition_version = *definition_version;
+                self.steps = steps.clone();
+                self.context = context.clone();
+                self.current_step = 0;
+                self.status = SagaStatus::Started;
+            }
+            SagaEvent::CommandSent { command_id, deadline, .. } => {
+                self.pending_command_id = Some(*command_id);
+                self.attempts = 1;
+                self.deadline = *deadline;
+            }
+            SagaEvent::ReplyReceived { status, result, .. } => {
+                self.clear_pending();
+                match (status, &self.status, &mut self.compensation) {
+                    (
+                        CommandStatus::Success | CommandStatus::Compensated,
+                        SagaStatus::Compensating,
+                        Some(compensation),
+                    ) => compensation.completed += 1,
+                    (CommandStatus::Success, _, _) => {
+                        self.record_step_result(result.clone());
+                        self.advance_step();
+                    }
+                    _ => {}
+                }
+            }
+            SagaEvent::CommandTimedOut { retry, .. } => {
+                if !retry {
+                    self.clear_pending();
+                }
+            }
+            SagaEvent::CommandResent { deadline, .. }
+            | SagaEvent::ManualIntervention {
+                intervention: Intervention::Resend { deadline, .. },
+                ..
+            } => {
+                self.attempts += 1;
+                self.deadline = *deadline;
+            }
+            SagaEvent::CompensationStarted { steps, round } => {
+                self.status = SagaStatus::Compensating;
+                self.compensation = Some(CompensationState {
+                    steps: steps.clone(),
+                    completed: 0,
+                    round: *round,
+                });
+            }
+            SagaEvent::CompensationCompleted => self.status = SagaStatus::Compensated,
+            SagaEvent::SagaCompleted => self.status = SagaStatus::Completed,
+            SagaEvent::DefinitionUpgraded {
+                definition_version,
+                steps,
+            } => {
+                self.definition_version = *definition_version;
+                self.steps = steps.clone();
+            }
+            SagaEvent::SagaFailed { .. } => {
+                self.clear_pending();
+                self.status = SagaStatus::Failed;
+            }
+            SagaEvent::ReservationExpired { .. } => self.clear_pending(),
+            SagaEvent::ManualIntervention {
+                intervention: Intervention::Abort,
+                ..
+            } => {
+                self.clear_pending();
+                self.status = SagaStatus::Failed;
+            }
+        }
+    }
+
+    /// Replays the events of saga `id`, oldest first, into a fresh instance.
+    pub fn rebuild<'a>(id: Uuid, events: impl IntoIterator<Item = &'a SagaEvent>) -> anyhow::Result<Self> {
+        let mut events = events.into_iter();
+        let Some(first @ SagaEvent::SagaStarted { .. }) = events.next() else {
+            return Err(anyhow::anyhow!("Event log of saga {} does not start with SagaStarted", id));
+        };
+
+        let mut saga = SagaTransaction::blank(id);
+        saga.apply(first);
+        for event in events {
+            saga.apply(event);
+        }
+        Ok(saga)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(command_type: CommandType, compensation_type: Option<CommandType>) -> SagaStep {
+        SagaStep {
+            command_type,
+            compensation_type,
+            service_name: "test-service".to_string(),
+            timeout: None,
+        }
+    }
+
+    #[test]
+    fn rebuilding_from_recorded_events_reproduces_the_saga() {
+        let steps = vec![
+            step(CommandType::CreateOrder, Some(CommandType::CancelOrder)),
+            step(CommandType::ProcessPayment, Some(CommandType::CompensatePayment)),
+        ];
+        let mut saga = SagaTransaction::new("test".to_string(), 1, steps, HashMap::new());
+        let first_command = Uuid::new_v4();
+        saga.record(SagaEvent::CommandSent {
+            step_index: 0,
+            command_id: first_command,
+            command_type: CommandType::CreateOrder,
+            deadline: None,
+        });
+        saga.record(SagaEvent::reply_received(&CommandReply::success(first_command, saga.id, None)));
+        let second_command = Uuid::new_v4();
+        saga.record(SagaEvent::CommandSent {
+            step_index: 1,
+            command_id: second_command,
+            command_type: CommandType::ProcessPayment,
+            deadline: None,
+        });
+        saga.record(SagaEvent::reply_received(&CommandReply::failed(second_command, saga.id, "declined".to_string())));
+        saga.record(SagaEvent::CompensationStarted { steps: vec![0], round: 0 });
+
+        let rebuilt = SagaTransaction::rebuild(saga.id, &saga.uncommitted_events).unwrap();
+
+        assert_eq!(rebuilt.current_step, 1);
+        assert_eq!(rebuilt.status, SagaStatus::Compensating);
+        assert_eq!(rebuilt.pending_command_id, None);
+        assert_eq!(rebuilt.compensation, saga.compensation);
+        assert!(rebuilt.uncommitted_events.is_empty());
+    }
+
+    #[test]
+    fn log_must_start_with_saga_started() {
+        let events = [SagaEvent::SagaCompleted];
+        assert!(SagaTransaction::rebuild(Uuid::new_v4(), &events).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use std::future::Future;
+use tracing::info;
+use uuid::Uuid;
+use crate::outbox::NewOutboxEvent;
+use crate::schema::{outbox_events, processed_commands};
+use crate::{Command, CommandReply};
+
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = processed_commands)]
+pub struct ProcessedCommand {
+    pub idempotency_key: String,
+    pub command_id: Uuid,
+    pub result: Option<serde_json::Value>,
+    pub processed_at: Option<DateTime<Utc>>,
+    pub status: String,
+    pub error: Option<String>,
+}
+
+/// The business side of a participant: applies a command to the service's
+/// own data and decides the reply.
+pub trait CommandProcessor: Sync {
+    /// `conn` is inside the inbox transaction, so everything written through
+    /// it commits or rolls back together with the dedup record and the reply.
+    fn process(&self, conn: &mut AsyncPgConnection, command: &Command) -> impl Future<Output = Result<CommandReply>> + Send;
+}
+
+/// Processes each command at most once per idempotency key. The dedup record,
+/// the processor's changes and the reply, which goes out through the outbox,
+/// are committed in a single transaction.
+pub struct Inbox {
+    reply_topic: String,
+}
+
+impl Inbox {
+    pub fn new(reply_topic: String) -> Self {
+        Self { reply_topic }
+    }
+
+    pub async fn handle<P: CommandProcessor>(
+        &self,
+        conn: &mut AsyncPgConnection,
+        processor: &P,
+        command: &Command,
+    ) -> Result<CommandReply> {
+        match self.try_handle(conn, processor, command).await {
+            // Another delivery of the same command committed first; ours was
+            // rolled back, so answer with its reply instead
+            Err(e) if is_unique_violation(&e) => {
+                info!("Command {} was processed concurrently, replaying its reply", command.id);
+                self.try_handle(conn, processor, command).await
+            }
+            result => result,
+        }
+    }
+
+    async fn try_handle<P: CommandProcessor>(
+        &self,
+        conn: &mut AsyncPgConnection,
+        processor: &P,
+        command: &Command,
+    ) -> Result<CommandReply> {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            Box::pin(async move {
+                let existing = processed_commands::table
+                    .filter(processed_commands::idempotency_key.eq(&command.idempotency_key))
+                    .first::<ProcessedCommand>(conn)
+                    .await
+                    .optional()?;
+
+                let reply = match existing {
+                    Some(existing) => {
+                        info!("Command already processed, replaying original reply");
+                        CommandReply {
+                            id: Uuid::new_v4(),
+                            command_id: command.id,
+                            saga_id: command.saga_id,
+                            status: existing.status.parse()?,
+                            result: existing.result,
+                            error: existing.error,
+                            created_at: Utc::now(),
+                        }
+                    }
+                    None => {
+                        let reply = processor.process(conn, command).await?;
+
+                        let processed_command = ProcessedCommand {
+                            idempotency_key: command.idempotency_key.clone(),
+                            command_id: command.id,
+                            result: reply.result.clone(),
+                            processed_at: Some(Utc::now()),
+                            status: reply.status.to_string(),
+                            error: reply.error.clone(),
+                        };
+                        diesel::insert_into(processed_commands::table)
+                            .values(&processed_command)
+                            .execute(conn)
+                            .await?;
+
+                        reply
+                    }
+                };
+
+                let outbox_event = NewOutboxEvent {
+                    id: reply.id,
+                    aggregate_id: reply.saga_id,
+                    event_type: "CommandReply".to_string(),
+                    event_data: serde_json::to_value(&reply)?,
+                    topic: Some(self.reply_topic.clone()),
+                };
+                diesel::insert_into(outbox_events::table)
+                    .values(&outbox_event)
+                    .execute(conn)
+                    .await?;
+
+                Ok(reply)
+            })
+        }).await
+    }
+}
+
+fn is_unique_violation(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<DieselError>(),
+        Some(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _))
+    )
+}
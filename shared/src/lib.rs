@@ -1,8 +1,21 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SubsecRound, Utc};
 use std::collections::HashMap;
 
+pub mod currency;
+pub mod definition;
+pub mod events;
+pub mod inbox;
+pub mod money;
+pub mod outbox;
+pub mod schema;
+
+pub use currency::Currency;
+pub use definition::*;
+pub use events::{Intervention, SagaEvent};
+pub use money::Money;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Command {
     pub id: Uuid,
@@ -13,12 +26,14 @@ pub struct Command {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CommandType {
     CreateOrder,
     ProcessPayment,
     ReserveInventory,
     ApproveOrder,
+    /// Turns an approved order's reservation into a final stock deduction.
+    CommitInventory,
     CompensatePayment,
     CompensateInventory,
     CancelOrder,
@@ -35,29 +50,92 @@ pub struct CommandReply {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CommandStatus {
     Success,
     Failed,
     Compensated,
 }
 
+impl std::fmt::Display for CommandStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::str::FromStr for CommandStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Success" => Ok(CommandStatus::Success),
+            "Failed" => Ok(CommandStatus::Failed),
+            "Compensated" => Ok(CommandStatus::Compensated),
+            _ => Err(anyhow::anyhow!("Unknown command status: {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SagaStep {
     pub command_type: CommandType,
     pub compensation_type: Option<CommandType>,
     pub service_name: String,
+    #[serde(default)]
+    pub timeout: Option<StepTimeout>,
+}
+
+/// How long to wait for the reply to a step's command, and how often to
+/// resend it before treating the step as failed. Compensations are resent
+/// until they succeed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct StepTimeout {
+    pub seconds: u64,
+    pub max_retries: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SagaTransaction {
     pub id: Uuid,
+    pub definition_name: String,
+    pub definition_version: u32,
     pub steps: Vec<SagaStep>,
     pub current_step: usize,
     pub status: SagaStatus,
+    /// Id of the command the saga is waiting on a reply for, if any.
+    pub pending_command_id: Option<Uuid>,
+    /// When the pending command times out, if its step has a timeout.
+    pub deadline: Option<DateTime<Utc>>,
+    /// How many times the pending command has been sent.
+    pub attempts: u32,
     pub context: HashMap<String, serde_json::Value>,
+    /// Progress of the compensation, once the saga has started compensating.
+    pub compensation: Option<CompensationState>,
+    /// Incremented on every persisted update, for optimistic concurrency control.
+    pub version: u32,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Events recorded since the saga was loaded, to be appended to its log
+    /// when it is saved.
+    #[serde(skip)]
+    pub uncommitted_events: Vec<SagaEvent>,
+}
+
+/// The completed steps being compensated, in the order their compensations
+/// run, and how many of those compensations have succeeded. `round` counts
+/// how often compensation was started over.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompensationState {
+    pub steps: Vec<usize>,
+    pub completed: usize,
+    pub round: u32,
+}
+
+impl CompensationState {
+    /// The step whose compensation runs next, if any is left.
+    pub fn current_step(&self) -> Option<usize> {
+        self.steps.get(self.completed).copied()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -70,27 +148,202 @@ pub enum SagaStatus {
     Failed,
 }
 
+impl SagaStatus {
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, SagaStatus::Completed | SagaStatus::Compensated | SagaStatus::Failed)
+    }
+}
+
+/// One product of an order. `amount` is what the line costs in total, i.e.
+/// unit price × quantity.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderLine {
+    pub product_id: Uuid,
+    pub quantity: i32,
+    pub amount: Money,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "OrderDataRepr")]
 pub struct OrderData {
     pub order_id: Uuid,
     pub customer_id: Uuid,
-    pub product_id: Uuid,
-    pub quantity: i32,
-    pub total_amount: f64,
+    pub lines: Vec<OrderLine>,
+    pub total_amount: Money,
+    /// Where the order ships to; stock is allocated from the warehouses
+    /// nearest to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ship_to: Option<GeoPoint>,
+    #[serde(default)]
+    pub allocation_strategy: AllocationStrategy,
+}
+
+/// `OrderData` as it is read, which includes the single `product_id` and
+/// `quantity` of sagas started before orders had lines.
+#[derive(Deserialize)]
+struct OrderDataRepr {
+    order_id: Uuid,
+    customer_id: Uuid,
+    #[serde(default)]
+    lines: Vec<OrderLine>,
+    product_id: Option<Uuid>,
+    quantity: Option<i32>,
+    total_amount: Money,
+    #[serde(default)]
+    ship_to: Option<GeoPoint>,
+    #[serde(default)]
+    allocation_strategy: AllocationStrategy,
+}
+
+impl From<OrderDataRepr> for OrderData {
+    fn from(repr: OrderDataRepr) -> Self {
+        let mut lines = repr.lines;
+        if let (true, Some(product_id), Some(quantity)) = (lines.is_empty(), repr.product_id, repr.quantity) {
+            lines.push(OrderLine {
+                product_id,
+                quantity,
+                amount: repr.total_amount.clone(),
+            });
+        }
+        Self {
+            order_id: repr.order_id,
+            customer_id: repr.customer_id,
+            lines,
+            total_amount: repr.total_amount,
+            ship_to: repr.ship_to,
+            allocation_strategy: repr.allocation_strategy,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PaymentData {
     pub order_id: Uuid,
-    pub amount: f64,
+    pub amount: Money,
     pub payment_method: String,
+    /// The currency the order was placed in, which `amount` has to be in.
+    /// Missing from payloads sent before it was added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct InventoryData {
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InventoryLine {
+    pub product_id: Uuid,
+    pub quantity: i32,
+}
+
+/// A position on the globe, in degrees.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GeoPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl GeoPoint {
+    pub fn is_valid(&self) -> bool {
+        (-90.0..=90.0).contains(&self.latitude) && (-180.0..=180.0).contains(&self.longitude)
+    }
+
+    /// Great-circle distance to `other` in kilometres.
+    pub fn distance_km(&self, other: &GeoPoint) -> f64 {
+        const EARTH_RADIUS_KM: f64 = 6371.0;
+
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.longitude - self.longitude).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+/// How the lines of an order may be spread over warehouses. Either way the
+/// warehouses nearest to the order's destination are tried first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AllocationStrategy {
+    /// Each line from the nearest warehouses that have it, split over
+    /// several when one does not have enough.
+    #[default]
+    SplitShipment,
+    /// The whole order from the nearest warehouse that has every line.
+    SingleShipment,
+}
+
+/// Stock of one product reserved at one warehouse.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Allocation {
     pub product_id: Uuid,
+    pub warehouse_id: Uuid,
     pub quantity: i32,
+}
+
+/// The stock an order needs. Commands carrying it apply to every line or,
+/// if one of them cannot be applied, to none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "InventoryDataRepr")]
+pub struct InventoryData {
     pub order_id: Uuid,
+    pub lines: Vec<InventoryLine>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ship_to: Option<GeoPoint>,
+    #[serde(default)]
+    pub allocation_strategy: AllocationStrategy,
+    /// Where the reservation took the stock from, as its reply reported.
+    /// Commits and compensations act on these locations; without them, on
+    /// every location the order has stock reserved at.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allocations: Vec<Allocation>,
+}
+
+/// `InventoryData` as it is read, which includes the single `product_id`
+/// and `quantity` sent before orders had lines.
+#[derive(Deserialize)]
+struct InventoryDataRepr {
+    order_id: Uuid,
+    #[serde(default)]
+    lines: Vec<InventoryLine>,
+    product_id: Option<Uuid>,
+    quantity: Option<i32>,
+    #[serde(default)]
+    ship_to: Option<GeoPoint>,
+    #[serde(default)]
+    allocation_strategy: AllocationStrategy,
+    #[serde(default)]
+    allocations: Vec<Allocation>,
+}
+
+impl From<InventoryDataRepr> for InventoryData {
+    fn from(repr: InventoryDataRepr) -> Self {
+        let mut lines = repr.lines;
+        if let (true, Some(product_id), Some(quantity)) = (lines.is_empty(), repr.product_id, repr.quantity) {
+            lines.push(InventoryLine { product_id, quantity });
+        }
+        Self {
+            order_id: repr.order_id,
+            lines,
+            ship_to: repr.ship_to,
+            allocation_strategy: repr.allocation_strategy,
+            allocations: repr.allocations,
+        }
+    }
+}
+
+/// Domain events the inventory service publishes on `inventory-events`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum InventoryEvent {
+    /// A reservation was held past its TTL without being approved or
+    /// compensated, and its stock was released. `saga_id` is missing for
+    /// reservations made before sagas were recorded with them.
+    ReservationExpired {
+        reservation_id: Uuid,
+        saga_id: Option<Uuid>,
+        order_id: Uuid,
+        product_id: Uuid,
+        quantity: i32,
+        expired_at: DateTime<Utc>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -104,44 +357,102 @@ pub struct OutboxEvent {
 }
 
 impl SagaTransaction {
-    pub fn new(order_data: OrderData) -> Self {
-        let steps = vec![
-            SagaStep {
-                command_type: CommandType::CreateOrder,
-                compensation_type: Some(CommandType::CancelOrder),
-                service_name: "order-service".to_string(),
-            },
-            SagaStep {
-                command_type: CommandType::ProcessPayment,
-                compensation_type: Some(CommandType::CompensatePayment),
-                service_name: "payment-service".to_string(),
-            },
-            SagaStep {
-                command_type: CommandType::ReserveInventory,
-                compensation_type: Some(CommandType::CompensateInventory),
-                service_name: "inventory-service".to_string(),
-            },
-            SagaStep {
-                command_type: CommandType::ApproveOrder,
-                compensation_type: None,
-                service_name: "order-service".to_string(),
-            },
-        ];
-
-        let mut context = HashMap::new();
-        context.insert("order_data".to_string(), serde_json::to_value(order_data).unwrap());
+    pub fn new(
+        definition_name: String,
+        definition_version: u32,
+        steps: Vec<SagaStep>,
+        context: HashMap<String, serde_json::Value>,
+    ) -> Self {
+        let mut saga = Self::blank(Uuid::new_v4());
+        saga.record(SagaEvent::SagaStarted {
+            definition_name,
+            definition_version,
+            steps,
+            context,
+        });
+        saga
+    }
 
+    /// A saga with no definition, the state before its `SagaStarted` event.
+    fn blank(id: Uuid) -> Self {
         Self {
-            id: Uuid::new_v4(),
-            steps,
+            id,
+            definition_name: String::new(),
+            definition_version: 0,
+            steps: Vec::new(),
             current_step: 0,
             status: SagaStatus::Started,
-            context,
+            pending_command_id: None,
+            deadline: None,
+            attempts: 0,
+            context: HashMap::new(),
+            compensation: None,
+            version: 0,
             created_at: Utc::now(),
             updated_at: Utc::now(),
+            uncommitted_events: Vec::new(),
         }
     }
 
+    pub fn context_value<T: serde::de::DeserializeOwned>(&self, key: &str) -> anyhow::Result<T> {
+        let value = self
+            .context
+            .get(key)
+            .ok_or_else(|| anyhow::anyhow!("Saga {} has no '{}' in its context", self.id, key))?;
+        Ok(serde_json::from_value(value.clone())?)
+    }
+
+    /// The result the step with `command_type` succeeded with, if its reply
+    /// carried one.
+    pub fn step_result<T: serde::de::DeserializeOwned>(&self, command_type: &CommandType) -> anyhow::Result<Option<T>> {
+        self.context
+            .get(&step_result_key(command_type))
+            .map(|value| serde_json::from_value(value.clone()))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    /// Records that the saga now waits for the reply to `command`, sent on
+    /// behalf of `step_index`, with the deadline set from the step's timeout.
+    pub fn await_reply(&mut self, command: &Command, step_index: usize) {
+        let deadline = self.step_deadline(step_index);
+        self.record(SagaEvent::CommandSent {
+            step_index,
+            command_id: command.id,
+            command_type: command.command_type.clone(),
+            deadline,
+        });
+    }
+
+    /// Records that the pending command of `step_index` was sent once more.
+    pub fn retry_pending(&mut self, step_index: usize) {
+        if let Some(command_id) = self.pending_command_id {
+            let deadline = self.step_deadline(step_index);
+            self.record(SagaEvent::CommandResent { command_id, deadline });
+        }
+    }
+
+    /// Deadline for a command of `step_index` sent now, at the microsecond
+    /// precision of the database so the event log and the saga row agree.
+    pub fn step_deadline(&self, step_index: usize) -> Option<DateTime<Utc>> {
+        let timeout = self.steps.get(step_index)?.timeout?;
+        Some((Utc::now() + chrono::Duration::seconds(timeout.seconds as i64)).trunc_subsecs(6))
+    }
+
+    /// Keeps the result the current step succeeded with, for the payloads
+    /// of later steps and compensations.
+    fn record_step_result(&mut self, result: Option<serde_json::Value>) {
+        if let (Some(result), Some(step)) = (result, self.steps.get(self.current_step)) {
+            self.context.insert(step_result_key(&step.command_type), result);
+        }
+    }
+
+    fn clear_pending(&mut self) {
+        self.pending_command_id = None;
+        self.deadline = None;
+        self.attempts = 0;
+    }
+
     pub fn next_step(&mut self) -> Option<&SagaStep> {
         if self.current_step < self.steps.len() {
             Some(&self.steps[self.current_step])
@@ -157,26 +468,53 @@ impl SagaTransaction {
         }
     }
 
-    pub fn get_compensation_steps(&self) -> Vec<&SagaStep> {
-        self.steps[0..self.current_step]
-            .iter()
+    /// Index of the last completed step that has no compensation. Once such
+    /// a step has succeeded, e.g. the order was approved, the saga can no
+    /// longer be rolled back.
+    pub fn irreversible_step(&self) -> Option<usize> {
+        (0..self.current_step).rev().find(|&i| self.steps[i].compensation_type.is_none())
+    }
+
+    /// Indices of the completed steps that need compensating, in the order
+    /// the compensations should run.
+    pub fn compensation_step_indices(&self) -> Vec<usize> {
+        (0..self.current_step)
             .rev()
-            .filter(|step| step.compensation_type.is_some())
+            .filter(|&i| self.steps[i].compensation_type.is_some())
             .collect()
     }
 }
 
+/// Context key a step's reply result is kept under.
+fn step_result_key(command_type: &CommandType) -> String {
+    format!("{:?}.result", command_type)
+}
+
 impl Command {
-    pub fn new(saga_id: Uuid, command_type: CommandType, payload: serde_json::Value) -> Self {
+    /// Creates the command for step `step_index` of a saga. `attempt` counts
+    /// deliberate re-executions of the step, e.g. a compensation that is
+    /// started over after it failed. Building the same command again yields
+    /// the same idempotency key, so participants answer it from their cache.
+    pub fn new(
+        saga_id: Uuid,
+        step_index: usize,
+        attempt: u32,
+        command_type: CommandType,
+        payload: serde_json::Value,
+    ) -> Self {
         Self {
             id: Uuid::new_v4(),
             saga_id,
+            idempotency_key: Self::idempotency_key(saga_id, step_index, attempt, &command_type),
             command_type,
             payload,
-            idempotency_key: format!("{}_{}", saga_id, Uuid::new_v4()),
             created_at: Utc::now(),
         }
     }
+
+    pub fn idempotency_key(saga_id: Uuid, step_index: usize, attempt: u32, command_type: &CommandType) -> String {
+        format!("{}:{}:{:?}:{}", saga_id, step_index, command_type, attempt)
+    }
 }
 
 impl CommandReply {
@@ -203,4 +541,107 @@ impl CommandReply {
             created_at: Utc::now(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebuilt_command_keeps_idempotency_key() {
+        let saga_id = Uuid::new_v4();
+        let first = Command::new(saga_id, 1, 0, CommandType::ProcessPayment, serde_json::json!({}));
+        let resent = Command::new(saga_id, 1, 0, CommandType::ProcessPayment, serde_json::json!({}));
+
+        assert_ne!(first.id, resent.id);
+        assert_eq!(first.idempotency_key, resent.idempotency_key);
+    }
+
+    #[test]
+    fn idempotency_key_distinguishes_logical_commands() {
+        let saga_id = Uuid::new_v4();
+        let forward = Command::idempotency_key(saga_id, 1, 0, &CommandType::ProcessPayment);
+
+        assert_ne!(forward, Command::idempotency_key(saga_id, 1, 0, &CommandType::CompensatePayment));
+        assert_ne!(forward, Command::idempotency_key(saga_id, 2, 0, &CommandType::ProcessPayment));
+        assert_ne!(forward, Command::idempotency_key(saga_id, 1, 1, &CommandType::ProcessPayment));
+        assert_ne!(forward, Command::idempotency_key(Uuid::new_v4(), 1, 0, &CommandType::ProcessPayment));
+    }
+
+    #[test]
+    fn single_product_payloads_read_as_one_line() {
+        let product_id = Uuid::new_v4();
+        let order: OrderData = serde_json::from_value(serde_json::json!({
+            "order_id": Uuid::new_v4(),
+            "customer_id": Uuid::new_v4(),
+            "product_id": product_id,
+            "quantity": 2,
+            "total_amount": { "amount": "99.98", "currency": "USD" },
+        }))
+        .unwrap();
+        assert_eq!(order.lines.len(), 1);
+        assert_eq!((order.lines[0].product_id, order.lines[0].quantity), (product_id, 2));
+        assert_eq!(order.lines[0].amount, order.total_amount);
+
+        let inventory: InventoryData = serde_json::from_value(serde_json::json!({
+            "order_id": Uuid::new_v4(),
+            "product_id": product_id,
+            "quantity": 2,
+        }))
+        .unwrap();
+        assert_eq!(inventory.lines, [InventoryLine { product_id, quantity: 2 }]);
+
+        // What is written now reads back the same
+        let round_trip: InventoryData = serde_json::from_value(serde_json::to_value(&inventory).unwrap()).unwrap();
+        assert_eq!(round_trip.lines, inventory.lines);
+    }
+
+    #[test]
+    fn successful_reply_result_is_kept_for_later_steps() {
+        let steps = vec![SagaStep {
+            command_type: CommandType::ReserveInventory,
+            compensation_type: Some(CommandType::CompensateInventory),
+            service_name: "inventory-service".to_string(),
+            timeout: None,
+        }];
+        let mut saga = SagaTransaction::new("test".to_string(), 1, steps, HashMap::new());
+        assert_eq!(saga.step_result::<serde_json::Value>(&CommandType::ReserveInventory).unwrap(), None);
+
+        let result = serde_json::json!({"reserved": true});
+        let reply = CommandReply::success(Uuid::new_v4(), saga.id, Some(result.clone()));
+        saga.record(SagaEvent::reply_received(&reply));
+
+        assert_eq!(saga.step_result(&CommandType::ReserveInventory).unwrap(), Some(result));
+    }
+
+    #[test]
+    fn saga_cannot_be_rolled_back_once_a_step_without_compensation_succeeded() {
+        let step = |command_type, compensation_type| SagaStep {
+            command_type,
+            compensation_type,
+            service_name: "test-service".to_string(),
+            timeout: None,
+        };
+        let steps = vec![
+            step(CommandType::ReserveInventory, Some(CommandType::CompensateInventory)),
+            step(CommandType::CommitInventory, None),
+            step(CommandType::ApproveOrder, None),
+        ];
+        let mut saga = SagaTransaction::new("test".to_string(), 1, steps, HashMap::new());
+
+        saga.current_step = 1;
+        assert_eq!(saga.irreversible_step(), None);
+        saga.current_step = 2;
+        assert_eq!(saga.irreversible_step(), Some(1));
+    }
+
+    #[test]
+    fn distance_between_points_is_great_circle() {
+        let newark = GeoPoint { latitude: 40.7357, longitude: -74.1724 };
+        let reno = GeoPoint { latitude: 39.5296, longitude: -119.8138 };
+
+        assert_eq!(newark.distance_km(&newark), 0.0);
+        assert!((newark.distance_km(&reno) - 3838.0).abs() < 1.0);
+        assert!(!GeoPoint { latitude: 91.0, longitude: 0.0 }.is_valid());
+    }
+}
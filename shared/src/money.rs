@@ -0,0 +1,137 @@
+use bigdecimal::{BigDecimal, Zero};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use crate::Currency;
+
+/// An exact monetary amount in a given currency.
+///
+/// The amount is serialized as a decimal string (`"99.99"`) so that it never
+/// passes through a binary float. JSON numbers are accepted on input and
+/// parsed from their shortest decimal representation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Money {
+    #[serde(with = "decimal")]
+    pub amount: BigDecimal,
+    pub currency: Currency,
+}
+
+impl Money {
+    pub fn new(amount: BigDecimal, currency: Currency) -> Self {
+        Self { amount, currency }
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.amount > BigDecimal::zero()
+    }
+
+    /// Whether the amount can be expressed in the currency's minor units,
+    /// e.g. `10.5` and `10.50` are valid USD amounts but `10.505` is not.
+    pub fn has_valid_precision(&self) -> bool {
+        self.amount.normalized().fractional_digit_count() <= i64::from(self.currency.minor_units())
+    }
+
+    /// The sum of both amounts, or `None` if they are in different currencies.
+    pub fn checked_add(&self, other: &Money) -> Option<Money> {
+        (self.currency == other.currency).then(|| Money::new(&self.amount + &other.amount, self.currency))
+    }
+
+    /// The price of `quantity` units at this unit price.
+    pub fn times(&self, quantity: i32) -> Money {
+        Money::new(&self.amount * BigDecimal::from(quantity), self.currency)
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.amount, self.currency)
+    }
+}
+
+mod decimal {
+    use bigdecimal::BigDecimal;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::str::FromStr;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        String(String),
+        Number(serde_json::Number),
+    }
+
+    pub fn serialize<S: Serializer>(amount: &BigDecimal, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&amount.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BigDecimal, D::Error> {
+        let repr = match Repr::deserialize(deserializer)? {
+            Repr::String(s) => s,
+            Repr::Number(n) => n.to_string(),
+        };
+        BigDecimal::from_str(repr.trim()).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn amount_round_trips_without_loss() {
+        let money: Money = serde_json::from_value(serde_json::json!({
+            "amount": "99.99",
+            "currency": "USD",
+        }))
+        .unwrap();
+        assert_eq!(money.amount, BigDecimal::from_str("99.99").unwrap());
+
+        let json = serde_json::to_value(&money).unwrap();
+        assert_eq!(json["amount"], "99.99");
+    }
+
+    #[test]
+    fn json_number_is_parsed_as_written() {
+        let money: Money = serde_json::from_value(serde_json::json!({
+            "amount": 0.1,
+            "currency": "USD",
+        }))
+        .unwrap();
+        assert_eq!(money.amount, BigDecimal::from_str("0.1").unwrap());
+    }
+
+    #[test]
+    fn precision_is_limited_by_currency_minor_units() {
+        let usd = |amount: &str| Money::new(BigDecimal::from_str(amount).unwrap(), Currency::USD);
+        assert!(usd("10.50").has_valid_precision());
+        assert!(usd("100").has_valid_precision());
+        assert!(!usd("10.505").has_valid_precision());
+
+        let jpy = Money::new(BigDecimal::from_str("1000.5").unwrap(), "JPY".parse().unwrap());
+        assert!(!jpy.has_valid_precision());
+    }
+
+    #[test]
+    fn multiplying_keeps_the_amount_exact() {
+        let unit = Money::new(BigDecimal::from_str("49.99").unwrap(), Currency::USD);
+        let total = unit.times(3);
+        assert_eq!(total, Money::new(BigDecimal::from_str("149.97").unwrap(), Currency::USD));
+    }
+
+    #[test]
+    fn only_amounts_in_one_currency_add_up() {
+        let usd = Money::new(BigDecimal::from_str("0.10").unwrap(), Currency::USD);
+        let jpy = Money::new(BigDecimal::from(5), "JPY".parse().unwrap());
+        assert_eq!(usd.checked_add(&usd), Some(Money::new(BigDecimal::from_str("0.2").unwrap(), Currency::USD)));
+        assert_eq!(usd.checked_add(&jpy), None);
+    }
+
+    #[test]
+    fn non_numeric_amount_is_rejected() {
+        let result = serde_json::from_value::<Money>(serde_json::json!({
+            "amount": "NaN",
+            "currency": "USD",
+        }));
+        assert!(result.is_err());
+    }
+}
@@ -1,15 +1,54 @@
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection, RunQueryDsl};
 use rdkafka::producer::{FutureProducer, FutureRecord};
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 use tokio::time;
 use tracing::{error, info};
-use crate::models::*;
-use crate::schema::*;
+use uuid::Uuid;
+use crate::schema::outbox_events;
+use crate::OutboxEvent;
 
 type DbPool = Pool<AsyncPgConnection>;
 
+#[derive(Debug, Clone, Queryable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = outbox_events)]
+pub struct DbOutboxEvent {
+    pub id: Uuid,
+    pub aggregate_id: Uuid,
+    pub event_type: String,
+    pub event_data: serde_json::Value,
+    pub processed: Option<bool>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub topic: Option<String>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = outbox_events)]
+pub struct NewOutboxEvent {
+    pub id: Uuid,
+    pub aggregate_id: Uuid,
+    pub event_type: String,
+    pub event_data: serde_json::Value,
+    pub topic: Option<String>,
+}
+
+impl From<OutboxEvent> for DbOutboxEvent {
+    fn from(event: OutboxEvent) -> Self {
+        Self {
+            id: event.id,
+            aggregate_id: event.aggregate_id,
+            event_type: event.event_type,
+            event_data: event.event_data,
+            processed: Some(event.processed),
+            created_at: Some(event.created_at),
+            topic: None,
+        }
+    }
+}
+
 pub struct OutboxProcessor {
     pool: DbPool,
     producer: FutureProducer,
@@ -21,7 +60,8 @@ impl OutboxProcessor {
     }
 
     pub async fn run(&self) {
-        let mut interval = time::interval(Duration::from_secs(5));
+        // Saga commands and replies go through the outbox too, so this bounds the latency of every saga step
+        let mut interval = time::interval(Duration::from_secs(1));
         
         loop {
             interval.tick().await;
@@ -60,11 +100,12 @@ impl OutboxProcessor {
     }
 
     async fn publish_event(&self, event: &DbOutboxEvent) -> Result<()> {
-        let topic = match event.event_type.as_str() {
-            "OrderCreated" => "order-events",
-            "PaymentProcessed" => "payment-events",
-            "InventoryReserved" => "inventory-events",
-            _ => "domain-events",
+        let topic = match (&event.topic, event.event_type.as_str()) {
+            (Some(topic), _) => topic.as_str(),
+            (None, "OrderCreated") => "order-events",
+            (None, "PaymentProcessed") => "payment-events",
+            (None, "InventoryReserved") => "inventory-events",
+            (None, _) => "domain-events",
         };
 
         let json = serde_json::to_string(&event.event_data)?;
@@ -0,0 +1,29 @@
+//! Messaging tables that every service database has.
+
+diesel::table! {
+    outbox_events (id) {
+        id -> Uuid,
+        aggregate_id -> Uuid,
+        event_type -> Varchar,
+        event_data -> Jsonb,
+        processed -> Nullable<Bool>,
+        created_at -> Nullable<Timestamptz>,
+        topic -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    processed_commands (idempotency_key) {
+        idempotency_key -> Varchar,
+        command_id -> Uuid,
+        result -> Nullable<Jsonb>,
+        processed_at -> Nullable<Timestamptz>,
+        status -> Varchar,
+        error -> Nullable<Text>,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(
+    outbox_events,
+    processed_commands,
+);